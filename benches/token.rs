@@ -6,30 +6,31 @@ extern crate rand;
 
 use rand::rngs::OsRng;
 use test::Bencher;
-use biscuit::{crypto::KeyPair, token::{Biscuit, builder::*}};
+use biscuit::{crypto::KeyPair, datalog::RunLimits, token::{Biscuit, builder::*}};
+use std::time::Duration;
 
 #[bench]
 fn create_block_1(b: &mut Bencher) {
   let mut rng = OsRng;
   let root = KeyPair::new(&mut rng);
 
-  let mut builder = Biscuit::builder(&mut rng, &root);
+  let mut builder = Biscuit::builder(&root);
   builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
   builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
   builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-  let token = builder.build().unwrap();
+  let token = builder.build(&mut rng).unwrap();
   let data = token.to_vec().unwrap();
 
   b.bytes = data.len() as u64;
   assert_eq!(b.bytes, 208);
   b.iter(|| {
-    let mut builder = Biscuit::builder(&mut rng, &root);
+    let mut builder = Biscuit::builder(&root);
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-    let token = builder.build().unwrap();
+    let token = builder.build(&mut rng).unwrap();
     let data = token.to_vec().unwrap();
   });
 }
@@ -40,12 +41,12 @@ fn append_block_2(b: &mut Bencher) {
   let root = KeyPair::new(&mut rng);
   let keypair2 = KeyPair::new(&mut rng);
 
-  let mut builder = Biscuit::builder(&mut rng, &root);
+  let mut builder = Biscuit::builder(&root);
   builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
   builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
   builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-  let token = builder.build().unwrap();
+  let token = builder.build(&mut rng).unwrap();
   let base_data = token.to_vec().unwrap();
   
   let mut block_builder = token.create_block();
@@ -77,12 +78,12 @@ fn append_block_5(b: &mut Bencher) {
   let keypair4 = KeyPair::new(&mut rng);
   let keypair5 = KeyPair::new(&mut rng);
 
-  let mut builder = Biscuit::builder(&mut rng, &root);
+  let mut builder = Biscuit::builder(&root);
   builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
   builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
   builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-  let token = builder.build().unwrap();
+  let token = builder.build(&mut rng).unwrap();
   let base_data = token.to_vec().unwrap();
   
   let mut block_builder = token.create_block();
@@ -128,12 +129,12 @@ fn verify_block_2(b: &mut Bencher) {
   let keypair2 = KeyPair::new(&mut rng);
 
   let data = {
-    let mut builder = Biscuit::builder(&mut rng, &root);
+    let mut builder = Biscuit::builder(&root);
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-    let token = builder.build().unwrap();
+    let token = builder.build(&mut rng).unwrap();
     let base_data = token.to_vec().unwrap();
     
     let mut block_builder = token.create_block();
@@ -170,12 +171,12 @@ fn verify_block_5(b: &mut Bencher) {
   let keypair5 = KeyPair::new(&mut rng);
 
   let data = {
-    let mut builder = Biscuit::builder(&mut rng, &root);
+    let mut builder = Biscuit::builder(&root);
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-    let token = builder.build().unwrap();
+    let token = builder.build(&mut rng).unwrap();
     let base_data = token.to_vec().unwrap();
 
     let mut block_builder = token.create_block();
@@ -227,12 +228,12 @@ fn check_signature_2(b: &mut Bencher) {
   let keypair2 = KeyPair::new(&mut rng);
 
   let data = {
-    let mut builder = Biscuit::builder(&mut rng, &root);
+    let mut builder = Biscuit::builder(&root);
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-    let token = builder.build().unwrap();
+    let token = builder.build(&mut rng).unwrap();
     let base_data = token.to_vec().unwrap();
 
     let mut block_builder = token.create_block();
@@ -266,12 +267,12 @@ fn check_signature_5(b: &mut Bencher) {
   let keypair5 = KeyPair::new(&mut rng);
 
   let data = {
-    let mut builder = Biscuit::builder(&mut rng, &root);
+    let mut builder = Biscuit::builder(&root);
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-    let token = builder.build().unwrap();
+    let token = builder.build(&mut rng).unwrap();
     let base_data = token.to_vec().unwrap();
     
     let mut block_builder = token.create_block();
@@ -319,12 +320,12 @@ fn caveats_block_2(b: &mut Bencher) {
   let keypair2 = KeyPair::new(&mut rng);
 
   let data = {
-    let mut builder = Biscuit::builder(&mut rng, &root);
+    let mut builder = Biscuit::builder(&root);
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-    let token = builder.build().unwrap();
+    let token = builder.build(&mut rng).unwrap();
     let base_data = token.to_vec().unwrap();
     
     let mut block_builder = token.create_block();
@@ -358,12 +359,12 @@ fn caveats_block_create_verifier2(b: &mut Bencher) {
   let keypair2 = KeyPair::new(&mut rng);
 
   let data = {
-    let mut builder = Biscuit::builder(&mut rng, &root);
+    let mut builder = Biscuit::builder(&root);
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-    let token = builder.build().unwrap();
+    let token = builder.build(&mut rng).unwrap();
     let base_data = token.to_vec().unwrap();
 
     let mut block_builder = token.create_block();
@@ -394,12 +395,12 @@ fn caveats_block_verify_only2(b: &mut Bencher) {
   let keypair2 = KeyPair::new(&mut rng);
 
   let data = {
-    let mut builder = Biscuit::builder(&mut rng, &root);
+    let mut builder = Biscuit::builder(&root);
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file2"), s("read")],));
     builder.add_authority_fact(fact("right", &[s("authority"), string("file1"), s("write")],));
 
-    let token = builder.build().unwrap();
+    let token = builder.build(&mut rng).unwrap();
     let base_data = token.to_vec().unwrap();
 
     let mut block_builder = token.create_block();
@@ -425,3 +426,38 @@ fn caveats_block_verify_only2(b: &mut Bencher) {
     verifier.verify().unwrap();
   });
 }
+
+#[bench]
+fn verify_chained_rules(b: &mut Bencher) {
+  let mut rng: OsRng = OsRng;
+  let root = KeyPair::new(&mut rng);
+
+  let token = {
+    let mut builder = Biscuit::builder(&root);
+    builder.add_authority_fact(fact("right", &[s("authority"), string("/folder0"), s("read")],));
+
+    builder.build(&mut rng).unwrap()
+  };
+
+  b.iter(|| {
+    let mut verifier = token.verify(root.public()).unwrap();
+    for i in 0..30 {
+      verifier.add_fact(fact("parent", &[string(&format!("/folder{}", i)), string(&format!("/folder{}", i + 1))])).unwrap();
+    }
+    for i in 0..200 {
+      verifier.add_fact(fact("owner", &[s("ambient"), string(&format!("user{}", i)), string(&format!("/folder{}", i % 30))])).unwrap();
+    }
+    verifier.add_rule("*ancestor($0, $1) <- parent($0, $1)").unwrap();
+    verifier.add_rule("*ancestor($0, $2) <- ancestor($0, $1), parent($1, $2)").unwrap();
+    verifier.add_rule("*right(#authority, $1, $2) <- right(#authority, $0, $2), ancestor($0, $1)").unwrap();
+    verifier.add_resource("/folder30");
+    verifier.add_operation("read");
+    verifier.add_caveat("*can_read($0) <- resource(#ambient, $0), operation(#ambient, #read), right(#authority, $0, #read)").unwrap();
+    verifier.set_limits(RunLimits {
+      max_facts: 10_000,
+      max_iterations: 100,
+      max_time: Duration::from_secs(1),
+    });
+    verifier.verify().unwrap();
+  });
+}
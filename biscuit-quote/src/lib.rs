@@ -0,0 +1,382 @@
+//! macros parsing Datalog at compile time
+//!
+//! `fact!`, `rule!` and `caveat!` take a Datalog text, in the same syntax as
+//! the strings accepted by the builder, and expand to the corresponding
+//! `biscuit_auth::token::builder::{Fact, Rule, Caveat}` value. A syntax error
+//! is reported when compiling, instead of when the string is parsed at runtime.
+//!
+//! Rust expressions can be used as terms with parameters, like `{path}`. A
+//! parameter takes the value of the argument with the same name, or of the
+//! variable with that name if there is no such argument. The value is converted
+//! with `Atom::from`, so it is always a single term, like a string or an integer:
+//!
+//! ```rust
+//! use biscuit_quote::{caveat, fact, rule};
+//!
+//! let path = "/a/file1.txt";
+//! let right = fact!(r#"right(#authority, {path}, {operation})"#, operation = 12);
+//! assert_eq!(right.to_string(), r#"right(#authority, "/a/file1.txt", 12)"#);
+//!
+//! let r = rule!("*can_read($f) <- right(#authority, $f, #read)");
+//! let c = caveat!("*check($f) <- resource(#ambient, $f) @ $f == {path}");
+//! assert_eq!(c.to_string(), r#"*check($f) <- resource(#ambient, $f) @ $f == "/a/file1.txt""#);
+//! ```
+extern crate proc_macro;
+
+use biscuit_auth::error;
+use biscuit_auth::token::builder::{
+    AggregateKind, Atom, Binary, BytesConstraint, Caveat, Constraint, ConstraintKind,
+    DateConstraint, Expression, Fact, IntConstraint, Op, Predicate, Rule, SetConstraint,
+    StrConstraint, SymbolConstraint,
+};
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, ToTokens};
+use std::collections::{BTreeSet, HashSet};
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Expr, Ident, LitStr, Token};
+
+/// creates a `Fact` from a Datalog text, like `fact!("right(#authority, {path}, #read)")`
+#[proc_macro]
+pub fn fact(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as Input);
+    expand(input, |source| Fact::try_from(source), fact_tokens)
+}
+
+/// creates a `Rule` from a Datalog text, like
+/// `rule!("*can_read($f) <- right(#authority, $f, #read)")`
+#[proc_macro]
+pub fn rule(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as Input);
+    expand(input, |source| Rule::try_from(source), rule_tokens)
+}
+
+/// creates a `Caveat` from a Datalog text, with one or more queries
+/// separated by `||`
+#[proc_macro]
+pub fn caveat(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as Input);
+    expand(input, |source| Caveat::try_from(source), caveat_tokens)
+}
+
+/// Datalog text followed by the named arguments: `"...", name = expr, ...`
+struct Input {
+    source: LitStr,
+    arguments: Vec<(Ident, Expr)>,
+}
+
+impl Parse for Input {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let source = input.parse()?;
+        let mut arguments = Vec::new();
+
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+
+            let name: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            let value: Expr = input.parse()?;
+            if arguments.iter().any(|(n, _)| *n == name) {
+                return Err(syn::Error::new(name.span(), "duplicate parameter"));
+            }
+            arguments.push((name, value));
+        }
+
+        Ok(Input { source, arguments })
+    }
+}
+
+/// values of the parameters, and the ones that were used
+struct Parameters {
+    arguments: Vec<(Ident, Expr)>,
+    used: HashSet<String>,
+    span: Span,
+    errors: Vec<syn::Error>,
+}
+
+impl Parameters {
+    fn new(arguments: Vec<(Ident, Expr)>, span: Span) -> Self {
+        Parameters {
+            arguments,
+            used: HashSet::new(),
+            span,
+            errors: Vec::new(),
+        }
+    }
+
+    fn value(&mut self, name: &str) -> TokenStream {
+        self.used.insert(name.to_string());
+        if let Some((_, value)) = self.arguments.iter().find(|(n, _)| n == name) {
+            return value.to_token_stream();
+        }
+
+        // captures the variable with the same name
+        match syn::parse_str::<Ident>(name) {
+            Ok(ident) => Ident::new(&ident.to_string(), self.span).to_token_stream(),
+            Err(_) => {
+                self.errors.push(syn::Error::new(
+                    self.span,
+                    format!("the parameter {{{}}} needs an argument", name),
+                ));
+                quote!(())
+            }
+        }
+    }
+
+    /// reports the arguments that do not match a parameter
+    fn check(mut self) -> Result<(), syn::Error> {
+        for (name, _) in self.arguments.iter() {
+            if !self.used.contains(&name.to_string()) {
+                self.errors.push(syn::Error::new(
+                    name.span(),
+                    format!("the parameter {{{}}} does not appear in the Datalog text", name),
+                ));
+            }
+        }
+
+        let mut errors = self.errors.into_iter();
+        match errors.next() {
+            None => Ok(()),
+            Some(mut error) => {
+                errors.for_each(|e| error.combine(e));
+                Err(error)
+            }
+        }
+    }
+}
+
+fn expand<T, P, G>(input: Input, parse: P, generate: G) -> proc_macro::TokenStream
+where
+    P: Fn(&str) -> Result<T, error::Token>,
+    G: Fn(&T, &mut Parameters) -> TokenStream,
+{
+    let source = input.source.value();
+    let value = match parse(&source) {
+        Ok(value) => value,
+        Err(e) => {
+            // the position of a syntax error is more useful than the generic message
+            let message = match e {
+                error::Token::ParseError(e) => e.to_string(),
+                e => e.to_string(),
+            };
+            return syn::Error::new(input.source.span(), message).to_compile_error().into();
+        }
+    };
+
+    let mut parameters = Parameters::new(input.arguments, input.source.span());
+    let tokens = generate(&value, &mut parameters);
+
+    match parameters.check() {
+        Ok(()) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn builder() -> TokenStream {
+    quote!(::biscuit_auth::token::builder)
+}
+
+fn fact_tokens(fact: &Fact, parameters: &mut Parameters) -> TokenStream {
+    let b = builder();
+    let predicate = predicate_tokens(&fact.0, parameters);
+    quote!(#b::Fact(#predicate))
+}
+
+fn rule_tokens(rule: &Rule, parameters: &mut Parameters) -> TokenStream {
+    let b = builder();
+    let head = predicate_tokens(&rule.head, parameters);
+    let body = rule.body.iter().map(|p| predicate_tokens(p, parameters)).collect::<Vec<_>>();
+    let constraints = rule.constraints.iter().map(constraint_tokens).collect::<Vec<_>>();
+    let expressions = rule.expressions.iter().map(|e| expression_tokens(e, parameters)).collect::<Vec<_>>();
+    let negated = rule.negated.iter().map(|p| predicate_tokens(p, parameters)).collect::<Vec<_>>();
+
+    quote!(#b::Rule {
+        head: #head,
+        body: vec![#(#body),*],
+        constraints: vec![#(#constraints),*],
+        expressions: vec![#(#expressions),*],
+        negated: vec![#(#negated),*],
+    })
+}
+
+fn caveat_tokens(caveat: &Caveat, parameters: &mut Parameters) -> TokenStream {
+    let b = builder();
+    let queries = caveat.queries.iter().map(|q| rule_tokens(q, parameters)).collect::<Vec<_>>();
+    quote!(#b::Caveat { queries: vec![#(#queries),*] })
+}
+
+fn predicate_tokens(predicate: &Predicate, parameters: &mut Parameters) -> TokenStream {
+    let b = builder();
+    let name = &predicate.name;
+    let ids = predicate.ids.iter().map(|a| atom_tokens(a, parameters)).collect::<Vec<_>>();
+    quote!(#b::Predicate { name: #name.to_string(), ids: vec![#(#ids),*] })
+}
+
+fn atom_tokens(atom: &Atom, parameters: &mut Parameters) -> TokenStream {
+    let b = builder();
+    match atom {
+        Atom::Symbol(s) => quote!(#b::Atom::Symbol(#s.to_string())),
+        Atom::Variable(v) => quote!(#b::Atom::Variable(#v.to_string())),
+        Atom::Integer(i) => quote!(#b::Atom::Integer(#i)),
+        Atom::Str(s) => quote!(#b::Atom::Str(#s.to_string())),
+        Atom::Date(d) => quote!(#b::Atom::Date(#d)),
+        Atom::Bytes(bytes) => quote!(#b::Atom::Bytes(vec![#(#bytes),*])),
+        Atom::Bool(v) => quote!(#b::Atom::Bool(#v)),
+        Atom::Set(s) => {
+            let atoms = s.iter().map(|a| atom_tokens(a, parameters)).collect::<Vec<_>>();
+            quote!(#b::Atom::Set(vec![#(#atoms),*].into_iter().collect()))
+        }
+        Atom::Aggregate(kind, v) => {
+            let kind = match kind {
+                AggregateKind::Count => quote!(Count),
+                AggregateKind::Min => quote!(Min),
+                AggregateKind::Max => quote!(Max),
+                AggregateKind::Sum => quote!(Sum),
+            };
+            quote!(#b::Atom::Aggregate(#b::AggregateKind::#kind, #v.to_string()))
+        }
+        Atom::Parameter(name) => {
+            let value = parameters.value(name);
+            quote!(#b::Atom::from(#value))
+        }
+    }
+}
+
+fn expression_tokens(expression: &Expression, parameters: &mut Parameters) -> TokenStream {
+    let b = builder();
+    let ops = expression.ops.iter().map(|op| match op {
+        Op::Value(a) => {
+            let a = atom_tokens(a, parameters);
+            quote!(#b::Op::Value(#a))
+        }
+        Op::Binary(binary) => {
+            let binary = match binary {
+                Binary::LessThan => quote!(LessThan),
+                Binary::GreaterThan => quote!(GreaterThan),
+                Binary::LessOrEqual => quote!(LessOrEqual),
+                Binary::GreaterOrEqual => quote!(GreaterOrEqual),
+                Binary::Equal => quote!(Equal),
+                Binary::NotEqual => quote!(NotEqual),
+                Binary::StartsWith => quote!(StartsWith),
+                Binary::EndsWith => quote!(EndsWith),
+                Binary::Contains => quote!(Contains),
+                Binary::Add => quote!(Add),
+                Binary::Sub => quote!(Sub),
+                Binary::Mul => quote!(Mul),
+                Binary::Div => quote!(Div),
+            };
+            quote!(#b::Op::Binary(#b::Binary::#binary))
+        }
+    }).collect::<Vec<_>>();
+
+    quote!(#b::Expression { ops: vec![#(#ops),*] })
+}
+
+fn constraint_tokens(constraint: &Constraint) -> TokenStream {
+    let b = builder();
+    let id = &constraint.id;
+    let kind = match &constraint.kind {
+        ConstraintKind::Integer(c) => {
+            let c = match c {
+                IntConstraint::Lower(i) => quote!(Lower(#i)),
+                IntConstraint::Larger(i) => quote!(Larger(#i)),
+                IntConstraint::LowerOrEqual(i) => quote!(LowerOrEqual(#i)),
+                IntConstraint::LargerOrEqual(i) => quote!(LargerOrEqual(#i)),
+                IntConstraint::Equal(i) => quote!(Equal(#i)),
+                IntConstraint::In(h) => in_tokens(quote!(In), h.iter()),
+                IntConstraint::NotIn(h) => in_tokens(quote!(NotIn), h.iter()),
+            };
+            quote!(Integer(#b::IntConstraint::#c))
+        }
+        ConstraintKind::String(c) => {
+            let c = match c {
+                StrConstraint::Prefix(s) => quote!(Prefix(#s.to_string())),
+                StrConstraint::Suffix(s) => quote!(Suffix(#s.to_string())),
+                StrConstraint::Equal(s) => quote!(Equal(#s.to_string())),
+                StrConstraint::In(h) => in_tokens(quote!(In), h.iter().map(|s| quote!(#s.to_string()))),
+                StrConstraint::NotIn(h) => in_tokens(quote!(NotIn), h.iter().map(|s| quote!(#s.to_string()))),
+                // the regular expression was compiled when parsing
+                StrConstraint::Regex(r) => {
+                    let r = r.as_str();
+                    quote!(Regex(#b::Regex::new(#r).unwrap()))
+                }
+            };
+            quote!(String(#b::StrConstraint::#c))
+        }
+        ConstraintKind::Date(c) => {
+            let c = match c {
+                DateConstraint::Before(t) => {
+                    let t = date_tokens(t);
+                    quote!(Before(#t))
+                }
+                DateConstraint::After(t) => {
+                    let t = date_tokens(t);
+                    quote!(After(#t))
+                }
+            };
+            quote!(Date(#b::DateConstraint::#c))
+        }
+        ConstraintKind::Symbol(c) => {
+            let c = match c {
+                SymbolConstraint::In(h) => in_tokens(quote!(In), h.iter().map(|s| quote!(#s.to_string()))),
+                SymbolConstraint::NotIn(h) => in_tokens(quote!(NotIn), h.iter().map(|s| quote!(#s.to_string()))),
+            };
+            quote!(Symbol(#b::SymbolConstraint::#c))
+        }
+        ConstraintKind::Bytes(c) => {
+            let bytes = |v: &Vec<u8>| quote!(vec![#(#v),*]);
+            let c = match c {
+                BytesConstraint::Equal(v) => {
+                    let v = bytes(v);
+                    quote!(Equal(#v))
+                }
+                BytesConstraint::In(h) => in_tokens(quote!(In), h.iter().map(bytes)),
+                BytesConstraint::NotIn(h) => in_tokens(quote!(NotIn), h.iter().map(bytes)),
+            };
+            quote!(Bytes(#b::BytesConstraint::#c))
+        }
+        ConstraintKind::Set(c) => {
+            // the parser does not accept parameters in constraints
+            let mut parameters = Parameters::new(Vec::new(), Span::call_site());
+            let mut set = |s: &BTreeSet<Atom>| {
+                let atoms = s.iter().map(|a| atom_tokens(a, &mut parameters)).collect::<Vec<_>>();
+                quote!(vec![#(#atoms),*].into_iter().collect())
+            };
+            let c = match c {
+                SetConstraint::Contains(a) => {
+                    let a = atom_tokens(a, &mut Parameters::new(Vec::new(), Span::call_site()));
+                    quote!(Contains(#a))
+                }
+                SetConstraint::Intersects(s) => {
+                    let s = set(s);
+                    quote!(Intersects(#s))
+                }
+                SetConstraint::Subset(s) => {
+                    let s = set(s);
+                    quote!(Subset(#s))
+                }
+            };
+            quote!(Set(#b::SetConstraint::#c))
+        }
+    };
+
+    quote!(#b::Constraint { id: #id.to_string(), kind: #b::ConstraintKind::#kind })
+}
+
+/// values of an `in` or `not in` constraint, the generated code
+/// collects them in a `HashSet`
+fn in_tokens<T: ToTokens, I: Iterator<Item = T>>(variant: TokenStream, values: I) -> TokenStream {
+    let mut values = values.map(|v| v.to_token_stream()).collect::<Vec<_>>();
+    // hash sets are not ordered, sort the values to get a stable expansion
+    values.sort_by_key(|v| v.to_string());
+    quote!(#variant(vec![#(#values),*].into_iter().collect()))
+}
+
+fn date_tokens(t: &SystemTime) -> TokenStream {
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    quote!(::std::time::UNIX_EPOCH + ::std::time::Duration::from_secs(#secs))
+}
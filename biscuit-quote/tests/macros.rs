@@ -0,0 +1,111 @@
+use biscuit_auth::crypto::KeyPair;
+use biscuit_auth::token::builder::{self, Caveat, Fact, Rule};
+use biscuit_auth::token::Biscuit;
+use biscuit_quote::{caveat, fact, rule};
+use rand::{prelude::StdRng, SeedableRng};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn same_as_parser() {
+    assert_eq!(
+        fact!(r#"right(#authority, "file1", #read, 12, true, hex:0a0b, 2020-12-04T09:46:41Z, [1, "a", #b])"#),
+        Fact::try_from(r#"right(#authority, "file1", #read, 12, true, hex:0a0b, 2020-12-04T09:46:41Z, [1, "a", #b])"#)
+            .unwrap()
+    );
+
+    let rules = [
+        "*nb($u, count($f)) <- right(#authority, $f, $u), !banned($u)",
+        "*valid($t) <- time(#ambient, $t) @ $t < 2020-12-04T09:46:41+00:00, $t > 2019-12-04T09:46:41+00:00",
+        "*valid($x) <- value($x, $y) @ $x > 1, $x in [1, 2, 3], $y not in [\"a\", \"b\"]",
+        "*valid($x) <- value($x) @ $x matches /file[0-9]+.txt/, $x matches \"/a/*\"",
+        "*valid($x) <- value($x) @ $x in [#a, #b], $x == hex:0102",
+        "*valid($x) <- value($x) @ $x contains #a, $x intersects [1, 2]",
+        "*valid($x) <- value($x, $y) @ $x + 1 <= $y * 2 - 3, $x.starts_with(\"a\")",
+    ];
+    for r in rules.iter() {
+        let expected = Rule::try_from(*r).unwrap();
+        assert_eq!(expected.to_string(), *r);
+    }
+    // the macros need literals, so the rules are repeated
+    let expanded = [
+        rule!("*nb($u, count($f)) <- right(#authority, $f, $u), !banned($u)"),
+        rule!("*valid($t) <- time(#ambient, $t) @ $t < 2020-12-04T09:46:41+00:00, $t > 2019-12-04T09:46:41+00:00"),
+        rule!("*valid($x) <- value($x, $y) @ $x > 1, $x in [1, 2, 3], $y not in [\"a\", \"b\"]"),
+        rule!("*valid($x) <- value($x) @ $x matches /file[0-9]+.txt/, $x matches \"/a/*\""),
+        rule!("*valid($x) <- value($x) @ $x in [#a, #b], $x == hex:0102"),
+        rule!("*valid($x) <- value($x) @ $x contains #a, $x intersects [1, 2]"),
+        rule!("*valid($x) <- value($x, $y) @ $x + 1 <= $y * 2 - 3, $x.starts_with(\"a\")"),
+    ];
+    for (r, expanded) in rules.iter().zip(expanded.iter()) {
+        assert_eq!(expanded, &Rule::try_from(*r).unwrap());
+    }
+
+    assert_eq!(
+        caveat!("*check(#read) <- operation(#ambient, #read) || *check($f) <- resource(#ambient, $f), owner($f)"),
+        Caveat::try_from("*check(#read) <- operation(#ambient, #read) || *check($f) <- resource(#ambient, $f), owner($f)")
+            .unwrap()
+    );
+}
+
+#[test]
+fn parameters() {
+    let file = String::from("file1");
+    let date = UNIX_EPOCH + Duration::from_secs(1_607_075_201);
+    let mut set = BTreeSet::new();
+    set.insert(builder::s("read"));
+
+    // named arguments take precedence over the variables
+    let f = fact!(
+        "right(#authority, {file}, {operation}, {n}, {date}, {set})",
+        operation = builder::s("read"),
+        n = 1 + 2,
+        set = set.clone(),
+    );
+    assert_eq!(
+        f.to_string(),
+        "right(#authority, \"file1\", #read, 3, 2020-12-04T09:46:41+00:00, [#read])"
+    );
+    // the value cannot change the structure of the fact
+    let file = "file1\", #write)";
+    let f = fact!("right(#authority, {file})");
+    assert_eq!(f.0.ids[1], builder::string("file1\", #write)"));
+
+    let c = caveat!(
+        "*check($f) <- resource(#ambient, $f) @ $f == {file} || *check({file}) <- owner({file})",
+        file = "file1",
+    );
+    assert_eq!(
+        c.to_string(),
+        "*check($f) <- resource(#ambient, $f) @ $f == \"file1\" || *check(\"file1\") <- owner(\"file1\")"
+    );
+}
+
+#[test]
+fn token() {
+    let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+    let root = KeyPair::new(&mut rng);
+
+    let mut builder = Biscuit::builder(&root);
+    for &file in ["file1", "file2"].iter() {
+        builder.add_authority_fact(fact!("right(#authority, {file}, #read)")).unwrap();
+    }
+    let biscuit1 = builder.build(&mut rng).unwrap();
+
+    let mut block2 = biscuit1.create_block();
+    block2
+        .add_caveat(caveat!("*check($f) <- resource(#ambient, $f), right(#authority, $f, #read)"))
+        .unwrap();
+    let keypair2 = KeyPair::new(&mut rng);
+    let biscuit2 = biscuit1.append(&mut rng, &keypair2, block2.build()).unwrap();
+
+    let mut verifier = biscuit2.verify(root.public()).unwrap();
+    verifier.add_resource("file2");
+    verifier.add_operation("read");
+    verifier.add_rule(rule!("*readable($f) <- right(#authority, $f, #read)")).unwrap();
+    assert!(verifier.verify().is_ok());
+
+    let res: Vec<Fact> = verifier.query(rule!("*readable($f) <- readable($f)")).unwrap();
+    assert_eq!(res.len(), 2);
+}
@@ -856,7 +856,7 @@ fn regex_constraint<T: Rng + CryptoRng>(rng: &mut T, target: &str, root: &KeyPai
         &[
           Constraint {
             id: "0".to_string(),
-            kind: ConstraintKind::String(StrConstraint::Regex("file[0-9]+.txt".to_string())),
+            kind: ConstraintKind::String(StrConstraint::Regex(Regex::new("file[0-9]+.txt").unwrap())),
           },
         ]
     ));
@@ -499,7 +499,7 @@ pub unsafe extern "C" fn verifier_verify(
     let verifier = verifier.unwrap();
 
     match verifier.0.verify() {
-        Ok(()) => true,
+        Ok(_) => true,
         Err(e) => {
             update_last_error(Error::Biscuit(e));
             false
@@ -18,7 +18,7 @@ use curve25519_dalek::{
 use zeroize::Zeroize;
 use rand_core::{RngCore, CryptoRng};
 use sha2::{Digest, Sha512};
-use std::{ops::{Deref, Drop}, convert::TryInto};
+use std::{collections::BTreeMap, ops::{Deref, Drop}, convert::TryInto};
 
 pub struct KeyPair {
     pub(crate) private: Scalar,
@@ -113,6 +113,73 @@ impl PublicKey {
     }
 }
 
+/// chooses the root public keys that can have created a token
+///
+/// a single `PublicKey` is a provider for itself
+pub trait RootKeyProvider {
+    /// keys tried in order for a token with this root key identifier
+    fn keys(&self, root_key_id: Option<u32>) -> Vec<PublicKey>;
+}
+
+impl RootKeyProvider for PublicKey {
+    fn keys(&self, _root_key_id: Option<u32>) -> Vec<PublicKey> {
+        vec![*self]
+    }
+}
+
+impl RootKeyProvider for [PublicKey] {
+    fn keys(&self, _root_key_id: Option<u32>) -> Vec<PublicKey> {
+        self.to_vec()
+    }
+}
+
+impl<K: RootKeyProvider + ?Sized> RootKeyProvider for &K {
+    fn keys(&self, root_key_id: Option<u32>) -> Vec<PublicKey> {
+        (**self).keys(root_key_id)
+    }
+}
+
+/// root public keys indexed by their identifier, to rotate the root key
+///
+/// a token with a root key identifier is only checked with the key for
+/// that identifier, a token without one is checked with all the keys
+#[derive(Debug, Clone, Default)]
+pub struct KeyStore {
+    keys: BTreeMap<u32, PublicKey>,
+}
+
+impl KeyStore {
+    pub fn new() -> Self {
+        KeyStore::default()
+    }
+
+    pub fn insert(&mut self, id: u32, key: PublicKey) {
+        self.keys.insert(id, key);
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<PublicKey> {
+        self.keys.remove(&id)
+    }
+
+    pub fn get(&self, id: u32) -> Option<PublicKey> {
+        self.keys.get(&id).copied()
+    }
+
+    /// identifier of a key
+    pub fn id(&self, key: &PublicKey) -> Option<u32> {
+        self.keys.iter().find(|(_, k)| *k == key).map(|(id, _)| *id)
+    }
+}
+
+impl RootKeyProvider for KeyStore {
+    fn keys(&self, root_key_id: Option<u32>) -> Vec<PublicKey> {
+        match root_key_id {
+            Some(id) => self.get(id).into_iter().collect(),
+            None => self.keys.values().copied().collect(),
+        }
+    }
+}
+
 #[allow(dead_code)]
 /// test structure for aggregated signatures
 struct Token {
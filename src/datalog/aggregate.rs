@@ -0,0 +1,263 @@
+//! aggregates in rule heads, like `nb_files(count($f)) <- right(#authority, $f, #read)`
+//!
+//! an aggregate replaces a variable of the head. The matches of the rule body
+//! are grouped by the values of the other head terms, and the aggregate is
+//! computed over the values of its variable in each group.
+//!
+//! Like the rest of Datalog, matches are sets: each distinct binding of the
+//! body variables is only counted once
+use super::{Fact, Predicate, RunBudget, ID};
+use crate::error;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AggregateKind {
+    Count,
+    Min,
+    Max,
+    Sum,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    /// position of the aggregated variable in the head
+    pub position: u32,
+    pub kind: AggregateKind,
+}
+
+impl AggregateKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AggregateKind::Count => "count",
+            AggregateKind::Min => "min",
+            AggregateKind::Max => "max",
+            AggregateKind::Sum => "sum",
+        }
+    }
+
+    /// computes the aggregate over a list of values
+    ///
+    /// `sum` requires integers, `min` and `max` require values of the same
+    /// type, either integers, dates or strings. There is no result if the
+    /// values do not have the right type, if the sum overflows, or for the
+    /// minimum or maximum of an empty list
+    pub fn compute(self, values: &[&ID]) -> Option<ID> {
+        match self {
+            AggregateKind::Count => Some(ID::Integer(values.len() as i64)),
+            AggregateKind::Sum => values.iter().try_fold(0i64, |sum, id| match id {
+                ID::Integer(i) => sum.checked_add(*i),
+                _ => None,
+            }).map(ID::Integer),
+            AggregateKind::Min => extremum(values, |a, b| a < b),
+            AggregateKind::Max => extremum(values, |a, b| a > b),
+        }
+    }
+}
+
+/// finds the value for which `better` is true compared to every other value
+fn extremum<F>(values: &[&ID], better: F) -> Option<ID>
+where
+    F: Fn(&Comparable, &Comparable) -> bool,
+{
+    let mut result: Option<Comparable> = None;
+
+    for id in values.iter() {
+        let value = match id {
+            ID::Integer(i) => Comparable::Integer(*i),
+            ID::Date(d) => Comparable::Date(*d),
+            ID::Str(s) => Comparable::Str(s.as_str()),
+            _ => return None,
+        };
+
+        result = match result {
+            None => Some(value),
+            Some(current) => {
+                if std::mem::discriminant(&current) != std::mem::discriminant(&value) {
+                    return None;
+                }
+                if better(&value, &current) {
+                    Some(value)
+                } else {
+                    Some(current)
+                }
+            }
+        };
+    }
+
+    result.map(|value| match value {
+        Comparable::Integer(i) => ID::Integer(i),
+        Comparable::Date(d) => ID::Date(d),
+        Comparable::Str(s) => ID::Str(s.to_string()),
+    })
+}
+
+#[derive(PartialEq, PartialOrd)]
+enum Comparable<'a> {
+    Integer(i64),
+    Date(u64),
+    Str(&'a str),
+}
+
+/// fact generated by an aggregate, with the matches of its group
+pub(crate) type AggregateFact = (Fact, Vec<HashMap<u32, ID>>);
+
+/// generates the facts of a rule with aggregates in its head, from the matches of its body
+///
+/// each fact comes with the matches of its group. The matches are kept until
+/// all of them are known, so their number is limited like the number of facts
+pub(crate) fn aggregate_facts<I>(
+    head: &Predicate,
+    aggregates: &[Aggregate],
+    bindings: I,
+    budget: Option<&RunBudget>,
+) -> Result<Vec<AggregateFact>, error::RunLimit>
+where
+    I: Iterator<Item = HashMap<u32, ID>>,
+{
+    let is_aggregate = |index: usize| aggregates.iter().any(|a| a.position as usize == index);
+
+    let mut seen = HashSet::new();
+    // the key contains the values of the head terms that are not aggregates
+    let mut groups: HashMap<Vec<Option<ID>>, Vec<HashMap<u32, ID>>> = HashMap::new();
+
+    'bindings: for binding in bindings {
+        if let Some(budget) = budget {
+            budget.check(seen.len())?;
+        }
+
+        let mut key = binding.iter().map(|(k, v)| (*k, v.clone())).collect::<Vec<_>>();
+        key.sort_by_key(|(k, _)| *k);
+        if !seen.insert(key) {
+            continue;
+        }
+
+        let mut group = Vec::with_capacity(head.ids.len());
+        for (index, id) in head.ids.iter().enumerate() {
+            if is_aggregate(index) {
+                group.push(None);
+                continue;
+            }
+
+            match id {
+                ID::Variable(v) => match binding.get(v) {
+                    Some(value) => group.push(Some(value.clone())),
+                    // the head variable does not appear in the body
+                    None => continue 'bindings,
+                },
+                id => group.push(Some(id.clone())),
+            }
+        }
+
+        groups.entry(group).or_default().push(binding);
+    }
+
+    // without any match, the aggregates are still computed if the head has no
+    // other variable, so that `nb(count($f))` generates `nb(0)`
+    let has_group_variables = head
+        .ids
+        .iter()
+        .enumerate()
+        .any(|(index, id)| !is_aggregate(index) && matches!(id, ID::Variable(_)));
+    if groups.is_empty() && !has_group_variables {
+        let group = head
+            .ids
+            .iter()
+            .enumerate()
+            .map(|(index, id)| if is_aggregate(index) { None } else { Some(id.clone()) })
+            .collect();
+        groups.insert(group, Vec::new());
+    }
+
+    let mut facts = Vec::new();
+    'groups: for (group, bindings) in groups.drain() {
+        let mut ids = Vec::with_capacity(group.len());
+
+        for (index, id) in group.into_iter().enumerate() {
+            match id {
+                Some(id) => ids.push(id),
+                None => {
+                    let kind = aggregates
+                        .iter()
+                        .find(|a| a.position as usize == index)
+                        .map(|a| a.kind)
+                        .unwrap();
+                    let values = match &head.ids[index] {
+                        ID::Variable(v) => bindings.iter().filter_map(|b| b.get(v)).collect::<Vec<_>>(),
+                        _ => Vec::new(),
+                    };
+
+                    match kind.compute(&values) {
+                        Some(value) => ids.push(value),
+                        None => continue 'groups,
+                    }
+                }
+            }
+        }
+
+        let fact = Fact {
+            predicate: Predicate { name: head.name, ids },
+        };
+        facts.push((fact, bindings));
+    }
+
+    Ok(facts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute() {
+        let values = [ID::Integer(3), ID::Integer(-1), ID::Integer(10)];
+        let values = values.iter().collect::<Vec<_>>();
+        assert_eq!(AggregateKind::Count.compute(&values), Some(ID::Integer(3)));
+        assert_eq!(AggregateKind::Sum.compute(&values), Some(ID::Integer(12)));
+        assert_eq!(AggregateKind::Min.compute(&values), Some(ID::Integer(-1)));
+        assert_eq!(AggregateKind::Max.compute(&values), Some(ID::Integer(10)));
+
+        let values = [ID::Str("b".to_string()), ID::Str("a".to_string())];
+        let values = values.iter().collect::<Vec<_>>();
+        assert_eq!(AggregateKind::Min.compute(&values), Some(ID::Str("a".to_string())));
+        assert_eq!(AggregateKind::Sum.compute(&values), None);
+
+        let values = [ID::Integer(1), ID::Date(2)];
+        let values = values.iter().collect::<Vec<_>>();
+        assert_eq!(AggregateKind::Max.compute(&values), None);
+
+        let values = [ID::Integer(i64::MAX), ID::Integer(1)];
+        let values = values.iter().collect::<Vec<_>>();
+        assert_eq!(AggregateKind::Sum.compute(&values), None);
+
+        assert_eq!(AggregateKind::Sum.compute(&[]), Some(ID::Integer(0)));
+        assert_eq!(AggregateKind::Min.compute(&[]), None);
+    }
+
+    #[test]
+    fn limits() {
+        use super::super::RunLimits;
+
+        // `nb(count($x)) <- a($x), b($y)`, with a hundred matches
+        let head = Predicate { name: 0, ids: vec![ID::Variable(1)] };
+        let aggregates = [Aggregate { position: 0, kind: AggregateKind::Count }];
+        let bindings = || {
+            (0..10).flat_map(|x| {
+                (0..10).map(move |y| {
+                    [(1, ID::Integer(x)), (2, ID::Integer(y))].iter().cloned().collect::<HashMap<_, _>>()
+                })
+            })
+        };
+
+        let facts = aggregate_facts(&head, &aggregates, bindings(), None).unwrap();
+        assert_eq!(facts[0].0.predicate.ids, vec![ID::Integer(100)]);
+
+        let budget = RunBudget::new(RunLimits {
+            max_facts: 50,
+            ..Default::default()
+        });
+        assert_eq!(
+            aggregate_facts(&head, &aggregates, bindings(), Some(&budget)),
+            Err(error::RunLimit::TooManyFacts)
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! diagnostics for rules that do not generate any fact
+//!
+//! the body predicates are matched in order, to find the first one that
+//! cannot be matched along with the previous ones. The facts with the same
+//! name are then compared to that predicate, to find the ones that came
+//! closest to matching it
+use super::{CombineIt, Constraint, Fact, FactView, MatchedVariables, Predicate, Rule, ID};
+use std::collections::HashMap;
+
+/// maximum number of partial matches reported for a predicate
+const MAX_CLOSEST: usize = 5;
+/// maximum number of matches of the previous predicates compared to the facts
+const MAX_BINDINGS: usize = 100;
+
+/// why a rule did not generate any fact
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleFailure {
+    /// no fact matched this body predicate, with the variables
+    /// bound by the previous predicates
+    UnmatchedPredicate {
+        /// index of the predicate in the rule body
+        index: usize,
+        /// facts that came closest to matching the predicate
+        closest: Vec<PartialMatch>,
+    },
+    /// all the body predicates matched, but the expressions or
+    /// negated predicates rejected every match
+    FailedConditions,
+}
+
+/// fact that matched some of the terms of a predicate
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialMatch {
+    pub fact: Fact,
+    /// positions of the terms that did not match, with the expected value,
+    /// or the variable if the value did not satisfy its constraints
+    pub mismatches: Vec<(usize, ID)>,
+}
+
+impl Rule {
+    /// explains why the rule does not generate any fact, returns None if it does
+    pub fn diagnose(&self, facts: &FactView) -> Option<RuleFailure> {
+        let mut generated = false;
+        self.apply_view(facts, None, &mut |_, _| {
+            generated = true;
+            // one fact is enough
+            false
+        });
+        if generated {
+            return None;
+        }
+
+        let sources = vec![facts; self.body.len()];
+        for index in 0..self.body.len() {
+            let matched = bindings(&self.body[..=index], &self.constraints, &sources)
+                .next()
+                .is_some();
+
+            if !matched {
+                let previous = bindings(&self.body[..index], &self.constraints, &sources)
+                    .take(MAX_BINDINGS)
+                    .collect::<Vec<_>>();
+
+                return Some(RuleFailure::UnmatchedPredicate {
+                    index,
+                    closest: closest_matches(
+                        &self.body[index],
+                        &self.constraints,
+                        &previous,
+                        facts,
+                    ),
+                });
+            }
+        }
+
+        Some(RuleFailure::FailedConditions)
+    }
+}
+
+/// matches of a list of predicates, checking the constraints on their variables
+fn bindings<'a>(
+    predicates: &'a [Predicate],
+    constraints: &'a [Constraint],
+    sources: &'a [&'a FactView<'a>],
+) -> Box<dyn Iterator<Item = HashMap<u32, ID>> + 'a> {
+    if predicates.is_empty() {
+        return Box::new(std::iter::once(HashMap::new()));
+    }
+
+    let variables = predicates
+        .iter()
+        .flat_map(|p| p.ids.iter())
+        .filter_map(|id| match id {
+            ID::Variable(v) => Some(*v),
+            _ => None,
+        })
+        .collect();
+
+    Box::new(CombineIt::new(
+        MatchedVariables::new(variables),
+        predicates,
+        constraints,
+        sources,
+    ))
+}
+
+/// compares the facts with the same name and arity as the predicate, for
+/// each match of the previous predicates, and keeps those with the fewest
+/// mismatched terms
+fn closest_matches(
+    pred: &Predicate,
+    constraints: &[Constraint],
+    previous: &[HashMap<u32, ID>],
+    facts: &FactView,
+) -> Vec<PartialMatch> {
+    let mut closest: Vec<PartialMatch> = Vec::new();
+
+    // every fact with the same name, whatever their terms
+    let name = Predicate { name: pred.name, ids: vec![] };
+    let candidates = facts
+        .iter_matching(&name)
+        .filter(|f| f.predicate.ids.len() == pred.ids.len());
+
+    for fact in candidates {
+        let best = previous
+            .iter()
+            .map(|binding| mismatches(pred, constraints, binding, fact))
+            .min_by_key(|m| m.len());
+
+        if let Some(mismatches) = best {
+            closest.push(PartialMatch {
+                fact: fact.clone(),
+                mismatches,
+            });
+        }
+    }
+
+    if let Some(min) = closest.iter().map(|m| m.mismatches.len()).min() {
+        closest.retain(|m| m.mismatches.len() == min);
+    }
+    // facts are not ordered, sort them to get a stable result
+    closest.sort_by(|a, b| a.fact.predicate.ids.cmp(&b.fact.predicate.ids));
+    closest.truncate(MAX_CLOSEST);
+    closest
+}
+
+fn mismatches(
+    pred: &Predicate,
+    constraints: &[Constraint],
+    binding: &HashMap<u32, ID>,
+    fact: &Fact,
+) -> Vec<(usize, ID)> {
+    let mut binding = binding.clone();
+    let mut mismatches = Vec::new();
+
+    for (position, (expected, found)) in pred.ids.iter().zip(fact.predicate.ids.iter()).enumerate() {
+        match expected {
+            ID::Variable(v) => match binding.get(v) {
+                Some(value) => {
+                    if value != found {
+                        mismatches.push((position, value.clone()));
+                    }
+                }
+                None => {
+                    if constraints.iter().all(|c| c.check(*v, found)) {
+                        binding.insert(*v, found.clone());
+                    } else {
+                        mismatches.push((position, expected.clone()));
+                    }
+                }
+            },
+            id => {
+                if id != found {
+                    mismatches.push((position, id.clone()));
+                }
+            }
+        }
+    }
+
+    mismatches
+}
@@ -0,0 +1,349 @@
+//! expressions comparing and combining the variables of a rule
+//!
+//! an expression is a list of operations in reverse polish notation:
+//! `$x + 1 == $y` is stored as `[$x, 1, +, $y, ==]`, and evaluated
+//! with a stack once all the variables of the rule are bound
+use super::ID;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    pub ops: Vec<Op>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Value(ID),
+    Binary(Binary),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Binary {
+    LessThan,
+    GreaterThan,
+    LessOrEqual,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+    StartsWith,
+    EndsWith,
+    Contains,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Expression {
+    /// evaluates the expression with the variables bound by the rule body
+    ///
+    /// returns false if a variable is not bound, if the operand types do not
+    /// match the operation, on integer overflow, if the expression is invalid
+    /// or if its result is not the boolean `true`
+    pub fn evaluate(&self, values: &HashMap<u32, ID>) -> bool {
+        let mut stack: Vec<ID> = Vec::new();
+
+        for op in self.ops.iter() {
+            match op {
+                Op::Value(ID::Variable(v)) => match values.get(v) {
+                    Some(id) => stack.push(id.clone()),
+                    None => return false,
+                },
+                Op::Value(id) => stack.push(id.clone()),
+                Op::Binary(binary) => {
+                    let right = stack.pop();
+                    let left = stack.pop();
+                    match (left, right) {
+                        (Some(left), Some(right)) => {
+                            match binary.evaluate(left, right) {
+                                Some(value) => stack.push(value),
+                                None => return false,
+                            }
+                        }
+                        _ => return false,
+                    }
+                }
+            }
+        }
+
+        stack == [ID::Bool(true)]
+    }
+
+    /// checks that the operations form a single expression
+    pub fn is_valid(&self) -> bool {
+        let mut depth = 0usize;
+
+        for op in self.ops.iter() {
+            match op {
+                Op::Value(_) => depth += 1,
+                Op::Binary(_) => {
+                    if depth < 2 {
+                        return false;
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+
+        depth == 1
+    }
+}
+
+impl Binary {
+    fn evaluate(self, left: ID, right: ID) -> Option<ID> {
+        match (self, left, right) {
+            (Binary::LessThan, ID::Integer(i), ID::Integer(j)) => Some(ID::Bool(i < j)),
+            (Binary::LessThan, ID::Date(i), ID::Date(j)) => Some(ID::Bool(i < j)),
+            (Binary::GreaterThan, ID::Integer(i), ID::Integer(j)) => Some(ID::Bool(i > j)),
+            (Binary::GreaterThan, ID::Date(i), ID::Date(j)) => Some(ID::Bool(i > j)),
+            (Binary::LessOrEqual, ID::Integer(i), ID::Integer(j)) => Some(ID::Bool(i <= j)),
+            (Binary::LessOrEqual, ID::Date(i), ID::Date(j)) => Some(ID::Bool(i <= j)),
+            (Binary::GreaterOrEqual, ID::Integer(i), ID::Integer(j)) => Some(ID::Bool(i >= j)),
+            (Binary::GreaterOrEqual, ID::Date(i), ID::Date(j)) => Some(ID::Bool(i >= j)),
+            (Binary::Equal, i, j) => Some(ID::Bool(i == j)),
+            (Binary::NotEqual, i, j) => Some(ID::Bool(i != j)),
+            (Binary::StartsWith, ID::Str(s), ID::Str(prefix)) => {
+                Some(ID::Bool(s.starts_with(prefix.as_str())))
+            }
+            (Binary::EndsWith, ID::Str(s), ID::Str(suffix)) => {
+                Some(ID::Bool(s.ends_with(suffix.as_str())))
+            }
+            (Binary::Contains, ID::Str(s), ID::Str(sub)) => {
+                Some(ID::Bool(s.contains(sub.as_str())))
+            }
+            (Binary::Contains, ID::Set(set), id) => Some(ID::Bool(set.contains(&id))),
+            (Binary::Add, ID::Integer(i), ID::Integer(j)) => i.checked_add(j).map(ID::Integer),
+            (Binary::Sub, ID::Integer(i), ID::Integer(j)) => i.checked_sub(j).map(ID::Integer),
+            (Binary::Mul, ID::Integer(i), ID::Integer(j)) => i.checked_mul(j).map(ID::Integer),
+            (Binary::Div, ID::Integer(i), ID::Integer(j)) => i.checked_div(j).map(ID::Integer),
+            // dates are in seconds, so adding an integer moves the date by that many seconds
+            (Binary::Add, ID::Date(d), ID::Integer(i)) | (Binary::Add, ID::Integer(i), ID::Date(d)) => {
+                add_seconds(d, i).map(ID::Date)
+            }
+            (Binary::Sub, ID::Date(d), ID::Integer(i)) => {
+                i.checked_neg().and_then(|i| add_seconds(d, i)).map(ID::Date)
+            }
+            (Binary::Sub, ID::Date(d1), ID::Date(d2)) => {
+                let d1 = d1 as i128;
+                let d2 = d2 as i128;
+                let diff = d1 - d2;
+                if diff >= i64::MIN as i128 && diff <= i64::MAX as i128 {
+                    Some(ID::Integer(diff as i64))
+                } else {
+                    None
+                }
+            }
+            (Binary::Add, ID::Str(s1), ID::Str(s2)) => Some(ID::Str(s1 + &s2)),
+            _ => None,
+        }
+    }
+
+    /// binding power of the operator, used to print parentheses
+    pub(crate) fn precedence(self) -> u8 {
+        match self {
+            Binary::LessThan
+            | Binary::GreaterThan
+            | Binary::LessOrEqual
+            | Binary::GreaterOrEqual
+            | Binary::Equal
+            | Binary::NotEqual => 1,
+            Binary::Add | Binary::Sub => 2,
+            Binary::Mul | Binary::Div => 3,
+            // method calls
+            Binary::StartsWith | Binary::EndsWith | Binary::Contains => 4,
+        }
+    }
+}
+
+fn add_seconds(date: u64, seconds: i64) -> Option<u64> {
+    if seconds >= 0 {
+        date.checked_add(seconds as u64)
+    } else {
+        date.checked_sub(seconds.unsigned_abs())
+    }
+}
+
+/// operation with its value already printed, so datalog and builder
+/// expressions share the same printing code
+pub(crate) enum PrintOp {
+    Value(String),
+    Binary(Binary),
+}
+
+/// prints an expression from its operations in reverse polish notation
+pub(crate) fn print_ops<I: IntoIterator<Item = PrintOp>>(ops: I) -> String {
+    // printed operand, with the precedence of its outermost operator
+    let mut stack: Vec<(String, u8)> = Vec::new();
+
+    for op in ops {
+        match op {
+            PrintOp::Value(s) => stack.push((s, u8::MAX)),
+            PrintOp::Binary(binary) => {
+                let (right, left) = match (stack.pop(), stack.pop()) {
+                    (Some(right), Some(left)) => (right, left),
+                    _ => return String::from("<invalid expression>"),
+                };
+                let precedence = binary.precedence();
+                let left = if left.1 < precedence {
+                    format!("({})", left.0)
+                } else {
+                    left.0
+                };
+
+                let printed = match binary {
+                    Binary::StartsWith => format!("{}.starts_with({})", left, right.0),
+                    Binary::EndsWith => format!("{}.ends_with({})", left, right.0),
+                    Binary::Contains => format!("{}.contains({})", left, right.0),
+                    _ => {
+                        // operators are left associative, so a right operand
+                        // with the same precedence needs parentheses
+                        let right = if right.1 <= precedence {
+                            format!("({})", right.0)
+                        } else {
+                            right.0
+                        };
+                        let symbol = match binary {
+                            Binary::LessThan => "<",
+                            Binary::GreaterThan => ">",
+                            Binary::LessOrEqual => "<=",
+                            Binary::GreaterOrEqual => ">=",
+                            Binary::Equal => "==",
+                            Binary::NotEqual => "!=",
+                            Binary::Add => "+",
+                            Binary::Sub => "-",
+                            Binary::Mul => "*",
+                            Binary::Div => "/",
+                            Binary::StartsWith | Binary::EndsWith | Binary::Contains => {
+                                unreachable!()
+                            }
+                        };
+                        format!("{} {} {}", left, symbol, right)
+                    }
+                };
+                stack.push((printed, precedence));
+            }
+        }
+    }
+
+    match stack.pop() {
+        Some((s, _)) if stack.is_empty() => s,
+        _ => String::from("<invalid expression>"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expr(ops: Vec<Op>) -> Expression {
+        Expression { ops }
+    }
+
+    #[test]
+    fn evaluate() {
+        let mut values = HashMap::new();
+        values.insert(0, ID::Integer(10));
+        values.insert(1, ID::Integer(11));
+        values.insert(2, ID::Str("/folder/file".to_string()));
+        values.insert(3, ID::Str("/folder/".to_string()));
+        values.insert(4, ID::Date(1000));
+
+        // $0 < $1
+        let e = expr(vec![
+            Op::Value(ID::Variable(0)),
+            Op::Value(ID::Variable(1)),
+            Op::Binary(Binary::LessThan),
+        ]);
+        assert!(e.evaluate(&values));
+
+        // $0 + 1 == $1
+        let e = expr(vec![
+            Op::Value(ID::Variable(0)),
+            Op::Value(ID::Integer(1)),
+            Op::Binary(Binary::Add),
+            Op::Value(ID::Variable(1)),
+            Op::Binary(Binary::Equal),
+        ]);
+        assert!(e.evaluate(&values));
+
+        // $2.starts_with($3)
+        let e = expr(vec![
+            Op::Value(ID::Variable(2)),
+            Op::Value(ID::Variable(3)),
+            Op::Binary(Binary::StartsWith),
+        ]);
+        assert!(e.evaluate(&values));
+
+        // $4 <= 500 + 499
+        let e = expr(vec![
+            Op::Value(ID::Variable(4)),
+            Op::Value(ID::Date(500)),
+            Op::Value(ID::Integer(499)),
+            Op::Binary(Binary::Add),
+            Op::Binary(Binary::LessOrEqual),
+        ]);
+        assert!(!e.evaluate(&values));
+
+        // type mismatch: $0 < $2
+        let e = expr(vec![
+            Op::Value(ID::Variable(0)),
+            Op::Value(ID::Variable(2)),
+            Op::Binary(Binary::LessThan),
+        ]);
+        assert!(!e.evaluate(&values));
+
+        // unbound variable
+        let e = expr(vec![
+            Op::Value(ID::Variable(5)),
+            Op::Value(ID::Integer(0)),
+            Op::Binary(Binary::GreaterThan),
+        ]);
+        assert!(!e.evaluate(&values));
+
+        // overflow
+        let e = expr(vec![
+            Op::Value(ID::Integer(i64::MAX)),
+            Op::Value(ID::Integer(1)),
+            Op::Binary(Binary::Add),
+            Op::Value(ID::Integer(0)),
+            Op::Binary(Binary::GreaterThan),
+        ]);
+        assert!(!e.evaluate(&values));
+
+        // not a boolean
+        let e = expr(vec![
+            Op::Value(ID::Integer(1)),
+            Op::Value(ID::Integer(1)),
+            Op::Binary(Binary::Add),
+        ]);
+        assert!(!e.evaluate(&values));
+        assert!(e.is_valid());
+
+        let e = expr(vec![Op::Value(ID::Integer(1)), Op::Binary(Binary::Add)]);
+        assert!(!e.is_valid());
+    }
+
+    #[test]
+    fn print() {
+        let print = |ops: Vec<PrintOp>| print_ops(ops);
+        let v = |s: &str| PrintOp::Value(s.to_string());
+
+        assert_eq!(
+            print(vec![v("$a"), v("1"), PrintOp::Binary(Binary::Add), v("$b"), PrintOp::Binary(Binary::Equal)]),
+            "$a + 1 == $b"
+        );
+        assert_eq!(
+            print(vec![v("$a"), v("$b"), v("$c"), PrintOp::Binary(Binary::Sub), PrintOp::Binary(Binary::Sub)]),
+            "$a - ($b - $c)"
+        );
+        assert_eq!(
+            print(vec![v("$a"), v("$b"), PrintOp::Binary(Binary::Add), v("2"), PrintOp::Binary(Binary::Mul)]),
+            "($a + $b) * 2"
+        );
+        assert_eq!(
+            print(vec![v("$path"), v("$prefix"), PrintOp::Binary(Binary::StartsWith)]),
+            "$path.starts_with($prefix)"
+        );
+        assert_eq!(print(vec![v("$a"), PrintOp::Binary(Binary::Add)]), "<invalid expression>");
+    }
+}
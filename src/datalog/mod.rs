@@ -1,14 +1,31 @@
 //! Logic language implementation for caveats
-use std::collections::{HashMap, HashSet};
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::AsRef;
 use std::fmt;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use regex::Regex;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use chrono::{DateTime, NaiveDateTime, Utc};
+use crate::error;
+
+mod aggregate;
+mod diagnostic;
+mod expression;
+mod origin;
+mod overlay;
+mod provenance;
+mod symbol_map;
+pub use aggregate::{Aggregate, AggregateKind};
+pub use diagnostic::{PartialMatch, RuleFailure};
+pub use expression::{Binary, Expression, Op};
+pub use overlay::Overlay;
+pub use provenance::{Derivation, DerivationTree};
+pub use symbol_map::SymbolMap;
+pub(crate) use expression::{print_ops, PrintOp};
 
 pub type Symbol = u64;
 
-#[derive(Debug, Clone, PartialEq, Hash, Eq)]
+#[derive(Debug, Clone, PartialEq, Hash, Eq, PartialOrd, Ord)]
 pub enum ID {
     Symbol(Symbol),
     Variable(u32),
@@ -16,6 +33,9 @@ pub enum ID {
     Str(String),
     Date(u64),
     Bytes(Vec<u8>),
+    Bool(bool),
+    /// set of values, that can contain other sets but not variables
+    Set(BTreeSet<ID>),
 }
 
 impl From<&ID> for ID {
@@ -27,6 +47,8 @@ impl From<&ID> for ID {
             ID::Str(ref s) => ID::Str(s.clone()),
             ID::Date(ref d) => ID::Date(*d),
             ID::Bytes(ref b) => ID::Bytes(b.clone()),
+            ID::Bool(ref b) => ID::Bool(*b),
+            ID::Set(ref s) => ID::Set(s.clone()),
         }
     }
 }
@@ -76,6 +98,12 @@ pub struct Rule {
     pub head: Predicate,
     pub body: Vec<Predicate>,
     pub constraints: Vec<Constraint>,
+    pub expressions: Vec<Expression>,
+    /// predicates that must not match any fact
+    pub negated: Vec<Predicate>,
+    /// aggregates computed over the matches of the body, each
+    /// replacing a variable of the head
+    pub aggregates: Vec<Aggregate>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -97,6 +125,7 @@ pub enum ConstraintKind {
     Date(DateConstraint),
     Symbol(SymbolConstraint),
     Bytes(BytesConstraint),
+    Set(SetConstraint),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -117,7 +146,41 @@ pub enum StrConstraint {
     Equal(String),
     In(HashSet<String>),
     NotIn(HashSet<String>),
-    Regex(String),
+    Regex(Regex),
+}
+
+/// regular expression used in string constraints
+///
+/// it is compiled once when the constraint is created, so an invalid
+/// expression is an error when parsing or deserializing the rule, and
+/// the compiled version is reused for every fact tested
+#[derive(Debug, Clone)]
+pub struct Regex(regex::Regex);
+
+impl Regex {
+    pub fn new(re: &str) -> Result<Self, regex::Error> {
+        regex::Regex::new(re).map(Regex)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        self.0.is_match(s)
+    }
+}
+
+impl PartialEq for Regex {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -139,6 +202,16 @@ pub enum BytesConstraint {
     NotIn(HashSet<Vec<u8>>),
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum SetConstraint {
+    /// the set contains this value
+    Contains(ID),
+    /// the set has at least one value in common with this one
+    Intersects(BTreeSet<ID>),
+    /// all the values of the set are in this one
+    Subset(BTreeSet<ID>),
+}
+
 impl Constraint {
     pub fn check(&self, name: u32, id: &ID) -> bool {
         if name != self.id {
@@ -146,7 +219,6 @@ impl Constraint {
         }
 
         match (id, &self.kind) {
-            (ID::Variable(_), _) => panic!("should not check constraint on a variable"),
             (ID::Integer(i), ConstraintKind::Int(c)) => match c {
                 IntConstraint::Lower(j) => *i < *j,
                 IntConstraint::Larger(j) => *i > *j,
@@ -160,14 +232,7 @@ impl Constraint {
                 StrConstraint::Prefix(pref) => s.as_str().starts_with(pref.as_str()),
                 StrConstraint::Suffix(suff) => s.as_str().ends_with(suff.as_str()),
                 StrConstraint::Equal(s2) => s == s2,
-                StrConstraint::Regex(r) => {
-                  if let Some(re) = Regex::new(r).ok() {
-                    re.is_match(s)
-                  } else {
-                    // an invalid regex will never match
-                    false
-                  }
-                },
+                StrConstraint::Regex(r) => r.is_match(s),
                 StrConstraint::In(h) => h.contains(s),
                 StrConstraint::NotIn(h) => !h.contains(s),
             },
@@ -184,6 +249,11 @@ impl Constraint {
                 BytesConstraint::In(h) => h.contains(s),
                 BytesConstraint::NotIn(h) => !h.contains(s),
             },
+            (ID::Set(s), ConstraintKind::Set(c)) => match c {
+                SetConstraint::Contains(id) => s.contains(id),
+                SetConstraint::Intersects(h) => !s.is_disjoint(h),
+                SetConstraint::Subset(h) => s.is_subset(h),
+            },
             _ => false,
         }
     }
@@ -202,7 +272,139 @@ impl fmt::Display for Fact {
 }
 
 impl Rule {
-    pub fn apply(&self, facts: &HashSet<Fact>, new_facts: &mut Vec<Fact>) {
+    pub fn apply(&self, facts: &FactSet, new_facts: &mut Vec<Fact>) {
+        self.apply_with(facts, None, &mut |fact, _| {
+            new_facts.push(fact);
+            true
+        });
+    }
+
+    /// semi-naive application of the rule
+    ///
+    /// only generates the facts that need at least one fact from `delta`
+    /// (the facts discovered in the previous iteration) to match the body.
+    /// `delta` must be a subset of `facts`
+    pub fn apply_delta(&self, facts: &FactSet, delta: &FactSet, new_facts: &mut Vec<Fact>) {
+        self.apply_with(facts, Some(delta), &mut |fact, _| {
+            new_facts.push(fact);
+            true
+        });
+    }
+
+    /// applies the rule, semi-naively if there is a `delta` (see `apply_delta`),
+    /// and calls `generated` with each fact and the matches of the body that
+    /// produced it. The application stops as soon as `generated` returns false
+    pub fn apply_with<F>(&self, facts: &FactSet, delta: Option<&FactSet>, generated: &mut F)
+    where
+        F: FnMut(Fact, &[HashMap<u32, ID>]) -> bool,
+    {
+        self.apply_view(&FactView::from(facts), delta, generated)
+    }
+
+    /// like `apply_with`, on the facts of a view
+    pub fn apply_view<F>(&self, facts: &FactView, delta: Option<&FactSet>, generated: &mut F)
+    where
+        F: FnMut(Fact, &[HashMap<u32, ID>]) -> bool,
+    {
+        // without a budget, the rule cannot run out of it
+        let _ = self.apply_budget(facts, delta, None, generated);
+    }
+
+    /// like `apply_view`, checking the budget of the run for every match of
+    /// the body, including the ones that generate known facts or that are
+    /// rejected by the expressions and negated predicates
+    pub fn apply_within<F>(
+        &self,
+        facts: &FactView,
+        delta: Option<&FactSet>,
+        budget: &RunBudget,
+        generated: &mut F,
+    ) -> Result<(), error::RunLimit>
+    where
+        F: FnMut(Fact, &[HashMap<u32, ID>]) -> bool,
+    {
+        self.apply_budget(facts, delta, Some(budget), generated)
+    }
+
+    fn apply_budget<F>(
+        &self,
+        facts: &FactView,
+        delta: Option<&FactSet>,
+        budget: Option<&RunBudget>,
+        generated: &mut F,
+    ) -> Result<(), error::RunLimit>
+    where
+        F: FnMut(Fact, &[HashMap<u32, ID>]) -> bool,
+    {
+        let delta = match delta {
+            None => {
+                let sources = vec![facts; self.body.len()];
+                self.apply_sources(facts, &sources, budget, generated)?;
+                return Ok(());
+            }
+            Some(delta) => delta,
+        };
+        let delta_view = FactView::from(delta);
+
+        for i in 0..self.body.len() {
+            // if no new fact can match this predicate, every combination was
+            // already tested in a previous iteration
+            if !delta
+                .iter_matching(&self.body[i])
+                .any(|fact| match_preds(&fact.predicate, &self.body[i]))
+            {
+                continue;
+            }
+
+            let mut sources = vec![facts; self.body.len()];
+            sources[i] = &delta_view;
+            if !self.apply_sources(facts, &sources, budget, generated)? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// facts matched by the body of the rule, for the given variable bindings
+    pub fn premises(&self, bindings: &[HashMap<u32, ID>]) -> Vec<Fact> {
+        let mut premises = Vec::new();
+
+        for binding in bindings.iter() {
+            for pred in self.body.iter() {
+                let mut p = pred.clone();
+                for id in p.ids.iter_mut() {
+                    if let ID::Variable(v) = id {
+                        if let Some(value) = binding.get(v) {
+                            *id = value.clone();
+                        }
+                    }
+                }
+
+                let fact = Fact { predicate: p };
+                if !premises.contains(&fact) {
+                    premises.push(fact);
+                }
+            }
+        }
+
+        premises
+    }
+
+    /// applies the rule, matching each body predicate against the
+    /// corresponding fact set in `sources`, and the negated predicates
+    /// against all the `facts`
+    ///
+    /// returns false if `generated` stopped the application
+    fn apply_sources<F>(
+        &self,
+        facts: &FactView,
+        sources: &[&FactView],
+        budget: Option<&RunBudget>,
+        generated: &mut F,
+    ) -> Result<bool, error::RunLimit>
+    where
+        F: FnMut(Fact, &[HashMap<u32, ID>]) -> bool,
+    {
         let variables_set = self
             .body
             .iter()
@@ -222,36 +424,138 @@ impl Rule {
 
         let variables = MatchedVariables::new(variables_set);
 
-        new_facts.extend(
-            CombineIt::new(variables, &self.body, &self.constraints, facts).map(|h| {
-                let mut p = self.head.clone();
-                for index in 0..p.ids.len() {
-                    let value = match &p.ids[index] {
-                        ID::Variable(i) => match h.get(i) {
-                          Some(val) => val,
-                          None => {
-                            println!("error: variables that appear in the head should appear in the body and constraints as well");
-                            continue;
-                          }
-                        },
-                        _ => continue,
-                    };
+        // a rule with only negated predicates is tested once, without bound variables
+        let bindings: Box<dyn Iterator<Item = HashMap<u32, ID>>> = if self.body.is_empty() {
+            Box::new(std::iter::once(HashMap::new()))
+        } else {
+            Box::new(CombineIt::new(variables, &self.body, &self.constraints, sources))
+        };
+
+        // the join can produce many matches without generating any new fact
+        let exceeded = Cell::new(None);
+        let bindings = bindings
+            .take_while(|_| match budget.map(|b| b.check(0)) {
+                Some(Err(e)) => {
+                    exceeded.set(Some(e));
+                    false
+                }
+                _ => true,
+            })
+            .filter(|h| self.expressions.iter().all(|e| e.evaluate(h)))
+            .filter(|h| self.negated.iter().all(|p| !matches_any(facts, p, h)));
+
+        if !self.aggregates.is_empty() {
+            let aggregated = aggregate::aggregate_facts(&self.head, &self.aggregates, bindings, budget)?;
+            // the aggregates are incomplete
+            if let Some(e) = exceeded.take() {
+                return Err(e);
+            }
+            for (fact, bindings) in aggregated {
+                if !generated(fact, &bindings) {
+                    return Ok(false);
+                }
+            }
+            return Ok(true);
+        }
 
-                    p.ids[index] = value.clone();
+        'bindings: for h in bindings {
+            let mut p = self.head.clone();
+            for id in p.ids.iter_mut() {
+                if let ID::Variable(v) = id {
+                    match h.get(v) {
+                        Some(value) => *id = value.clone(),
+                        // the rule is not safe (see `is_safe`), the fact would contain a variable
+                        None => continue 'bindings,
+                    }
                 }
+            }
 
-                Fact { predicate: p }
-            }),
-        );
+            if !generated(Fact { predicate: p }, std::slice::from_ref(&h)) {
+                return Ok(false);
+            }
+        }
+        match exceeded.take() {
+            Some(e) => Err(e),
+            None => Ok(true),
+        }
+    }
+
+    /// checks that the rule can be evaluated
+    ///
+    /// the variables of the head, the constraints, the expressions and the
+    /// negated predicates must all appear in the body predicates, otherwise
+    /// they would not have a value. Sets cannot contain variables
+    pub fn is_safe(&self) -> bool {
+        let mut bound = HashSet::new();
+        for p in self.body.iter() {
+            for id in p.ids.iter() {
+                match id {
+                    ID::Variable(v) => {
+                        bound.insert(*v);
+                    }
+                    id if !is_constant(id) => return false,
+                    _ => {}
+                }
+            }
+        }
+
+        let is_bound = |id: &ID| match id {
+            ID::Variable(v) => bound.contains(v),
+            id => is_constant(id),
+        };
+
+        self.head.ids.iter().all(is_bound)
+            && self.negated.iter().flat_map(|p| p.ids.iter()).all(is_bound)
+            && self.constraints.iter().all(|c| {
+                bound.contains(&c.id)
+                    && match &c.kind {
+                        ConstraintKind::Set(SetConstraint::Contains(id)) => is_constant(id),
+                        ConstraintKind::Set(SetConstraint::Intersects(s))
+                        | ConstraintKind::Set(SetConstraint::Subset(s)) => s.iter().all(is_constant),
+                        _ => true,
+                    }
+            })
+            && self.expressions.iter().flat_map(|e| e.ops.iter()).all(|op| match op {
+                Op::Value(id) => is_bound(id),
+                Op::Binary(_) => true,
+            })
     }
 }
 
+/// a value that is not and does not contain a variable
+fn is_constant(id: &ID) -> bool {
+    match id {
+        ID::Variable(_) => false,
+        ID::Set(s) => s.iter().all(is_constant),
+        _ => true,
+    }
+}
+
+/// checks if a fact matches the predicate, once its variables are replaced
+/// with their values. Variables that are not bound match any value
+fn matches_any(facts: &FactView, pred: &Predicate, values: &HashMap<u32, ID>) -> bool {
+    let mut p = pred.clone();
+    for id in p.ids.iter_mut() {
+        if let ID::Variable(v) = id {
+            if let Some(value) = values.get(v) {
+                *id = value.clone();
+            }
+        }
+    }
+
+    let matched = facts.iter_matching(&p).any(|fact| match_preds(&fact.predicate, &p));
+    matched
+}
+
 /// recursive iterator for rule application
+///
+/// each predicate is matched against its own fact set, which is how the
+/// semi-naive evaluation restricts one predicate to the latest facts
 pub struct CombineIt<'a> {
     variables: MatchedVariables,
     predicates: &'a [Predicate],
     constraints: &'a [Constraint],
-    all_facts: &'a HashSet<Fact>,
+    sources: &'a [&'a FactView<'a>],
     current_facts: Box<dyn Iterator<Item = &'a Fact> + 'a>,
     current_it: Option<Box<CombineIt<'a>>>,
 }
@@ -261,17 +565,17 @@ impl<'a> CombineIt<'a> {
         variables: MatchedVariables,
         predicates: &'a [Predicate],
         constraints: &'a [Constraint],
-        facts: &'a HashSet<Fact>,
+        sources: &'a [&'a FactView<'a>],
     ) -> Self {
         let p = predicates[0].clone();
         CombineIt {
             variables,
             predicates,
             constraints,
-            all_facts: facts,
+            sources,
             current_facts: Box::new(
-                facts
-                    .iter()
+                sources[0]
+                    .iter_matching(&predicates[0])
                     .filter(move |fact| match_preds(&fact.predicate, &p)),
             ),
             current_it: None,
@@ -329,12 +633,12 @@ impl<'a> Iterator for CombineIt<'a> {
                             }
                         } else {
                             // create a new iterator with the matched variables, the rest of the predicates,
-                            // and all of the facts
+                            // and their fact sets
                             self.current_it = Some(Box::new(CombineIt::new(
                                 vars,
                                 &self.predicates[1..],
                                 self.constraints,
-                                &self.all_facts,
+                                &self.sources[1..],
                             )));
                         }
                         break;
@@ -417,6 +721,9 @@ pub fn rule<I: AsRef<ID>, P: AsRef<Predicate>>(
         head: pred(head_name, head_ids),
         body: predicates.iter().map(|p| p.as_ref().clone()).collect(),
         constraints: Vec::new(),
+        expressions: Vec::new(),
+        negated: Vec::new(),
+        aggregates: Vec::new(),
     }
 }
 
@@ -430,6 +737,9 @@ pub fn constrained_rule<I: AsRef<ID>, P: AsRef<Predicate>, C: AsRef<Constraint>>
         head: pred(head_name, head_ids),
         body: predicates.iter().map(|p| p.as_ref().clone()).collect(),
         constraints: constraints.iter().map(|c| c.as_ref().clone()).collect(),
+        expressions: Vec::new(),
+        negated: Vec::new(),
+        aggregates: Vec::new(),
     }
 }
 
@@ -465,14 +775,280 @@ pub fn match_preds(pred1: &Predicate, pred2: &Predicate) -> bool {
                 (ID::Integer(i), ID::Integer(j)) => i == j,
                 (ID::Str(i), ID::Str(j)) => i == j,
                 (ID::Date(i), ID::Date(j)) => i == j,
+                (ID::Bytes(i), ID::Bytes(j)) => i == j,
+                (ID::Bool(i), ID::Bool(j)) => i == j,
+                (ID::Set(i), ID::Set(j)) => i == j,
                 _ => false,
             })
 }
 
+/// limits on the Datalog engine execution, to protect the verifier
+/// from tokens generating too many facts or taking too long to evaluate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunLimits {
+    /// maximum number of facts in the world, including the generated ones
+    pub max_facts: u32,
+    /// maximum number of iterations of the rules application
+    pub max_iterations: u32,
+    /// maximum time spent running the rules
+    pub max_time: Duration,
+}
+
+impl Default for RunLimits {
+    fn default() -> Self {
+        RunLimits {
+            max_facts: 1000,
+            max_iterations: 100,
+            max_time: Duration::from_millis(5),
+        }
+    }
+}
+
+/// limits of a run, with the time it started
+///
+/// a budget can be shared by multiple runs, like the runs of the rules for
+/// the caveats of each block in the verifier: they have the same deadline,
+/// and the facts generated by a run count for the following ones
+#[derive(Debug, Clone, Copy)]
+pub struct RunBudget {
+    pub limits: RunLimits,
+    pub start: Instant,
+    /// facts generated by the previous runs
+    pub generated: usize,
+}
+
+impl RunBudget {
+    /// starts a run now
+    pub fn new(limits: RunLimits) -> Self {
+        RunBudget {
+            limits,
+            start: Instant::now(),
+            generated: 0,
+        }
+    }
+
+    /// checks the number of facts and the time spent since the start
+    pub fn check(&self, facts: usize) -> Result<(), error::RunLimit> {
+        if facts > self.limits.max_facts as usize {
+            Err(error::RunLimit::TooManyFacts)
+        } else if self.start.elapsed() >= self.limits.max_time {
+            Err(error::RunLimit::Timeout)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// set of facts, indexed by predicate name then by first term
+///
+/// most predicates start with a constant tag like `#authority` or `#ambient`,
+/// so rule bodies and queries only need to look at the facts sharing their
+/// name and first term
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FactSet {
+    inner: HashMap<Symbol, HashMap<Option<ID>, HashSet<Fact>>>,
+    len: usize,
+}
+
+impl FactSet {
+    pub fn new() -> Self {
+        FactSet::default()
+    }
+
+    /// adds a fact to the set, returns false if it was already present
+    pub fn insert(&mut self, fact: Fact) -> bool {
+        let inserted = self
+            .inner
+            .entry(fact.predicate.name)
+            .or_default()
+            .entry(fact.predicate.ids.first().cloned())
+            .or_default()
+            .insert(fact);
+
+        if inserted {
+            self.len += 1;
+        }
+        inserted
+    }
+
+    pub fn contains(&self, fact: &Fact) -> bool {
+        self.inner
+            .get(&fact.predicate.name)
+            .and_then(|by_first| by_first.get(&fact.predicate.ids.first().cloned()))
+            .map(|facts| facts.contains(fact))
+            .unwrap_or(false)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Fact> {
+        self.inner
+            .values()
+            .flat_map(|by_first| by_first.values())
+            .flat_map(|facts| facts.iter())
+    }
+
+    /// iterates over the facts that could match the predicate: they have
+    /// the same name, and the same first term if it is not a variable
+    ///
+    /// the terms still have to be checked with `match_preds`
+    pub fn iter_matching<'a>(&'a self, pred: &Predicate) -> impl Iterator<Item = &'a Fact> + 'a {
+        let sets: Box<dyn Iterator<Item = &'a HashSet<Fact>> + 'a> =
+            match (self.inner.get(&pred.name), pred.ids.first()) {
+                (None, _) => Box::new(std::iter::empty()),
+                (Some(by_first), None) | (Some(by_first), Some(ID::Variable(_))) => {
+                    Box::new(by_first.values())
+                }
+                (Some(by_first), Some(id)) => Box::new(by_first.get(&Some(id.clone())).into_iter()),
+            };
+
+        sets.flat_map(|facts| facts.iter())
+    }
+}
+
+impl Extend<Fact> for FactSet {
+    fn extend<T: IntoIterator<Item = Fact>>(&mut self, iter: T) {
+        for fact in iter {
+            self.insert(fact);
+        }
+    }
+}
+
+impl std::iter::FromIterator<Fact> for FactSet {
+    fn from_iter<T: IntoIterator<Item = Fact>>(iter: T) -> Self {
+        let mut set = FactSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+/// facts matched by the rules: the union of some fact sets, where the facts
+/// of a world can be restricted to the ones provided by some blocks
+///
+/// the sets are only borrowed, so rules can run over a world shared by
+/// multiple verifiers without copying it (see `Overlay`)
+#[derive(Debug, Clone, Default)]
+pub struct FactView<'a> {
+    layers: Vec<Layer<'a>>,
+}
+
+/// origins of the facts, and the blocks visible in the view
+type LayerScope<'a> = (&'a HashMap<Fact, BTreeSet<usize>>, &'a BTreeSet<usize>);
+
+#[derive(Debug, Clone, Copy)]
+struct Layer<'a> {
+    facts: &'a FactSet,
+    scope: Option<LayerScope<'a>>,
+}
+
+impl<'a> Layer<'a> {
+    fn visible(&self, fact: &Fact) -> bool {
+        match self.scope {
+            None => true,
+            Some((origins, blocks)) => origins
+                .get(fact)
+                .map(|origins| !origins.is_disjoint(blocks))
+                .unwrap_or(true),
+        }
+    }
+
+    fn contains(&self, fact: &Fact) -> bool {
+        self.facts.contains(fact) && self.visible(fact)
+    }
+}
+
+impl<'a> FactView<'a> {
+    pub fn new() -> Self {
+        FactView::default()
+    }
+
+    /// adds all the facts of a set
+    pub fn add(&mut self, facts: &'a FactSet) {
+        self.layers.push(Layer { facts, scope: None });
+    }
+
+    /// adds the facts of a world, and if there is a scope, only keeps the
+    /// ones provided by these blocks or visible in every scope, like `World::scoped`
+    pub fn add_world(&mut self, world: &'a World, scope: Option<&'a BTreeSet<usize>>) {
+        self.layers.push(Layer {
+            facts: &world.facts,
+            scope: scope.map(|blocks| (&world.fact_origins, blocks)),
+        });
+    }
+
+    pub fn contains(&self, fact: &Fact) -> bool {
+        self.layers.iter().any(|layer| layer.contains(fact))
+    }
+
+    /// number of facts in the sets, including the ones outside of the scope
+    pub fn len(&self) -> usize {
+        self.layers.iter().map(|layer| layer.facts.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.iter().all(|layer| layer.facts.is_empty())
+    }
+
+    /// iterates over the facts that could match the predicate, like
+    /// `FactSet::iter_matching`. A fact present in multiple sets is only
+    /// returned once
+    pub fn iter_matching<'b>(&'b self, pred: &'b Predicate) -> impl Iterator<Item = &'a Fact> + 'b {
+        self.layers.iter().enumerate().flat_map(move |(i, layer)| {
+            let previous = &self.layers[..i];
+            layer.facts.iter_matching(pred).filter(move |fact| {
+                layer.visible(fact) && !previous.iter().any(|l| l.contains(fact))
+            })
+        })
+    }
+
+    pub fn query_rule(&self, rule: Rule) -> Vec<Fact> {
+        let mut new_facts: Vec<Fact> = Vec::new();
+        rule.apply_view(self, None, &mut |fact, _| {
+            new_facts.push(fact);
+            true
+        });
+        new_facts
+    }
+
+    /// like `query_rule`, but each fact comes with the facts that matched
+    /// the rule body the first time it was generated
+    pub fn query_rule_premises(&self, rule: &Rule) -> Vec<(Fact, Vec<Fact>)> {
+        let mut results: Vec<(Fact, Vec<Fact>)> = Vec::new();
+        rule.apply_view(self, None, &mut |fact, bindings| {
+            if !results.iter().any(|(f, _)| *f == fact) {
+                let premises = rule.premises(bindings);
+                results.push((fact, premises));
+            }
+            true
+        });
+        results
+    }
+}
+
+impl<'a> From<&'a FactSet> for FactView<'a> {
+    fn from(facts: &'a FactSet) -> Self {
+        let mut view = FactView::new();
+        view.add(facts);
+        view
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct World {
-    pub facts: HashSet<Fact>,
+    pub facts: FactSet,
     pub rules: Vec<Rule>,
+    /// derivation of the generated facts, if provenance is enabled
+    provenance: Option<HashMap<Fact, Derivation>>,
+    /// blocks that provided the facts, see `add_fact_with_origin`
+    fact_origins: HashMap<Fact, BTreeSet<usize>>,
+    /// block that provided the rule at each index of `rules`
+    rule_origins: HashMap<usize, usize>,
 }
 
 impl World {
@@ -480,7 +1056,9 @@ impl World {
         World::default()
     }
 
+    /// adds a fact visible in every scope, see `scoped`
     pub fn add_fact(&mut self, fact: Fact) {
+        self.fact_origins.remove(&fact);
         self.facts.insert(fact);
     }
 
@@ -488,31 +1066,50 @@ impl World {
         self.rules.push(rule);
     }
 
-    pub fn run(&mut self) {
-        let mut index = 0;
-        loop {
-            let mut new_facts: Vec<Fact> = Vec::new();
-            for rule in self.rules.iter() {
-                rule.apply(&self.facts, &mut new_facts);
-                //println!("new_facts after applying {:?}:\n{:#?}", rule, new_facts);
-            }
+    /// runs the rules until no new fact is generated, with the default limits
+    pub fn run(&mut self) -> Result<(), error::RunLimit> {
+        self.run_with_limits(RunLimits::default())
+    }
 
-            let len = self.facts.len();
-            self.facts.extend(new_facts.drain(..));
-            if self.facts.len() == len {
-                break;
-            }
+    /// runs the rules until no new fact is generated
+    ///
+    /// the rules are evaluated stratum by stratum (see `stratify`), so a
+    /// negated predicate is only tested once all its facts are known. If
+    /// the rules cannot be stratified, they are not evaluated, and this
+    /// returns the index of a rule in the cycle
+    ///
+    /// in each stratum, this uses semi-naive evaluation: after the first
+    /// iteration, rules are only applied to combinations involving at least
+    /// one fact generated by the previous iteration
+    ///
+    /// the execution stops with an error as soon as it exceeds one of the
+    /// limits, even in the middle of a rule application
+    ///
+    /// with provenance enabled, the first derivation of each new fact is recorded
+    pub fn run_with_limits(&mut self, limits: RunLimits) -> Result<(), error::RunLimit> {
+        let rules = self.rules.iter().collect::<Vec<_>>();
+        let mut generated = FactSet::new();
+        let res = run_rules(
+            &rules,
+            &FactView::from(&self.facts),
+            &mut generated,
+            self.provenance.as_mut(),
+            &mut RunBudget::new(limits),
+        );
 
-            index += 1;
-            if index == 100 {
-                panic!();
-            }
-        }
+        // the facts of the iterations that completed are kept on error
+        self.facts.extend(generated.iter().cloned());
+        res
+    }
+
+    /// assigns a stratum to each rule, see `stratify`
+    pub fn stratify(&self) -> Result<Vec<usize>, usize> {
+        stratify(&self.rules.iter().collect::<Vec<_>>())
     }
 
     pub fn query(&self, pred: Predicate) -> Vec<&Fact> {
         self.facts
-            .iter()
+            .iter_matching(&pred)
             .filter(|f| {
                 f.predicate.name == pred.name
                     && f.predicate
@@ -525,6 +1122,9 @@ impl World {
                             (ID::Integer(i), ID::Integer(ref j)) => i == j,
                             (ID::Str(i), ID::Str(ref j)) => i == j,
                             (ID::Date(i), ID::Date(ref j)) => i == j,
+                            (ID::Bytes(i), ID::Bytes(ref j)) => i == j,
+                            (ID::Bool(i), ID::Bool(ref j)) => i == j,
+                            (ID::Set(i), ID::Set(ref j)) => i == j,
                             _ => false,
                         })
             })
@@ -532,15 +1132,181 @@ impl World {
     }
 
     pub fn query_rule(&self, rule: Rule) -> Vec<Fact> {
-        let mut new_facts: Vec<Fact> = Vec::new();
-        rule.apply(&self.facts, &mut new_facts);
-        new_facts
+        FactView::from(&self.facts).query_rule(rule)
+    }
+
+    /// like `query_rule`, but each fact comes with the facts that matched
+    /// the rule body the first time it was generated
+    pub fn query_rule_premises(&self, rule: &Rule) -> Vec<(Fact, Vec<Fact>)> {
+        FactView::from(&self.facts).query_rule_premises(rule)
     }
 }
 
+/// runs the rules until no new fact is generated, matching them against the
+/// facts of `base` and the ones they generated, which are added to `generated`
+///
+/// the rules are evaluated stratum by stratum, with semi-naive evaluation in
+/// each stratum (see `World::run_with_limits`). With provenance, the first
+/// derivation of each new fact is recorded with the index of its rule in `rules`
+///
+/// the facts generated by this run are added to the ones of the budget
+fn run_rules(
+    rules: &[&Rule],
+    base: &FactView,
+    generated: &mut FactSet,
+    provenance: Option<&mut HashMap<Fact, Derivation>>,
+    budget: &mut RunBudget,
+) -> Result<(), error::RunLimit> {
+    let before = generated.len();
+    let res = run_strata(rules, base, generated, provenance, budget);
+    budget.generated += generated.len() - before;
+    res
+}
+
+fn run_strata(
+    rules: &[&Rule],
+    base: &FactView,
+    generated: &mut FactSet,
+    mut provenance: Option<&mut HashMap<Fact, Derivation>>,
+    budget: &RunBudget,
+) -> Result<(), error::RunLimit> {
+    let mut index = 0;
+
+    let strata = stratify(rules).map_err(error::RunLimit::UnstratifiableRules)?;
+    let max_stratum = strata.iter().cloned().max().unwrap_or(0);
+
+    for stratum in 0..=max_stratum {
+        let rules = rules
+            .iter()
+            .enumerate()
+            .zip(strata.iter())
+            .filter(|(_, s)| **s == stratum)
+            .map(|(rule, _)| rule)
+            .collect::<Vec<_>>();
+
+        let mut delta: Option<FactSet> = None;
+        loop {
+            // only the facts that are not already known
+            let mut new_delta = FactSet::new();
+            let mut derivations: Vec<(Fact, Derivation)> = Vec::new();
+            {
+                let mut facts = base.clone();
+                facts.add(generated);
+
+                for (rule_index, rule) in rules.iter() {
+                    let mut exceeded = Ok(());
+                    // in the first iteration, there is no delta: every fact is new
+                    let applied = rule.apply_within(&facts, delta.as_ref(), budget, &mut |fact, bindings| {
+                        if facts.contains(&fact) || new_delta.contains(&fact) {
+                            return true;
+                        }
+                        if provenance.is_some() {
+                            let derivation = Derivation {
+                                rule: *rule_index,
+                                premises: rule.premises(bindings),
+                            };
+                            derivations.push((fact.clone(), derivation));
+                        }
+                        new_delta.insert(fact);
+
+                        exceeded = budget.check(budget.generated + facts.len() + new_delta.len());
+                        exceeded.is_ok()
+                    });
+
+                    applied?;
+                    exceeded?;
+                    budget.check(budget.generated + facts.len() + new_delta.len())?;
+                }
+            }
+
+            if new_delta.is_empty() {
+                break;
+            }
+            if let Some(provenance) = provenance.as_mut() {
+                for (fact, derivation) in derivations.drain(..) {
+                    if new_delta.contains(&fact) && !provenance.contains_key(&fact) {
+                        provenance.insert(fact, derivation);
+                    }
+                }
+            }
+            generated.extend(new_delta.iter().cloned());
+            delta = Some(new_delta);
+
+            index += 1;
+            if index >= budget.limits.max_iterations {
+                return Err(error::RunLimit::TooManyIterations);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// assigns a stratum to each rule, so that the facts a rule depends on
+/// through a negated predicate are all generated in a lower stratum.
+/// Rules with aggregates need all the facts matching their body, so
+/// those are generated in a lower stratum too
+///
+/// returns the stratum of each rule, in the same order as `rules`. If
+/// a predicate depends on its own negation, the rules cannot be stratified
+/// and it returns the index of a rule in that cycle (a predicate computed
+/// with an aggregate cannot depend on itself either)
+pub fn stratify(rules: &[&Rule]) -> Result<Vec<usize>, usize> {
+    let mut strata: HashMap<Symbol, usize> = HashMap::new();
+
+    loop {
+        let mut changed = false;
+
+        for (i, rule) in rules.iter().enumerate() {
+            let current = strata.get(&rule.head.name).cloned().unwrap_or(0);
+            let mut stratum = current;
+            let body_offset = if rule.aggregates.is_empty() { 0 } else { 1 };
+            for p in rule.body.iter() {
+                stratum = stratum.max(strata.get(&p.name).cloned().unwrap_or(0) + body_offset);
+            }
+            for p in rule.negated.iter() {
+                stratum = stratum.max(strata.get(&p.name).cloned().unwrap_or(0) + 1);
+            }
+
+            if stratum > current {
+                // without a cycle through a negation, there cannot be
+                // more strata than rules
+                if stratum > rules.len() {
+                    return Err(i);
+                }
+                strata.insert(rule.head.name, stratum);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    Ok(rules
+        .iter()
+        .map(|rule| strata.get(&rule.head.name).cloned().unwrap_or(0))
+        .collect())
+}
+
+/// the symbols used by facts and rules, a symbol being stored as its index
+///
+/// a table can extend a shared base table, the symbols of the base keeping
+/// their index, so a table prepared once, like the symbols of a verifier
+/// policy, can be extended by each request without being copied
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct SymbolTable {
+    /// symbols of the table, or the ones added to the base table if there
+    /// is one, their index starting after the symbols of the base
     pub symbols: Vec<String>,
+    base: Option<Arc<SymbolTable>>,
+}
+
+impl From<Vec<String>> for SymbolTable {
+    fn from(symbols: Vec<String>) -> Self {
+        SymbolTable { symbols, base: None }
+    }
 }
 
 impl SymbolTable {
@@ -548,12 +1314,34 @@ impl SymbolTable {
         SymbolTable::default()
     }
 
+    /// creates a table extending `base`
+    pub fn with_base(base: Arc<SymbolTable>) -> Self {
+        SymbolTable {
+            symbols: Vec::new(),
+            base: Some(base),
+        }
+    }
+
+    /// number of symbols, including the ones of the base table
+    pub fn len(&self) -> usize {
+        self.offset() + self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// all the symbols, in the order of their index
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        (0..self.len() as Symbol).filter_map(move |i| self.symbol(i))
+    }
+
     pub fn insert(&mut self, s: &str) -> Symbol {
-        match self.symbols.iter().position(|sym| sym.as_str() == s) {
-            Some(index) => index as u64,
+        match self.get(s) {
+            Some(index) => index,
             None => {
                 self.symbols.push(s.to_string());
-                (self.symbols.len() - 1) as u64
+                (self.len() - 1) as u64
             }
         }
     }
@@ -564,14 +1352,30 @@ impl SymbolTable {
     }
 
     pub fn get(&self, s: &str) -> Option<Symbol> {
+        if let Some(index) = self.base.as_ref().and_then(|base| base.get(s)) {
+            return Some(index);
+        }
+
         self.symbols
             .iter()
             .position(|sym| sym.as_str() == s)
-            .map(|i| i as u64)
+            .map(|i| (self.offset() + i) as u64)
+    }
+
+    fn offset(&self) -> usize {
+        self.base.as_ref().map(|base| base.len()).unwrap_or(0)
+    }
+
+    fn symbol(&self, s: Symbol) -> Option<&str> {
+        let offset = self.offset();
+        match self.base.as_ref() {
+            Some(base) if (s as usize) < offset => base.symbol(s),
+            _ => self.symbols.get(s as usize - offset).map(|s| s.as_str()),
+        }
     }
 
     pub fn print_symbol(&self, s: Symbol) -> String {
-      self.symbols.get(s as usize).map(|s| s.to_string()).unwrap_or_else(|| format!("<{}?>", s))
+      self.symbol(s).map(|s| s.to_string()).unwrap_or_else(|| format!("<{}?>", s))
     }
 
     pub fn print_world(&self, w: &World) -> String {
@@ -592,28 +1396,36 @@ impl SymbolTable {
         self.print_predicate(&f.predicate)
     }
 
+    pub fn print_id(&self, id: &ID) -> String {
+        match id {
+            ID::Variable(i) => format!("${}", self.print_symbol(*i as u64)),
+            ID::Integer(i) => i.to_string(),
+            ID::Str(s) => format!("\"{}\"", s),
+            ID::Symbol(index) => format!("#{}", self.print_symbol(*index)),
+            ID::Date(d) => {
+                let t = UNIX_EPOCH + Duration::from_secs(*d);
+                format!("{:?}", t)
+            },
+            ID::Bytes(s) => format!("hex:{}", hex::encode(s)),
+            ID::Bool(b) => b.to_string(),
+            ID::Set(s) => self.print_set(s),
+        }
+    }
+
+    pub fn print_set(&self, s: &BTreeSet<ID>) -> String {
+        let ids = s.iter().map(|id| self.print_id(id)).collect::<Vec<_>>();
+        format!("[{}]", ids.join(", "))
+    }
+
     pub fn print_predicate(&self, p: &Predicate) -> String {
         let strings = p
             .ids
             .iter()
-            .map(|id| match id {
-                ID::Variable(i) => format!("${}", self.print_symbol(*i as u64)),
-                ID::Integer(i) => i.to_string(),
-                ID::Str(s) => format!("\"{}\"", s),
-                ID::Symbol(index) => format!("#{}", self.print_symbol(*index as u64)),
-                ID::Date(d) => {
-                    let t = UNIX_EPOCH + Duration::from_secs(*d);
-                    format!("{:?}", t)
-                },
-                ID::Bytes(s) => format!("hex:{}", hex::encode(s)),
-            })
+            .map(|id| self.print_id(id))
             .collect::<Vec<_>>();
         format!(
             "{}({})",
-            self.symbols
-                .get(p.name as usize)
-                .map(|s| s.as_str())
-                .unwrap_or("<?>"),
+            self.symbol(p.name).unwrap_or("<?>"),
             strings.join(", ")
         )
     }
@@ -654,16 +1466,52 @@ impl SymbolTable {
                 format!("${} not in {:?}", self.print_symbol(c.id as u64), i.iter()
                         .map(|s| format!("hex:{}", hex::encode(s))).collect::<HashSet<_>>())
             },
+            ConstraintKind::Set(SetConstraint::Contains(id)) => {
+                format!("${} contains {}", self.print_symbol(c.id as u64), self.print_id(id))
+            },
+            ConstraintKind::Set(SetConstraint::Intersects(s)) => {
+                format!("${} intersects {}", self.print_symbol(c.id as u64), self.print_set(s))
+            },
+            ConstraintKind::Set(SetConstraint::Subset(s)) => {
+                format!("${} subset {}", self.print_symbol(c.id as u64), self.print_set(s))
+            },
         }
     }
 
+    pub fn print_expression(&self, e: &Expression) -> String {
+        print_ops(e.ops.iter().map(|op| match op {
+            Op::Value(id) => PrintOp::Value(self.print_id(id)),
+            Op::Binary(b) => PrintOp::Binary(*b),
+        }))
+    }
+
     pub fn print_rule(&self, r: &Rule) -> String {
-        let res = self.print_predicate(&r.head);
-        let preds: Vec<_> = r.body.iter().map(|p| self.print_predicate(p)).collect();
+        let res = if r.aggregates.is_empty() {
+            self.print_predicate(&r.head)
+        } else {
+            let ids = r
+                .head
+                .ids
+                .iter()
+                .enumerate()
+                .map(|(i, id)| match r.aggregates.iter().find(|a| a.position as usize == i) {
+                    Some(a) => format!("{}({})", a.kind.name(), self.print_id(id)),
+                    None => self.print_id(id),
+                })
+                .collect::<Vec<_>>();
+            format!("{}({})", self.print_symbol(r.head.name), ids.join(", "))
+        };
+        let preds: Vec<_> = r
+            .body
+            .iter()
+            .map(|p| self.print_predicate(p))
+            .chain(r.negated.iter().map(|p| format!("!{}", self.print_predicate(p))))
+            .collect();
         let constraints: Vec<_> = r
             .constraints
             .iter()
             .map(|c| self.print_constraint(c))
+            .chain(r.expressions.iter().map(|e| self.print_expression(e)))
             .collect();
 
         let c = if constraints.is_empty() {
@@ -744,7 +1592,7 @@ mod tests {
         println!("adding r2: {}", syms.print_rule(&r2));
         w.add_rule(r2);
 
-        w.run();
+        w.run().unwrap();
 
         println!("parents:");
         let res = w.query(pred(parent, &[var(&mut syms, "parent"), var(&mut syms, "child")]));
@@ -761,7 +1609,7 @@ mod tests {
             w.query(pred(grandparent, &[var(&mut syms, "grandparent"), var(&mut syms, "grandchild")]))
         );
         w.add_fact(fact(parent, &[&c, &e]));
-        w.run();
+        w.run().unwrap();
         let mut res = w.query(pred(grandparent, &[var(&mut syms, "grandparent"), var(&mut syms, "grandchild")]));
         println!("grandparents after inserting parent(C, E): {:?}", res);
 
@@ -780,11 +1628,578 @@ mod tests {
           pred(parent, &[var(parent), var("B")])
         ]));
 
-        w.run();
+        w.run().unwrap();
         println!("siblings: {:#?}", w.query(pred("siblings", &[var("A"), var("B")])));
         */
     }
 
+    #[test]
+    fn transitive_closure() {
+        let mut w = World::new();
+        let mut syms = SymbolTable::new();
+
+        let parent = syms.insert("parent");
+        let ancestor = syms.insert("ancestor");
+        let nodes = (0..10).map(|i| syms.add(&format!("n{}", i))).collect::<Vec<_>>();
+
+        for i in 0..9 {
+            w.add_fact(fact(parent, &[&nodes[i], &nodes[i + 1]]));
+        }
+
+        w.add_rule(rule(
+            ancestor,
+            &[var(&mut syms, "a"), var(&mut syms, "b")],
+            &[pred(parent, &[var(&mut syms, "a"), var(&mut syms, "b")])],
+        ));
+        w.add_rule(rule(
+            ancestor,
+            &[var(&mut syms, "a"), var(&mut syms, "c")],
+            &[
+                pred(ancestor, &[var(&mut syms, "a"), var(&mut syms, "b")]),
+                pred(parent, &[var(&mut syms, "b"), var(&mut syms, "c")]),
+            ],
+        ));
+
+        w.run().unwrap();
+
+        let res = w
+            .query(pred(ancestor, &[var(&mut syms, "a"), var(&mut syms, "b")]))
+            .drain(..)
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        let mut compared = HashSet::new();
+        for i in 0..10 {
+            for j in i + 1..10 {
+                compared.insert(fact(ancestor, &[&nodes[i], &nodes[j]]));
+            }
+        }
+        assert_eq!(res, compared);
+    }
+
+    #[test]
+    fn symbol_table_base() {
+        let mut base = SymbolTable::new();
+        let authority = base.insert("authority");
+        let read = base.insert("read");
+        let base = Arc::new(base);
+
+        let mut syms = SymbolTable::with_base(Arc::clone(&base));
+        assert_eq!(syms.insert("read"), read);
+        let write = syms.insert("write");
+        assert_eq!(write, 2);
+        assert_eq!(syms.symbols, vec!["write".to_string()]);
+        assert_eq!(syms.len(), 3);
+        assert_eq!(syms.get("authority"), Some(authority));
+        assert_eq!(syms.print_symbol(write), "write");
+        assert_eq!(syms.iter().collect::<Vec<_>>(), vec!["authority", "read", "write"]);
+
+        // the base is not modified, and can be extended again
+        assert_eq!(base.len(), 2);
+        let mut other = SymbolTable::with_base(Arc::clone(&base));
+        assert_eq!(other.insert("owner"), 2);
+        assert_eq!(other.print_symbol(3), "<3?>");
+
+        let mut layered = SymbolTable::with_base(Arc::new(syms));
+        assert_eq!(layered.insert("owner"), 3);
+        assert_eq!(layered.print_symbol(write), "write");
+    }
+
+    #[test]
+    fn fact_set_index() {
+        let mut syms = SymbolTable::new();
+        let right = syms.insert("right");
+        let authority = syms.add("authority");
+        let ambient = syms.add("ambient");
+        let read = syms.add("read");
+
+        let mut facts = FactSet::new();
+        for i in 0..10 {
+            let file = string(&format!("file{}", i));
+            assert!(facts.insert(fact(right, &[&authority, &file, &read])));
+            assert!(facts.insert(fact(right, &[&ambient, &file, &read])));
+        }
+        assert!(!facts.insert(fact(right, &[&authority, &string("file0"), &read])));
+        assert_eq!(facts.len(), 20);
+        assert!(facts.contains(&fact(right, &[&ambient, &string("file9"), &read])));
+        assert!(!facts.contains(&fact(right, &[&ambient, &string("file10"), &read])));
+
+        let query = pred(right, &[&authority, &var(&mut syms, "file"), &read]);
+        let matching = facts.iter_matching(&query).collect::<Vec<_>>();
+        assert_eq!(matching.len(), 10);
+        assert!(matching.iter().all(|f| f.predicate.ids[0] == authority));
+
+        let query = pred(right, &[&var(&mut syms, "tag"), &var(&mut syms, "file"), &read]);
+        assert_eq!(facts.iter_matching(&query).count(), 20);
+
+        let query = pred(syms.insert("owner"), &[&var(&mut syms, "user")]);
+        assert_eq!(facts.iter_matching(&query).count(), 0);
+    }
+
+    #[test]
+    fn negation() {
+        let mut w = World::new();
+        let mut syms = SymbolTable::new();
+
+        let user = syms.insert("user");
+        let banned = syms.insert("banned");
+        let member = syms.insert("member");
+        let allowed = syms.insert("allowed");
+        let alice = syms.add("alice");
+        let bob = syms.add("bob");
+        let admins = syms.add("admins");
+
+        w.add_fact(fact(user, &[&alice]));
+        w.add_fact(fact(user, &[&bob]));
+        w.add_fact(fact(member, &[&bob, &admins]));
+
+        // allowed($u) <- user($u), !banned($u)
+        let mut r = rule(allowed, &[var(&mut syms, "u")], &[pred(user, &[var(&mut syms, "u")])]);
+        r.negated.push(pred(banned, &[var(&mut syms, "u")]));
+        w.add_rule(r);
+        // banned($u) <- member($u, #admins)
+        w.add_rule(rule(
+            banned,
+            &[var(&mut syms, "u")],
+            &[pred(member, &[var(&mut syms, "u"), admins.clone()])],
+        ));
+
+        assert_eq!(w.stratify(), Ok(vec![1, 0]));
+        w.run().unwrap();
+
+        let res = w.query(pred(allowed, &[var(&mut syms, "u")]));
+        assert_eq!(res, vec![&fact(allowed, &[&alice])]);
+
+        // a predicate that depends on its own negation cannot be stratified
+        let mut r = rule(banned, &[var(&mut syms, "u")], &[pred(user, &[var(&mut syms, "u")])]);
+        r.negated.push(pred(allowed, &[var(&mut syms, "u")]));
+        w.add_rule(r);
+        assert!(w.stratify().is_err());
+        assert!(matches!(w.run(), Err(error::RunLimit::UnstratifiableRules(_))));
+    }
+
+    #[test]
+    fn unsafe_rules() {
+        let mut w = World::new();
+        let mut syms = SymbolTable::new();
+
+        let user = syms.insert("user");
+        let owner = syms.insert("owner");
+        let alice = syms.add("alice");
+
+        w.add_fact(fact(user, &[&alice]));
+
+        // owner($u, $f) <- user($u)
+        let r = rule(
+            owner,
+            &[var(&mut syms, "u"), var(&mut syms, "f")],
+            &[pred(user, &[var(&mut syms, "u")])],
+        );
+        assert!(!r.is_safe());
+        // the rule does not generate facts containing variables
+        w.add_rule(r);
+        w.run().unwrap();
+        assert!(w.query(pred(owner, &[var(&mut syms, "u"), var(&mut syms, "f")])).is_empty());
+
+        // owner($u) <- user($u), $f > 0
+        let r = constrained_rule(
+            owner,
+            &[var(&mut syms, "u")],
+            &[pred(user, &[var(&mut syms, "u")])],
+            &[Constraint {
+                id: syms.insert("f") as u32,
+                kind: ConstraintKind::Int(IntConstraint::Larger(0)),
+            }],
+        );
+        assert!(!r.is_safe());
+        // an unbound variable does not satisfy a constraint
+        assert!(!r.constraints[0].check(syms.insert("f") as u32, &var(&mut syms, "f")));
+
+        // owner($u) <- user($u), !owner($u, $f)
+        let mut r = rule(owner, &[var(&mut syms, "u")], &[pred(user, &[var(&mut syms, "u")])]);
+        assert!(r.is_safe());
+        r.negated.push(pred(owner, &[var(&mut syms, "u"), var(&mut syms, "f")]));
+        assert!(!r.is_safe());
+    }
+
+    #[test]
+    fn aggregates() {
+        let mut w = World::new();
+        let mut syms = SymbolTable::new();
+
+        let size = syms.insert("size");
+        let total = syms.insert("total");
+        let nb_files = syms.insert("nb_files");
+        let largest = syms.insert("largest");
+        let nb_dirs = syms.insert("nb_dirs");
+        let dir = syms.insert("dir");
+        let a = syms.add("a");
+        let b = syms.add("b");
+
+        w.add_fact(fact(size, &[&a, &ID::Str("file1".to_string()), &ID::Integer(10)]));
+        w.add_fact(fact(size, &[&a, &ID::Str("file2".to_string()), &ID::Integer(20)]));
+        w.add_fact(fact(size, &[&b, &ID::Str("file3".to_string()), &ID::Integer(5)]));
+
+        // total($d, sum($s)) <- size($d, $f, $s)
+        let mut r = rule(
+            total,
+            &[var(&mut syms, "d"), var(&mut syms, "s")],
+            &[pred(size, &[var(&mut syms, "d"), var(&mut syms, "f"), var(&mut syms, "s")])],
+        );
+        r.aggregates.push(Aggregate { position: 1, kind: AggregateKind::Sum });
+        w.add_rule(r);
+        // nb_files(count($f)) <- size($d, $f, $s)
+        let mut r = rule(
+            nb_files,
+            &[var(&mut syms, "f")],
+            &[pred(size, &[var(&mut syms, "d"), var(&mut syms, "f"), var(&mut syms, "s")])],
+        );
+        r.aggregates.push(Aggregate { position: 0, kind: AggregateKind::Count });
+        w.add_rule(r);
+        // largest(max($t)) <- total($d, $t)
+        let mut r = rule(
+            largest,
+            &[var(&mut syms, "t")],
+            &[pred(total, &[var(&mut syms, "d"), var(&mut syms, "t")])],
+        );
+        r.aggregates.push(Aggregate { position: 0, kind: AggregateKind::Max });
+        w.add_rule(r);
+        // nb_dirs(count($d)) <- dir($d)
+        let mut r = rule(nb_dirs, &[var(&mut syms, "d")], &[pred(dir, &[var(&mut syms, "d")])]);
+        r.aggregates.push(Aggregate { position: 0, kind: AggregateKind::Count });
+        w.add_rule(r);
+
+        // aggregates are computed once their body predicates are complete
+        assert_eq!(w.stratify(), Ok(vec![1, 1, 2, 1]));
+        w.run().unwrap();
+
+        let mut res = w.query_rule(rule(
+            total,
+            &[var(&mut syms, "d"), var(&mut syms, "t")],
+            &[pred(total, &[var(&mut syms, "d"), var(&mut syms, "t")])],
+        ));
+        res.sort_by_key(|f| format!("{:?}", f));
+        assert_eq!(
+            res,
+            vec![
+                fact(total, &[&a, &ID::Integer(30)]),
+                fact(total, &[&b, &ID::Integer(5)]),
+            ]
+        );
+
+        let facts = w.facts.iter().cloned().collect::<Vec<_>>();
+        assert!(facts.contains(&fact(nb_files, &[&ID::Integer(3)])));
+        assert!(facts.contains(&fact(largest, &[&ID::Integer(30)])));
+        // without any match, the count is 0
+        assert!(facts.contains(&fact(nb_dirs, &[&ID::Integer(0)])));
+
+        let r = &w.rules[0];
+        assert_eq!(syms.print_rule(r), "*total($d, sum($s)) <- size($d, $f, $s)");
+    }
+
+    #[test]
+    fn scoped() {
+        let mut w = World::new();
+        let mut syms = SymbolTable::new();
+
+        let right = syms.insert("right");
+        let allowed = syms.insert("allowed");
+        let a = syms.add("A");
+        let b = syms.add("B");
+        let c = syms.add("C");
+
+        w.add_fact_with_origin(fact(right, &[&a]), 0);
+        w.add_fact_with_origin(fact(right, &[&b]), 1);
+        w.add_fact_with_origin(fact(right, &[&b]), 2);
+        w.add_fact(fact(right, &[&c]));
+        // already visible in every scope
+        w.add_fact_with_origin(fact(right, &[&c]), 1);
+        w.add_rule_with_origin(
+            rule(allowed, &[var(&mut syms, "x")], &[pred(right, &[var(&mut syms, "x")])]),
+            2,
+        );
+
+        assert_eq!(w.fact_origin(&fact(right, &[&b])), Some(&[1, 2].iter().cloned().collect()));
+        assert_eq!(w.fact_origin(&fact(right, &[&c])), None);
+        assert_eq!(w.rule_origin(0), Some(2));
+
+        let mut scoped = w.scoped(&[0, 1].iter().cloned().collect());
+        assert_eq!(scoped.facts.len(), 3);
+        assert!(scoped.rules.is_empty());
+        scoped.run().unwrap();
+        assert!(scoped.query(pred(allowed, &[var(&mut syms, "x")])).is_empty());
+
+        let mut scoped = w.scoped(&[2].iter().cloned().collect());
+        assert_eq!(scoped.fact_origin(&fact(right, &[&b])), Some(&[2].iter().cloned().collect()));
+        scoped.run().unwrap();
+        let res = scoped.query(pred(allowed, &[var(&mut syms, "x")]));
+        assert_eq!(
+            res.into_iter().cloned().collect::<HashSet<_>>(),
+            [fact(allowed, &[&b]), fact(allowed, &[&c])].iter().cloned().collect::<HashSet<_>>()
+        );
+    }
+
+    #[test]
+    fn overlay() {
+        let mut policy = World::new();
+        let mut token = World::new();
+        let mut syms = SymbolTable::new();
+
+        let right = syms.insert("right");
+        let allowed = syms.insert("allowed");
+        let denied = syms.insert("denied");
+        let a = syms.add("A");
+        let b = syms.add("B");
+
+        policy.add_rule(rule(allowed, &[var(&mut syms, "x")], &[pred(right, &[var(&mut syms, "x")])]));
+        token.add_fact_with_origin(fact(right, &[&a]), 0);
+        token.add_fact_with_origin(fact(right, &[&b]), 1);
+        token.add_rule_with_origin(
+            rule(denied, &[var(&mut syms, "x")], &[pred(right, &[var(&mut syms, "x")])]),
+            1,
+        );
+
+        let scope = [0].iter().cloned().collect::<BTreeSet<usize>>();
+        let mut overlay = Overlay::new();
+        overlay.add_world(&policy, None);
+        overlay.add_world(&token, Some(&scope));
+        overlay.enable_provenance();
+        assert_eq!(overlay.rules().len(), 1);
+        overlay.run_with_limits(RunLimits::default()).unwrap();
+
+        let query = rule(allowed, &[var(&mut syms, "x")], &[pred(allowed, &[var(&mut syms, "x")])]);
+        assert_eq!(overlay.query_rule(query.clone()), vec![fact(allowed, &[&a])]);
+        assert_eq!(overlay.generated().len(), 1);
+        // the premises of the generated facts come from the worlds
+        assert_eq!(
+            overlay.explain(&fact(allowed, &[&a])),
+            DerivationTree {
+                fact: fact(allowed, &[&a]),
+                rule: Some(0),
+                premises: vec![DerivationTree {
+                    fact: fact(right, &[&a]),
+                    rule: None,
+                    premises: vec![],
+                }],
+            }
+        );
+
+        // same result as running a scoped copy of the worlds
+        let mut scoped = token.scoped(&scope);
+        scoped.rules = policy.rules.clone();
+        scoped.run().unwrap();
+        assert_eq!(scoped.query_rule(query), vec![fact(allowed, &[&a])]);
+
+        // the worlds are not modified
+        assert!(policy.facts.is_empty());
+        assert_eq!(token.facts.len(), 2);
+    }
+
+    #[test]
+    fn provenance() {
+        let mut w = World::new();
+        let mut syms = SymbolTable::new();
+
+        let parent = syms.insert("parent");
+        let grandparent = syms.insert("grandparent");
+        let a = syms.add("A");
+        let b = syms.add("B");
+        let c = syms.add("C");
+
+        w.add_fact(fact(parent, &[&a, &b]));
+        w.add_fact(fact(parent, &[&b, &c]));
+        w.add_rule(rule(
+            grandparent,
+            &[var(&mut syms, "grandparent"), var(&mut syms, "grandchild")],
+            &[
+                pred(parent, &[var(&mut syms, "grandparent"), var(&mut syms, "parent")]),
+                pred(parent, &[var(&mut syms, "parent"), var(&mut syms, "grandchild")]),
+            ],
+        ));
+
+        // provenance is only recorded once enabled
+        let mut w2 = w.clone();
+        w2.run().unwrap();
+        assert_eq!(w2.derivation(&fact(grandparent, &[&a, &c])), None);
+
+        w.enable_provenance();
+        w.run().unwrap();
+
+        assert_eq!(
+            w.explain(&fact(grandparent, &[&a, &c])),
+            DerivationTree {
+                fact: fact(grandparent, &[&a, &c]),
+                rule: Some(0),
+                premises: vec![
+                    DerivationTree {
+                        fact: fact(parent, &[&a, &b]),
+                        rule: None,
+                        premises: vec![],
+                    },
+                    DerivationTree {
+                        fact: fact(parent, &[&b, &c]),
+                        rule: None,
+                        premises: vec![],
+                    },
+                ],
+            }
+        );
+        assert_eq!(w.derivation(&fact(parent, &[&a, &b])), None);
+
+        let query = rule(
+            grandparent,
+            &[var(&mut syms, "x")],
+            &[pred(grandparent, &[var(&mut syms, "x"), c.clone()])],
+        );
+        assert_eq!(
+            w.query_rule_premises(&query),
+            vec![(fact(grandparent, &[&a]), vec![fact(grandparent, &[&a, &c])])]
+        );
+    }
+
+    #[test]
+    fn run_limits() {
+        let mut w = World::new();
+        let mut syms = SymbolTable::new();
+
+        let parent = syms.insert("parent");
+        let ancestor = syms.insert("ancestor");
+        let nodes = (0..10).map(|i| syms.add(&format!("n{}", i))).collect::<Vec<_>>();
+
+        for i in 0..9 {
+            w.add_fact(fact(parent, &[&nodes[i], &nodes[i + 1]]));
+        }
+
+        w.add_rule(rule(
+            ancestor,
+            &[var(&mut syms, "a"), var(&mut syms, "b")],
+            &[pred(parent, &[var(&mut syms, "a"), var(&mut syms, "b")])],
+        ));
+        w.add_rule(rule(
+            ancestor,
+            &[var(&mut syms, "a"), var(&mut syms, "c")],
+            &[
+                pred(ancestor, &[var(&mut syms, "a"), var(&mut syms, "b")]),
+                pred(parent, &[var(&mut syms, "b"), var(&mut syms, "c")]),
+            ],
+        ));
+
+        let limits = RunLimits {
+            max_iterations: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            w.clone().run_with_limits(limits),
+            Err(error::RunLimit::TooManyIterations)
+        );
+
+        let limits = RunLimits {
+            max_facts: 20,
+            ..Default::default()
+        };
+        assert_eq!(
+            w.clone().run_with_limits(limits),
+            Err(error::RunLimit::TooManyFacts)
+        );
+
+        let limits = RunLimits {
+            max_time: Duration::from_secs(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            w.clone().run_with_limits(limits),
+            Err(error::RunLimit::Timeout)
+        );
+
+        // 9 parent facts and 45 ancestor facts
+        let limits = RunLimits {
+            max_facts: 54,
+            max_iterations: 10,
+            max_time: Duration::from_secs(1),
+        };
+        assert_eq!(w.clone().run_with_limits(limits), Ok(()));
+
+        // the facts generated by a run count for the next runs sharing its budget
+        let mut budget = RunBudget::new(RunLimits {
+            max_facts: 90,
+            ..limits
+        });
+        let mut overlay = Overlay::new();
+        overlay.add_world(&w, None);
+        assert_eq!(overlay.run_within(&mut budget), Ok(()));
+        assert_eq!(budget.generated, 45);
+        let mut overlay = Overlay::new();
+        overlay.add_world(&w, None);
+        assert_eq!(overlay.run_within(&mut budget), Err(error::RunLimit::TooManyFacts));
+    }
+
+    #[test]
+    fn limits_in_rule_application() {
+        let mut w = World::new();
+        let mut syms = SymbolTable::new();
+
+        let value = syms.insert("value");
+        let product = syms.insert("product");
+        for i in 0..100 {
+            w.add_fact(fact(value, &[&int(i)]));
+        }
+        // a million combinations in one application of the rule
+        let r = rule(
+            product,
+            &[var(&mut syms, "x"), var(&mut syms, "y"), var(&mut syms, "z")],
+            &[
+                pred(value, &[var(&mut syms, "x")]),
+                pred(value, &[var(&mut syms, "y")]),
+                pred(value, &[var(&mut syms, "z")]),
+            ],
+        );
+
+        let mut generated = 0;
+        r.apply_with(&w.facts, None, &mut |_, _| {
+            generated += 1;
+            generated < 10
+        });
+        assert_eq!(generated, 10);
+
+        // the budget is checked before generating any fact
+        let budget = RunBudget::new(RunLimits {
+            max_time: Duration::from_secs(0),
+            ..Default::default()
+        });
+        let mut generated = 0;
+        let res = r.apply_within(&FactView::from(&w.facts), None, &budget, &mut |_, _| {
+            generated += 1;
+            true
+        });
+        assert_eq!(res, Err(error::RunLimit::Timeout));
+        assert_eq!(generated, 0);
+
+        // a hundred million combinations, all rejected by the negated
+        // predicate: no fact is generated, but the join is still stopped
+        let mut rejected = r.clone();
+        rejected.body.push(pred(value, &[var(&mut syms, "t")]));
+        rejected.negated = vec![pred(value, &[var(&mut syms, "x")])];
+        let mut w2 = w.clone();
+        w2.add_rule(rejected);
+        let limits = RunLimits {
+            max_time: Duration::from_millis(1),
+            ..Default::default()
+        };
+        assert_eq!(w2.run_with_limits(limits), Err(error::RunLimit::Timeout));
+
+        w.add_rule(r);
+        let limits = RunLimits {
+            max_facts: 1000,
+            max_time: Duration::from_secs(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            w.clone().run_with_limits(limits),
+            Err(error::RunLimit::TooManyFacts)
+        );
+        assert_eq!(w.facts.len(), 100);
+    }
+
     #[test]
     fn numbers() {
         let mut w = World::new();
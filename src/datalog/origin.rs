@@ -0,0 +1,78 @@
+//! origin of the facts and rules provided to a world
+//!
+//! the facts and rules of a token are tagged with the index of the block
+//! that provided them (0 for the authority block). A scoped world only
+//! contains the facts and rules of some blocks, so the caveats of a block
+//! cannot match facts generated by the rules of other blocks. Facts and
+//! rules added without an origin, like the ones of the verifier, are
+//! visible in every scope
+use super::{Fact, Rule, World};
+use std::collections::BTreeSet;
+
+impl World {
+    /// adds a fact provided by a block
+    ///
+    /// the same fact can be provided by multiple blocks, it is then
+    /// visible in the scopes of each of them
+    pub fn add_fact_with_origin(&mut self, fact: Fact, origin: usize) {
+        // already visible in every scope
+        if self.facts.contains(&fact) && !self.fact_origins.contains_key(&fact) {
+            return;
+        }
+
+        self.fact_origins.entry(fact.clone()).or_default().insert(origin);
+        self.facts.insert(fact);
+    }
+
+    /// adds a rule provided by a block
+    pub fn add_rule_with_origin(&mut self, rule: Rule, origin: usize) {
+        self.rule_origins.insert(self.rules.len(), origin);
+        self.rules.push(rule);
+    }
+
+    /// blocks that provided a fact, None if it is visible in every scope
+    pub fn fact_origin(&self, fact: &Fact) -> Option<&BTreeSet<usize>> {
+        self.fact_origins.get(fact)
+    }
+
+    /// block that provided the rule at this index of `rules`, None if it
+    /// is visible in every scope
+    pub fn rule_origin(&self, index: usize) -> Option<usize> {
+        self.rule_origins.get(&index).cloned()
+    }
+
+    /// copy of the world with only the facts and rules provided by these
+    /// blocks, and the ones visible in every scope
+    ///
+    /// the generated facts are not tracked, so this must be called before
+    /// running the rules
+    pub fn scoped(&self, blocks: &BTreeSet<usize>) -> World {
+        let mut world = World {
+            provenance: self.provenance.as_ref().map(|_| Default::default()),
+            ..World::default()
+        };
+
+        for fact in self.facts.iter() {
+            match self.fact_origins.get(fact) {
+                None => world.add_fact(fact.clone()),
+                Some(origins) => {
+                    for origin in origins.intersection(blocks) {
+                        world.add_fact_with_origin(fact.clone(), *origin);
+                    }
+                }
+            }
+        }
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            match self.rule_origin(i) {
+                None => world.add_rule(rule.clone()),
+                Some(origin) if blocks.contains(&origin) => {
+                    world.add_rule_with_origin(rule.clone(), origin)
+                }
+                Some(_) => {}
+            }
+        }
+
+        world
+    }
+}
@@ -0,0 +1,96 @@
+//! rules run over shared worlds, without copying them
+//!
+//! an `Overlay` borrows some worlds, and runs their rules over their facts,
+//! keeping the generated facts apart. The facts and rules of a world can be
+//! restricted to the ones provided by some blocks, like `World::scoped`
+//! does, so a world prepared once, like the facts of a token or of a
+//! verifier policy, can be used by many requests without being copied
+use super::{provenance, run_rules, Derivation, DerivationTree, Fact, FactSet, FactView, Rule, RunBudget, RunLimits, World};
+use crate::error;
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Clone, Default)]
+pub struct Overlay<'a> {
+    base: FactView<'a>,
+    rules: Vec<&'a Rule>,
+    /// facts generated by the rules, that are not in the worlds
+    generated: FactSet,
+    /// derivation of the generated facts, if provenance is enabled
+    provenance: Option<HashMap<Fact, Derivation>>,
+}
+
+impl<'a> Overlay<'a> {
+    pub fn new() -> Self {
+        Overlay::default()
+    }
+
+    /// adds the facts and rules of a world, restricted to the ones provided
+    /// by these blocks and the ones visible in every scope if there is a scope
+    ///
+    /// this must be called before running the rules
+    pub fn add_world(&mut self, world: &'a World, scope: Option<&'a BTreeSet<usize>>) {
+        self.base.add_world(world, scope);
+        for (i, rule) in world.rules.iter().enumerate() {
+            let visible = match (scope, world.rule_origin(i)) {
+                (Some(blocks), Some(origin)) => blocks.contains(&origin),
+                _ => true,
+            };
+            if visible {
+                self.rules.push(rule);
+            }
+        }
+    }
+
+    /// records how facts are generated, see `World::enable_provenance`
+    pub fn enable_provenance(&mut self) {
+        if self.provenance.is_none() {
+            self.provenance = Some(Default::default());
+        }
+    }
+
+    /// rules of the worlds, the derivations refer to their index in this list
+    pub fn rules(&self) -> &[&'a Rule] {
+        &self.rules
+    }
+
+    /// facts generated by the rules
+    pub fn generated(&self) -> &FactSet {
+        &self.generated
+    }
+
+    /// facts of the worlds and generated facts
+    pub fn facts(&self) -> FactView<'_> {
+        let mut facts = self.base.clone();
+        facts.add(&self.generated);
+        facts
+    }
+
+    /// runs the rules until no new fact is generated, see `World::run_with_limits`
+    pub fn run_with_limits(&mut self, limits: RunLimits) -> Result<(), error::RunLimit> {
+        self.run_within(&mut RunBudget::new(limits))
+    }
+
+    /// like `run_with_limits`, with a budget shared with other runs
+    pub fn run_within(&mut self, budget: &mut RunBudget) -> Result<(), error::RunLimit> {
+        run_rules(
+            &self.rules,
+            &self.base,
+            &mut self.generated,
+            self.provenance.as_mut(),
+            budget,
+        )
+    }
+
+    pub fn query_rule(&self, rule: Rule) -> Vec<Fact> {
+        self.facts().query_rule(rule)
+    }
+
+    pub fn query_rule_premises(&self, rule: &Rule) -> Vec<(Fact, Vec<Fact>)> {
+        self.facts().query_rule_premises(rule)
+    }
+
+    /// builds the derivation tree of a fact, see `World::explain`
+    pub fn explain(&self, fact: &Fact) -> DerivationTree {
+        provenance::explain(self.provenance.as_ref(), fact)
+    }
+}
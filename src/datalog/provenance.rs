@@ -0,0 +1,68 @@
+//! provenance of the facts generated by the rules
+//!
+//! when it is enabled in a `World`, each generated fact records the rule
+//! that produced it and the facts that matched the rule body. A fact can
+//! then be explained by a derivation tree, going back to the facts that
+//! were provided directly
+use super::{Fact, World};
+use std::collections::HashMap;
+
+/// how a fact was first generated
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derivation {
+    /// index of the rule in `World::rules`
+    pub rule: usize,
+    /// facts that matched the rule body
+    pub premises: Vec<Fact>,
+}
+
+/// explanation of a fact
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivationTree {
+    pub fact: Fact,
+    /// index of the rule that generated the fact, or None if it was provided directly
+    pub rule: Option<usize>,
+    /// explanations of the facts that matched the rule body
+    pub premises: Vec<DerivationTree>,
+}
+
+impl World {
+    /// records how facts are generated in the next runs
+    ///
+    /// this keeps the premises of every generated fact, so it is
+    /// only meant to explain results, not for normal verification
+    pub fn enable_provenance(&mut self) {
+        if self.provenance.is_none() {
+            self.provenance = Some(Default::default());
+        }
+    }
+
+    /// how a fact was generated, if provenance is enabled and it was not provided directly
+    pub fn derivation(&self, fact: &Fact) -> Option<&Derivation> {
+        self.provenance.as_ref().and_then(|p| p.get(fact))
+    }
+
+    /// builds the derivation tree of a fact
+    ///
+    /// the premises of a fact were all known before it was generated, so the
+    /// tree always ends with facts that were provided directly
+    pub fn explain(&self, fact: &Fact) -> DerivationTree {
+        explain(self.provenance.as_ref(), fact)
+    }
+}
+
+/// builds the derivation tree of a fact from the recorded derivations
+pub(super) fn explain(provenance: Option<&HashMap<Fact, Derivation>>, fact: &Fact) -> DerivationTree {
+    match provenance.and_then(|p| p.get(fact)) {
+        None => DerivationTree {
+            fact: fact.clone(),
+            rule: None,
+            premises: Vec::new(),
+        },
+        Some(derivation) => DerivationTree {
+            fact: fact.clone(),
+            rule: Some(derivation.rule),
+            premises: derivation.premises.iter().map(|p| explain(provenance, p)).collect(),
+        },
+    }
+}
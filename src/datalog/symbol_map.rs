@@ -0,0 +1,163 @@
+//! conversion of facts and rules between symbol tables
+//!
+//! a token and a verifier policy are built with different symbol tables.
+//! A `SymbolMap` gives the index in the verifier table of each symbol of
+//! the token, so the facts, rules and caveats of the token are converted
+//! while they are read, instead of printing and parsing them again
+use super::{
+    Caveat, Constraint, ConstraintKind, Expression, Fact, Op, Predicate, Rule, SetConstraint,
+    Symbol, SymbolConstraint, SymbolTable, ID,
+};
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolMap {
+    /// index in the target table of each symbol of the source table
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolMap {
+    /// adds the symbols of `from` missing in `to`
+    pub fn new(from: &SymbolTable, to: &mut SymbolTable) -> Self {
+        SymbolMap {
+            symbols: from.iter().map(|s| to.insert(s)).collect(),
+        }
+    }
+
+    /// true if the symbols keep their index, when the target table
+    /// extends the source table
+    pub fn is_identity(&self) -> bool {
+        self.symbols.iter().enumerate().all(|(i, s)| *s == i as Symbol)
+    }
+
+    /// symbols that are not in the source table are kept, like
+    /// `SymbolTable::print_symbol` they will be printed as unknown
+    pub fn symbol(&self, s: Symbol) -> Symbol {
+        self.symbols.get(s as usize).cloned().unwrap_or(s)
+    }
+
+    pub fn id(&self, id: &ID) -> ID {
+        match id {
+            ID::Symbol(s) => ID::Symbol(self.symbol(*s)),
+            ID::Variable(v) => ID::Variable(self.variable(*v)),
+            ID::Set(s) => ID::Set(self.set(s)),
+            id => id.clone(),
+        }
+    }
+
+    pub fn predicate(&self, p: &Predicate) -> Predicate {
+        Predicate {
+            name: self.symbol(p.name),
+            ids: p.ids.iter().map(|id| self.id(id)).collect(),
+        }
+    }
+
+    pub fn fact(&self, f: &Fact) -> Fact {
+        Fact {
+            predicate: self.predicate(&f.predicate),
+        }
+    }
+
+    pub fn rule(&self, r: &Rule) -> Rule {
+        Rule {
+            head: self.predicate(&r.head),
+            body: r.body.iter().map(|p| self.predicate(p)).collect(),
+            constraints: r.constraints.iter().map(|c| self.constraint(c)).collect(),
+            expressions: r
+                .expressions
+                .iter()
+                .map(|e| Expression {
+                    ops: e
+                        .ops
+                        .iter()
+                        .map(|op| match op {
+                            Op::Value(id) => Op::Value(self.id(id)),
+                            Op::Binary(b) => Op::Binary(*b),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            negated: r.negated.iter().map(|p| self.predicate(p)).collect(),
+            aggregates: r.aggregates.clone(),
+        }
+    }
+
+    pub fn caveat(&self, c: &Caveat) -> Caveat {
+        Caveat {
+            queries: c.queries.iter().map(|q| self.rule(q)).collect(),
+        }
+    }
+
+    fn variable(&self, v: u32) -> u32 {
+        self.symbol(v as Symbol) as u32
+    }
+
+    fn set(&self, s: &BTreeSet<ID>) -> BTreeSet<ID> {
+        s.iter().map(|id| self.id(id)).collect()
+    }
+
+    fn constraint(&self, c: &Constraint) -> Constraint {
+        let kind = match &c.kind {
+            ConstraintKind::Symbol(SymbolConstraint::In(h)) => {
+                ConstraintKind::Symbol(SymbolConstraint::In(h.iter().map(|s| self.symbol(*s)).collect()))
+            }
+            ConstraintKind::Symbol(SymbolConstraint::NotIn(h)) => {
+                ConstraintKind::Symbol(SymbolConstraint::NotIn(h.iter().map(|s| self.symbol(*s)).collect()))
+            }
+            ConstraintKind::Set(SetConstraint::Contains(id)) => {
+                ConstraintKind::Set(SetConstraint::Contains(self.id(id)))
+            }
+            ConstraintKind::Set(SetConstraint::Intersects(s)) => {
+                ConstraintKind::Set(SetConstraint::Intersects(self.set(s)))
+            }
+            ConstraintKind::Set(SetConstraint::Subset(s)) => {
+                ConstraintKind::Set(SetConstraint::Subset(self.set(s)))
+            }
+            kind => kind.clone(),
+        };
+
+        Constraint {
+            id: self.variable(c.id),
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert() {
+        let mut from = SymbolTable::new();
+        let mut to = SymbolTable::new();
+        to.insert("a");
+        to.insert("right");
+
+        let right = from.insert("right");
+        let read = from.insert("read");
+        let x = from.insert("x") as u32;
+        let map = SymbolMap::new(&from, &mut to);
+        assert_eq!(to.iter().collect::<Vec<_>>(), vec!["a", "right", "read", "x"]);
+
+        let rule = Rule {
+            head: Predicate::new(right, &[ID::Symbol(read), ID::Variable(x)]),
+            body: vec![Predicate::new(right, &[ID::Variable(x)])],
+            constraints: vec![Constraint {
+                id: x,
+                kind: ConstraintKind::Symbol(SymbolConstraint::In([read].iter().cloned().collect())),
+            }],
+            expressions: vec![],
+            negated: vec![],
+            aggregates: vec![],
+        };
+        let converted = map.rule(&rule);
+        assert_eq!(to.print_predicate(&converted.head), from.print_predicate(&rule.head));
+        assert_eq!(converted.head, Predicate::new(1, &[ID::Symbol(2), ID::Variable(3)]));
+        assert_eq!(converted.constraints[0].id, 3);
+        assert_eq!(
+            converted.constraints[0].kind,
+            ConstraintKind::Symbol(SymbolConstraint::In([2].iter().cloned().collect()))
+        );
+    }
+}
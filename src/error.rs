@@ -1,6 +1,7 @@
 //! error types
 //!
 
+use std::convert::Infallible;
 use thiserror::Error;
 
 #[derive(Error, Clone, Debug, PartialEq)]
@@ -21,8 +22,26 @@ pub enum Token {
     Sealed,
     #[error("caveat validation failed")]
     FailedLogic(Logic),
-    #[error("Datalog parsing error")]
-    ParseError,
+    #[error("Datalog parsing error: {0}")]
+    ParseError(Parse),
+    #[error("Reached Datalog execution limits")]
+    RunLimit(RunLimit),
+    #[error("a rule uses variables that are not bound by its body predicates")]
+    UnsafeRule(String),
+    #[error("a rule has no body predicates, so it cannot be printed as Datalog source")]
+    EmptyRuleBody(String),
+    #[error("the parameter does not appear in the template")]
+    UnknownParameter(String),
+    #[error("some parameters of the template are not bound to a value")]
+    UnboundParameters(Vec<String>),
+}
+
+/// conversions that cannot fail, like passing a `Fact` where
+/// a value convertible to a `Fact` is expected
+impl From<Infallible> for Token {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -31,6 +50,50 @@ pub struct InvalidBlockIndex {
     pub found: u32,
 }
 
+/// position and description of an error in a Datalog text
+#[derive(Error, Clone, Debug, PartialEq)]
+#[error("{message} at line {line}, column {column}")]
+pub struct Parse {
+    /// the text given to the parser
+    pub input: String,
+    /// position of the error in the input, in bytes
+    pub offset: usize,
+    /// line number, starting at 1
+    pub line: usize,
+    /// column number in characters, starting at 1
+    pub column: usize,
+    /// what the parser expected at this position, like `')'` or `a term`
+    pub expected: Option<String>,
+    pub message: String,
+}
+
+impl Parse {
+    pub(crate) fn new<S: Into<String>>(input: &str, offset: usize, message: S) -> Self {
+        let before = &input[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = match before.rfind('\n') {
+            Some(start) => before[start + 1..].chars().count() + 1,
+            None => before.chars().count() + 1,
+        };
+
+        Parse {
+            input: input.to_string(),
+            offset,
+            line,
+            column,
+            expected: None,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn expected(input: &str, offset: usize, expected: String) -> Self {
+        Parse {
+            expected: Some(expected.clone()),
+            ..Parse::new(input, offset, format!("expected {}", expected))
+        }
+    }
+}
+
 #[derive(Error, Clone, Debug, PartialEq)]
 pub enum Format {
     #[error("failed verifying the signature")]
@@ -49,6 +112,8 @@ pub enum Format {
     BlockDeserializationError(String),
     #[error("could not serialize the block")]
     BlockSerializationError(String),
+    #[error("a rule of the block uses variables that are not bound by its body predicates")]
+    UnsafeRule(String),
 }
 
 #[derive(Error, Clone, Debug, PartialEq)]
@@ -71,6 +136,16 @@ pub enum Logic {
     InvalidBlockRule(u32, String),
     #[error("list of caveats that failed validation")]
     FailedCaveats(Vec<FailedCaveat>),
+    #[error("the rules cannot be stratified: a predicate depends on its own negation")]
+    UnstratifiableRules(String),
+    #[error("a deny policy matched")]
+    Deny(usize),
+    #[error("no policy matched")]
+    NoMatchingPolicy,
+    #[error("the token was revoked")]
+    Revoked(u32),
+    #[error("a fact with the tag of a trusted third party was not provided by a block it signed")]
+    UntrustedThirdPartyFact(u32, String),
 }
 
 #[derive(Error, Clone, Debug, PartialEq)]
@@ -95,3 +170,15 @@ pub struct FailedVerifierCaveat {
     /// pretty print of the rule that failed
     pub rule: String,
 }
+
+#[derive(Error, Clone, Debug, PartialEq)]
+pub enum RunLimit {
+    #[error("too many facts generated")]
+    TooManyFacts,
+    #[error("too many engine iterations")]
+    TooManyIterations,
+    #[error("spent too much time verifying")]
+    Timeout,
+    #[error("the rules cannot be stratified: a predicate depends on its own negation")]
+    UnstratifiableRules(usize),
+}
@@ -6,6 +6,7 @@ use super::schema;
 use crate::datalog::*;
 use crate::error;
 use crate::token::Block;
+use std::collections::BTreeSet;
 
 pub fn token_sig_to_proto_sig(input: &TokenSignature) -> schema::Signature {
     schema::Signature {
@@ -76,22 +77,34 @@ pub fn proto_block_to_token_block(input: &schema::Block) -> Result<Block, error:
     }
 
     let mut rules = vec![];
-    for rule in input.rules.iter() {
-        rules.push(proto_rule_to_token_rule(rule)?);
+    for (i, rule) in input.rules.iter().enumerate() {
+        let rule = proto_rule_to_token_rule(rule)?;
+        if !rule.is_safe() {
+            return Err(error::Format::UnsafeRule(format!(
+                "block {}, rule {}",
+                input.index, i
+            )));
+        }
+        rules.push(rule);
     }
 
     let mut caveats = vec![];
-    for caveat in input.caveats.iter() {
-        caveats.push(proto_caveat_to_token_caveat(caveat)?);
+    for (i, caveat) in input.caveats.iter().enumerate() {
+        let caveat = proto_caveat_to_token_caveat(caveat)?;
+        if let Some(j) = caveat.queries.iter().position(|q| !q.is_safe()) {
+            return Err(error::Format::UnsafeRule(format!(
+                "block {}, caveat {}, query {}",
+                input.index, i, j
+            )));
+        }
+        caveats.push(caveat);
     }
 
     let context = input.context.clone();
 
     Ok(Block {
         index: input.index,
-        symbols: SymbolTable {
-            symbols: input.symbols.clone(),
-        },
+        symbols: SymbolTable::from(input.symbols.clone()),
         facts,
         rules,
         caveats,
@@ -140,6 +153,21 @@ pub fn token_rule_to_proto_rule(input: &Rule) -> schema::Rule {
             .iter()
             .map(token_constraint_to_proto_constraint)
             .collect(),
+        expressions: input
+            .expressions
+            .iter()
+            .map(token_expression_to_proto_expression)
+            .collect(),
+        negated: input
+            .negated
+            .iter()
+            .map(token_predicate_to_proto_predicate)
+            .collect(),
+        aggregates: input
+            .aggregates
+            .iter()
+            .map(token_aggregate_to_proto_aggregate)
+            .collect(),
     }
 }
 
@@ -156,10 +184,42 @@ pub fn proto_rule_to_token_rule(input: &schema::Rule) -> Result<Rule, error::For
         constraints.push(proto_constraint_to_token_constraint(c)?);
     }
 
+    let mut expressions = vec![];
+
+    for e in input.expressions.iter() {
+        expressions.push(proto_expression_to_token_expression(e)?);
+    }
+
+    let mut negated = vec![];
+
+    for p in input.negated.iter() {
+        negated.push(proto_predicate_to_token_predicate(p)?);
+    }
+
+    let head = proto_predicate_to_token_predicate(&input.head)?;
+    let mut aggregates = vec![];
+
+    for a in input.aggregates.iter() {
+        let aggregate = proto_aggregate_to_token_aggregate(a)?;
+
+        // an aggregate replaces a variable of the head
+        match head.ids.get(aggregate.position as usize) {
+            Some(ID::Variable(_)) => aggregates.push(aggregate),
+            _ => {
+                return Err(error::Format::DeserializationError(
+                    "deserialization error: invalid aggregate position".to_string(),
+                ))
+            }
+        }
+    }
+
     Ok(Rule {
-        head: proto_predicate_to_token_predicate(&input.head)?,
+        head,
         body,
         constraints,
+        expressions,
+        negated,
+        aggregates,
     })
 }
 
@@ -197,6 +257,8 @@ pub fn token_id_to_proto_id(input: &ID) -> schema::Id {
             str: None,
             date: None,
             bytes: None,
+            bool: None,
+            set: None,
         },
         ID::Variable(v) => schema::Id {
             kind: Kind::Variable as i32,
@@ -206,6 +268,8 @@ pub fn token_id_to_proto_id(input: &ID) -> schema::Id {
             str: None,
             date: None,
             bytes: None,
+            bool: None,
+            set: None,
         },
         ID::Integer(i) => schema::Id {
             kind: Kind::Integer as i32,
@@ -215,6 +279,8 @@ pub fn token_id_to_proto_id(input: &ID) -> schema::Id {
             str: None,
             date: None,
             bytes: None,
+            bool: None,
+            set: None,
         },
         ID::Str(s) => schema::Id {
             kind: Kind::Str as i32,
@@ -224,6 +290,8 @@ pub fn token_id_to_proto_id(input: &ID) -> schema::Id {
             str: Some(s.clone()),
             date: None,
             bytes: None,
+            bool: None,
+            set: None,
         },
         ID::Date(d) => schema::Id {
             kind: Kind::Date as i32,
@@ -233,6 +301,8 @@ pub fn token_id_to_proto_id(input: &ID) -> schema::Id {
             str: None,
             date: Some(*d),
             bytes: None,
+            bool: None,
+            set: None,
         },
         ID::Bytes(s) => schema::Id {
             kind: Kind::Bytes as i32,
@@ -242,6 +312,32 @@ pub fn token_id_to_proto_id(input: &ID) -> schema::Id {
             str: None,
             date: None,
             bytes: Some(s.clone()),
+            bool: None,
+            set: None,
+        },
+        ID::Bool(b) => schema::Id {
+            kind: Kind::Bool as i32,
+            symbol: None,
+            variable: None,
+            integer: None,
+            str: None,
+            date: None,
+            bytes: None,
+            bool: Some(*b),
+            set: None,
+        },
+        ID::Set(s) => schema::Id {
+            kind: Kind::Set as i32,
+            symbol: None,
+            variable: None,
+            integer: None,
+            str: None,
+            date: None,
+            bytes: None,
+            bool: None,
+            set: Some(schema::IdSet {
+                set: s.iter().map(token_id_to_proto_id).collect(),
+            }),
         },
     }
 }
@@ -288,6 +384,16 @@ pub fn proto_id_to_token_id(input: &schema::Id) -> Result<ID, error::Format> {
                 return Ok(ID::Bytes(s.clone()));
             }
         }
+        Kind::Bool => {
+            if let Some(b) = input.bool {
+                return Ok(ID::Bool(b));
+            }
+        }
+        Kind::Set => {
+            if let Some(ref s) = input.set {
+                return proto_set_to_token_set(&s.set).map(ID::Set);
+            }
+        }
     }
 
     Err(error::Format::DeserializationError(
@@ -307,6 +413,7 @@ pub fn token_constraint_to_proto_constraint(input: &Constraint) -> schema::Const
             date: None,
             symbol: None,
             bytes: None,
+            set: None,
         },
         ConstraintKind::Str(ref c) => schema::Constraint {
             id: input.id,
@@ -316,6 +423,7 @@ pub fn token_constraint_to_proto_constraint(input: &Constraint) -> schema::Const
             date: None,
             symbol: None,
             bytes: None,
+            set: None,
         },
         ConstraintKind::Date(ref c) => schema::Constraint {
             id: input.id,
@@ -325,6 +433,7 @@ pub fn token_constraint_to_proto_constraint(input: &Constraint) -> schema::Const
             date: Some(token_date_constraint_to_proto_date_constraint(c)),
             symbol: None,
             bytes: None,
+            set: None,
         },
         ConstraintKind::Symbol(ref c) => schema::Constraint {
             id: input.id,
@@ -334,6 +443,7 @@ pub fn token_constraint_to_proto_constraint(input: &Constraint) -> schema::Const
             date: None,
             symbol: Some(token_symbol_constraint_to_proto_symbol_constraint(c)),
             bytes: None,
+            set: None,
         },
         ConstraintKind::Bytes(ref c) => schema::Constraint {
             id: input.id,
@@ -343,7 +453,18 @@ pub fn token_constraint_to_proto_constraint(input: &Constraint) -> schema::Const
             date: None,
             symbol: None,
             bytes: Some(token_bytes_constraint_to_proto_bytes_constraint(c)),
-        }
+            set: None,
+        },
+        ConstraintKind::Set(ref c) => schema::Constraint {
+            id: input.id,
+            kind: Kind::Set as i32,
+            int: None,
+            str: None,
+            date: None,
+            symbol: None,
+            bytes: None,
+            set: Some(token_set_constraint_to_proto_set_constraint(c)),
+        },
     }
 }
 
@@ -401,6 +522,14 @@ pub fn proto_constraint_to_token_constraint(
                 });
             }
         }
+        Kind::Set => {
+            if let Some(ref i) = input.set {
+                return proto_set_constraint_to_token_set_constraint(i).map(|c| Constraint {
+                    id: input.id,
+                    kind: ConstraintKind::Set(c),
+                });
+            }
+        }
     }
 
     Err(error::Format::DeserializationError(
@@ -585,7 +714,7 @@ pub fn token_str_constraint_to_proto_str_constraint(
             equal: None,
             in_set: vec![],
             not_in_set: vec![],
-            regex: Some(r.clone()),
+            regex: Some(r.as_str().to_string()),
         },
         StrConstraint::In(s) => schema::StringConstraint {
             kind: Kind::In as i32,
@@ -639,7 +768,12 @@ pub fn proto_str_constraint_to_token_str_constraint(
         }
         Kind::Regex => {
             if let Some(ref r) = input.regex {
-                return Ok(StrConstraint::Regex(r.clone()));
+                return Regex::new(r).map(StrConstraint::Regex).map_err(|e| {
+                    error::Format::DeserializationError(format!(
+                        "deserialization error: invalid regex: {}",
+                        e
+                    ))
+                });
             }
         }
         Kind::In => {
@@ -827,3 +961,245 @@ pub fn proto_bytes_constraint_to_token_bytes_constraint(
         "deserialization error: invalid string constraint".to_string(),
     ))
 }
+
+/// converts the elements of a set, which cannot be variables
+pub fn proto_set_to_token_set(input: &[schema::Id]) -> Result<BTreeSet<ID>, error::Format> {
+    let mut set = BTreeSet::new();
+
+    for id in input.iter() {
+        match proto_id_to_token_id(id)? {
+            ID::Variable(_) => {
+                return Err(error::Format::DeserializationError(
+                    "deserialization error: sets cannot contain variables".to_string(),
+                ))
+            }
+            id => {
+                set.insert(id);
+            }
+        }
+    }
+
+    Ok(set)
+}
+
+pub fn token_set_constraint_to_proto_set_constraint(
+    input: &SetConstraint,
+) -> schema::SetConstraint {
+    use schema::set_constraint::Kind;
+
+    match input {
+        SetConstraint::Contains(id) => schema::SetConstraint {
+            kind: Kind::Contains as i32,
+            contains: Some(token_id_to_proto_id(id)),
+            intersects: vec![],
+            subset: vec![],
+        },
+        SetConstraint::Intersects(s) => schema::SetConstraint {
+            kind: Kind::Intersects as i32,
+            contains: None,
+            intersects: s.iter().map(token_id_to_proto_id).collect(),
+            subset: vec![],
+        },
+        SetConstraint::Subset(s) => schema::SetConstraint {
+            kind: Kind::Subset as i32,
+            contains: None,
+            intersects: vec![],
+            subset: s.iter().map(token_id_to_proto_id).collect(),
+        },
+    }
+}
+
+pub fn proto_set_constraint_to_token_set_constraint(
+    input: &schema::SetConstraint,
+) -> Result<SetConstraint, error::Format> {
+    use schema::set_constraint::Kind;
+
+    let kind = if let Some(i) = Kind::from_i32(input.kind) {
+        i
+    } else {
+        return Err(error::Format::DeserializationError(
+            "deserialization error: invalid set constraint kind".to_string(),
+        ));
+    };
+
+    match kind {
+        Kind::Contains => {
+            if let Some(ref id) = input.contains {
+                match proto_id_to_token_id(id)? {
+                    ID::Variable(_) => {}
+                    id => return Ok(SetConstraint::Contains(id)),
+                }
+            }
+        }
+        Kind::Intersects => {
+            return proto_set_to_token_set(&input.intersects).map(SetConstraint::Intersects);
+        }
+        Kind::Subset => {
+            return proto_set_to_token_set(&input.subset).map(SetConstraint::Subset);
+        }
+    }
+
+    Err(error::Format::DeserializationError(
+        "deserialization error: invalid set constraint".to_string(),
+    ))
+}
+
+pub fn token_expression_to_proto_expression(input: &Expression) -> schema::Expression {
+    schema::Expression {
+        ops: input.ops.iter().map(token_op_to_proto_op).collect(),
+    }
+}
+
+pub fn proto_expression_to_token_expression(
+    input: &schema::Expression,
+) -> Result<Expression, error::Format> {
+    let mut ops = vec![];
+
+    for op in input.ops.iter() {
+        ops.push(proto_op_to_token_op(op)?);
+    }
+
+    let expression = Expression { ops };
+    if !expression.is_valid() {
+        return Err(error::Format::DeserializationError(
+            "deserialization error: invalid expression".to_string(),
+        ));
+    }
+
+    Ok(expression)
+}
+
+pub fn token_op_to_proto_op(input: &Op) -> schema::Op {
+    use schema::op::Kind;
+
+    match input {
+        Op::Value(id) => schema::Op {
+            kind: Kind::Value as i32,
+            value: Some(token_id_to_proto_id(id)),
+            binary: None,
+        },
+        Op::Binary(b) => schema::Op {
+            kind: Kind::Binary as i32,
+            value: None,
+            binary: Some(token_binary_to_proto_binary(*b)),
+        },
+    }
+}
+
+pub fn proto_op_to_token_op(input: &schema::Op) -> Result<Op, error::Format> {
+    use schema::op::Kind;
+
+    let kind = if let Some(i) = Kind::from_i32(input.kind) {
+        i
+    } else {
+        return Err(error::Format::DeserializationError(
+            "deserialization error: invalid operation kind".to_string(),
+        ));
+    };
+
+    match kind {
+        Kind::Value => {
+            if let Some(ref id) = input.value {
+                return Ok(Op::Value(proto_id_to_token_id(id)?));
+            }
+        }
+        Kind::Binary => {
+            if let Some(ref b) = input.binary {
+                return Ok(Op::Binary(proto_binary_to_token_binary(b)?));
+            }
+        }
+    }
+
+    Err(error::Format::DeserializationError(
+        "deserialization error: invalid operation".to_string(),
+    ))
+}
+
+pub fn token_binary_to_proto_binary(input: Binary) -> schema::OpBinary {
+    use schema::op_binary::Kind;
+
+    let kind = match input {
+        Binary::LessThan => Kind::LessThan,
+        Binary::GreaterThan => Kind::GreaterThan,
+        Binary::LessOrEqual => Kind::LessOrEqual,
+        Binary::GreaterOrEqual => Kind::GreaterOrEqual,
+        Binary::Equal => Kind::Equal,
+        Binary::NotEqual => Kind::NotEqual,
+        Binary::StartsWith => Kind::StartsWith,
+        Binary::EndsWith => Kind::EndsWith,
+        Binary::Contains => Kind::Contains,
+        Binary::Add => Kind::Add,
+        Binary::Sub => Kind::Sub,
+        Binary::Mul => Kind::Mul,
+        Binary::Div => Kind::Div,
+    };
+
+    schema::OpBinary { kind: kind as i32 }
+}
+
+pub fn proto_binary_to_token_binary(input: &schema::OpBinary) -> Result<Binary, error::Format> {
+    use schema::op_binary::Kind;
+
+    let kind = if let Some(i) = Kind::from_i32(input.kind) {
+        i
+    } else {
+        return Err(error::Format::DeserializationError(
+            "deserialization error: invalid binary operation kind".to_string(),
+        ));
+    };
+
+    Ok(match kind {
+        Kind::LessThan => Binary::LessThan,
+        Kind::GreaterThan => Binary::GreaterThan,
+        Kind::LessOrEqual => Binary::LessOrEqual,
+        Kind::GreaterOrEqual => Binary::GreaterOrEqual,
+        Kind::Equal => Binary::Equal,
+        Kind::NotEqual => Binary::NotEqual,
+        Kind::StartsWith => Binary::StartsWith,
+        Kind::EndsWith => Binary::EndsWith,
+        Kind::Contains => Binary::Contains,
+        Kind::Add => Binary::Add,
+        Kind::Sub => Binary::Sub,
+        Kind::Mul => Binary::Mul,
+        Kind::Div => Binary::Div,
+    })
+}
+
+pub fn token_aggregate_to_proto_aggregate(input: &Aggregate) -> schema::Aggregate {
+    use schema::aggregate::Kind;
+
+    let kind = match input.kind {
+        AggregateKind::Count => Kind::Count,
+        AggregateKind::Min => Kind::Min,
+        AggregateKind::Max => Kind::Max,
+        AggregateKind::Sum => Kind::Sum,
+    };
+
+    schema::Aggregate {
+        position: input.position,
+        kind: kind as i32,
+    }
+}
+
+pub fn proto_aggregate_to_token_aggregate(
+    input: &schema::Aggregate,
+) -> Result<Aggregate, error::Format> {
+    use schema::aggregate::Kind;
+
+    let kind = match Kind::from_i32(input.kind) {
+        Some(Kind::Count) => AggregateKind::Count,
+        Some(Kind::Min) => AggregateKind::Min,
+        Some(Kind::Max) => AggregateKind::Max,
+        Some(Kind::Sum) => AggregateKind::Sum,
+        None => {
+            return Err(error::Format::DeserializationError(
+                "deserialization error: invalid aggregate kind".to_string(),
+            ))
+        }
+    };
+
+    Ok(Aggregate {
+        position: input.position,
+        kind,
+    })
+}
@@ -5,10 +5,11 @@
 //! - serialization of Biscuit blocks to Protobuf then `Vec<u8>`
 //! - serialization of a wrapper structure containing serialized blocks and the signature
 use super::crypto::{KeyPair, TokenSignature};
-use crate::crypto::PublicKey;
+use crate::crypto::{PublicKey, RootKeyProvider};
 use curve25519_dalek::ristretto::CompressedRistretto;
 use prost::Message;
 use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
 
 use super::error;
 use super::token::Block;
@@ -32,6 +33,67 @@ pub struct SerializedBiscuit {
     pub blocks: Vec<Vec<u8>>,
     pub keys: Vec<PublicKey>,
     pub signature: TokenSignature,
+    /// identifier of the root key, to choose it among multiple keys
+    ///
+    /// it is not signed: a modified identifier selects another key, and
+    /// then the token fails the root key check
+    pub root_key_id: Option<u32>,
+    /// signatures of the third party blocks, for each element of `blocks`
+    pub external_signatures: Vec<Option<ExternalSignature>>,
+}
+
+/// signature of a block by a third party, like an identity provider
+///
+/// it covers the unique identifier of the previous block (see
+/// `revocation_identifiers`) and the serialized block, so the block
+/// cannot be moved to another token
+#[derive(Clone, Debug)]
+pub struct ExternalSignature {
+    pub public_key: PublicKey,
+    pub signature: TokenSignature,
+}
+
+impl ExternalSignature {
+    pub fn new<T: RngCore + CryptoRng>(
+        rng: &mut T,
+        keypair: &KeyPair,
+        previous_id: &[u8],
+        payload: &[u8],
+    ) -> Self {
+        ExternalSignature {
+            public_key: keypair.public(),
+            signature: TokenSignature::new(rng, keypair, &external_message(previous_id, payload)),
+        }
+    }
+
+    pub fn verify(&self, previous_id: &[u8], payload: &[u8]) -> Result<(), error::Format> {
+        self.signature
+            .verify(&[self.public_key], &[external_message(previous_id, payload)])
+            .map_err(error::Format::Signature)
+    }
+}
+
+fn external_message(previous_id: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut message = previous_id.to_vec();
+    message.extend_from_slice(payload);
+    message
+}
+
+pub(crate) fn proto_key_to_public_key(key: &[u8]) -> Result<PublicKey, error::Format> {
+    if key.len() == 32 {
+        if let Some(k) = CompressedRistretto::from_slice(key).decompress() {
+            Ok(PublicKey(k))
+        } else {
+            Err(error::Format::DeserializationError(
+                "deserialization error: cannot decompress key point".to_string(),
+            ))
+        }
+    } else {
+        Err(error::Format::DeserializationError(format!(
+            "deserialization error: invalid size for key = {} bytes",
+            key.len()
+        )))
+    }
 }
 
 impl SerializedBiscuit {
@@ -43,29 +105,38 @@ impl SerializedBiscuit {
         let mut keys = vec![];
 
         for key in data.keys {
-            if key.len() == 32 {
-                if let Some(k) = CompressedRistretto::from_slice(&key[..]).decompress() {
-                    keys.push(PublicKey(k));
-                } else {
-                    return Err(error::Format::DeserializationError(
-                        "deserialization error: cannot decompress key point".to_string(),
-                    ));
-                }
-            } else {
-                return Err(error::Format::DeserializationError(format!(
-                    "deserialization error: invalid size for key = {} bytes",
-                    key.len()
-                )));
-            }
+            keys.push(proto_key_to_public_key(&key)?);
         }
 
         let signature = proto_sig_to_token_sig(data.signature)?;
 
+        let mut external_signatures = vec![None; data.blocks.len()];
+        for external in data.external_signatures {
+            // the authority block cannot be signed by a third party
+            let position = (external.block as usize).checked_sub(1);
+            match position.and_then(|i| external_signatures.get_mut(i)) {
+                Some(slot @ None) => {
+                    *slot = Some(ExternalSignature {
+                        public_key: proto_key_to_public_key(&external.public_key)?,
+                        signature: proto_sig_to_token_sig(external.signature)?,
+                    });
+                }
+                _ => {
+                    return Err(error::Format::DeserializationError(format!(
+                        "deserialization error: invalid block index for external signature = {}",
+                        external.block
+                    )));
+                }
+            }
+        }
+
         let deser = SerializedBiscuit {
             authority: data.authority,
             blocks: data.blocks,
             keys,
             signature,
+            root_key_id: data.root_key_id,
+            external_signatures,
         };
 
         match deser.verify() {
@@ -85,6 +156,19 @@ impl SerializedBiscuit {
                 .map(|k| Vec::from(&k.0.compress().to_bytes()[..]))
                 .collect(),
             signature: token_sig_to_proto_sig(&self.signature),
+            root_key_id: self.root_key_id,
+            external_signatures: self
+                .external_signatures
+                .iter()
+                .enumerate()
+                .filter_map(|(i, external)| {
+                    external.as_ref().map(|e| schema::ExternalSignature {
+                        block: i as u32 + 1,
+                        public_key: e.public_key.to_bytes().to_vec(),
+                        signature: token_sig_to_proto_sig(&e.signature),
+                    })
+                })
+                .collect(),
         }
     }
 
@@ -119,6 +203,8 @@ impl SerializedBiscuit {
             blocks: vec![],
             keys: vec![keypair.public()],
             signature,
+            root_key_id: None,
+            external_signatures: vec![],
         })
     }
 
@@ -136,23 +222,33 @@ impl SerializedBiscuit {
                 error::Format::SerializationError(format!("serialization error: {:?}", e))
             })?;
 
-        let mut blocks = Vec::new();
-        blocks.push(self.authority.clone());
-        blocks.extend(self.blocks.iter().cloned());
+        Ok(self.append_serialized(rng, keypair, v, None))
+    }
 
-        let signature = self.signature.sign(rng, keypair, &v);
+    /// adds a block that is already serialized, with the signature of a third party
+    pub fn append_serialized<T: RngCore + CryptoRng>(
+        &self,
+        rng: &mut T,
+        keypair: &KeyPair,
+        payload: Vec<u8>,
+        external: Option<ExternalSignature>,
+    ) -> Self {
+        let signature = self.signature.sign(rng, keypair, &payload);
 
         let mut t = SerializedBiscuit {
             authority: self.authority.clone(),
             blocks: self.blocks.clone(),
             keys: self.keys.clone(),
             signature,
+            root_key_id: self.root_key_id,
+            external_signatures: self.external_signatures.clone(),
         };
 
-        t.blocks.push(v);
+        t.blocks.push(payload);
         t.keys.push(keypair.public());
+        t.external_signatures.push(external);
 
-        Ok(t)
+        t
     }
 
     /// checks the signature on a deserialized token
@@ -167,17 +263,56 @@ impl SerializedBiscuit {
 
         self.signature
             .verify(&self.keys, &blocks)
-            .map_err(error::Format::Signature)
+            .map_err(error::Format::Signature)?;
+
+        let ids = self.revocation_identifiers();
+        // ids[i] is the identifier of the block before blocks[i]
+        for ((external, block), previous_id) in self.external_signatures.iter().zip(self.blocks.iter()).zip(ids.iter()) {
+            if let Some(external) = external {
+                external.verify(previous_id, block)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// unique identifiers of the authority block, then of each block
+    ///
+    /// the identifier of a block is a hash of the identifier of the previous
+    /// block, of the serialized block, and of its public key and signature
+    /// parameter. Tokens derived from a token share its identifiers, and two
+    /// tokens with the same content have different identifiers, since the
+    /// signature parameters are random
+    pub fn revocation_identifiers(&self) -> Vec<Vec<u8>> {
+        let blocks = std::iter::once(&self.authority).chain(self.blocks.iter());
+        let mut previous: Vec<u8> = Vec::new();
+        let mut ids = Vec::new();
+
+        for ((block, key), parameter) in blocks.zip(self.keys.iter()).zip(self.signature.parameters.iter()) {
+            let id = Sha256::new()
+                .chain(&previous)
+                .chain(block)
+                .chain(key.0.compress().as_bytes())
+                .chain(parameter.compress().as_bytes())
+                .finalize()
+                .to_vec();
+            ids.push(id.clone());
+            previous = id;
+        }
+
+        ids
     }
 
-    pub fn check_root_key(&self, root: PublicKey) -> Result<(), error::Format> {
+    /// checks that the token was created with one of the root keys
+    /// chosen by the provider, and returns that key
+    pub fn check_root_key<K: RootKeyProvider>(&self, root: K) -> Result<PublicKey, error::Format> {
         if self.keys.is_empty() {
             return Err(error::Format::EmptyKeys);
         }
-        if self.keys[0] != root {
-            return Err(error::Format::UnknownPublicKey);
-        }
 
-        Ok(())
+        root.keys(self.root_key_id)
+            .into_iter()
+            .find(|key| *key == self.keys[0])
+            .ok_or(error::Format::UnknownPublicKey)
     }
 }
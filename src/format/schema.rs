@@ -8,6 +8,37 @@ pub struct Biscuit {
     pub keys: ::std::vec::Vec<std::vec::Vec<u8>>,
     #[prost(message, required, tag="4")]
     pub signature: Signature,
+    #[prost(uint32, optional, tag="5")]
+    pub root_key_id: ::std::option::Option<u32>,
+    #[prost(message, repeated, tag="6")]
+    pub external_signatures: ::std::vec::Vec<ExternalSignature>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ExternalSignature {
+    #[prost(uint32, required, tag="1")]
+    pub block: u32,
+    #[prost(bytes, required, tag="2")]
+    pub public_key: std::vec::Vec<u8>,
+    #[prost(message, required, tag="3")]
+    pub signature: Signature,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ThirdPartyBlockRequest {
+    #[prost(bytes, required, tag="1")]
+    pub previous_id: std::vec::Vec<u8>,
+    #[prost(uint32, required, tag="2")]
+    pub index: u32,
+    #[prost(string, repeated, tag="3")]
+    pub symbols: ::std::vec::Vec<std::string::String>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ThirdPartyBlockContents {
+    #[prost(bytes, required, tag="1")]
+    pub payload: std::vec::Vec<u8>,
+    #[prost(bytes, required, tag="2")]
+    pub public_key: std::vec::Vec<u8>,
+    #[prost(message, required, tag="3")]
+    pub signature: Signature,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct SealedBiscuit {
@@ -53,6 +84,29 @@ pub struct Rule {
     pub body: ::std::vec::Vec<Predicate>,
     #[prost(message, repeated, tag="3")]
     pub constraints: ::std::vec::Vec<Constraint>,
+    #[prost(message, repeated, tag="4")]
+    pub expressions: ::std::vec::Vec<Expression>,
+    #[prost(message, repeated, tag="5")]
+    pub negated: ::std::vec::Vec<Predicate>,
+    #[prost(message, repeated, tag="6")]
+    pub aggregates: ::std::vec::Vec<Aggregate>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Aggregate {
+    #[prost(uint32, required, tag="1")]
+    pub position: u32,
+    #[prost(enumeration="aggregate::Kind", required, tag="2")]
+    pub kind: i32,
+}
+pub mod aggregate {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        Count = 0,
+        Min = 1,
+        Max = 2,
+        Sum = 3,
+    }
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Caveat {
@@ -60,6 +114,34 @@ pub struct Caveat {
     pub queries: ::std::vec::Vec<Rule>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
+pub struct VerifierPolicies {
+    #[prost(string, repeated, tag="1")]
+    pub symbols: ::std::vec::Vec<std::string::String>,
+    #[prost(message, repeated, tag="2")]
+    pub facts: ::std::vec::Vec<Fact>,
+    #[prost(message, repeated, tag="3")]
+    pub rules: ::std::vec::Vec<Rule>,
+    #[prost(message, repeated, tag="4")]
+    pub caveats: ::std::vec::Vec<Caveat>,
+    #[prost(message, repeated, tag="5")]
+    pub policies: ::std::vec::Vec<Policy>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Policy {
+    #[prost(message, repeated, tag="1")]
+    pub queries: ::std::vec::Vec<Rule>,
+    #[prost(enumeration="policy::Kind", required, tag="2")]
+    pub kind: i32,
+}
+pub mod policy {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        Allow = 0,
+        Deny = 1,
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Predicate {
     #[prost(uint64, required, tag="1")]
     pub name: u64,
@@ -82,6 +164,10 @@ pub struct Id {
     pub date: ::std::option::Option<u64>,
     #[prost(bytes, optional, tag="7")]
     pub bytes: ::std::option::Option<std::vec::Vec<u8>>,
+    #[prost(bool, optional, tag="8")]
+    pub bool: ::std::option::Option<bool>,
+    #[prost(message, optional, tag="9")]
+    pub set: ::std::option::Option<IdSet>,
 }
 pub mod id {
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
@@ -93,9 +179,16 @@ pub mod id {
         Str = 3,
         Date = 4,
         Bytes = 5,
+        Bool = 6,
+        Set = 7,
     }
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
+pub struct IdSet {
+    #[prost(message, repeated, tag="1")]
+    pub set: ::std::vec::Vec<Id>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Constraint {
     #[prost(uint32, required, tag="1")]
     pub id: u32,
@@ -111,6 +204,8 @@ pub struct Constraint {
     pub symbol: ::std::option::Option<SymbolConstraint>,
     #[prost(message, optional, tag="7")]
     pub bytes: ::std::option::Option<BytesConstraint>,
+    #[prost(message, optional, tag="8")]
+    pub set: ::std::option::Option<SetConstraint>,
 }
 pub mod constraint {
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
@@ -121,6 +216,7 @@ pub mod constraint {
         Date = 2,
         Symbol = 3,
         Bytes = 4,
+        Set = 5,
     }
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -238,3 +334,69 @@ pub mod bytes_constraint {
         NotIn = 2,
     }
 }
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetConstraint {
+    #[prost(enumeration="set_constraint::Kind", required, tag="1")]
+    pub kind: i32,
+    #[prost(message, optional, tag="2")]
+    pub contains: ::std::option::Option<Id>,
+    #[prost(message, repeated, tag="3")]
+    pub intersects: ::std::vec::Vec<Id>,
+    #[prost(message, repeated, tag="4")]
+    pub subset: ::std::vec::Vec<Id>,
+}
+pub mod set_constraint {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        Contains = 0,
+        Intersects = 1,
+        Subset = 2,
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Expression {
+    #[prost(message, repeated, tag="1")]
+    pub ops: ::std::vec::Vec<Op>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Op {
+    #[prost(enumeration="op::Kind", required, tag="1")]
+    pub kind: i32,
+    #[prost(message, optional, tag="2")]
+    pub value: ::std::option::Option<Id>,
+    #[prost(message, optional, tag="3")]
+    pub binary: ::std::option::Option<OpBinary>,
+}
+pub mod op {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        Value = 0,
+        Binary = 1,
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct OpBinary {
+    #[prost(enumeration="op_binary::Kind", required, tag="1")]
+    pub kind: i32,
+}
+pub mod op_binary {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        LessThan = 0,
+        GreaterThan = 1,
+        LessOrEqual = 2,
+        GreaterOrEqual = 3,
+        Equal = 4,
+        NotEqual = 5,
+        StartsWith = 6,
+        EndsWith = 7,
+        Contains = 8,
+        Add = 9,
+        Sub = 10,
+        Mul = 11,
+        Div = 12,
+    }
+}
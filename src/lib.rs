@@ -81,7 +81,7 @@
 //!     ));
 //!
 //!     // the previous caveat could also be written like this
-//!     // builder.add_caveat("*caveat(#resource) <- resource(#ambient, \"/a/file1.txt\"), !operation(#ambient, #read)")?;
+//!     // builder.add_caveat("*caveat(#resource) <- resource(#ambient, \"/a/file1.txt\"), operation(#ambient, #read)")?;
 //!
 //!     let keypair = KeyPair::new(&mut rng);
 //!     // we can now create a new token
@@ -180,16 +180,43 @@
 //! Blocks can provide facts but they cannot be authority or ambient facts. They
 //! contain rules that use facts from the current block, or from the authority
 //! and ambient contexts. If all rules in a block succeed, the block is validated.
+//! The caveats of a block only see the facts of the authority block, of the verifier
+//! and of the block itself, so a block cannot make the caveats of another block pass.
+//! `Verifier::set_fact_scope(FactScope::Shared)` restores the behavior of previous
+//! versions, where the facts of all blocks are shared.
 //!
 //! A caveat rule requires the presence of one or more facts, and can have additional
-//! constraints on these facts (the constraints are implemented separately to simplify
-//! the language implementation: among other things, it avoids implementing negation).
+//! constraints on these facts. It can also require the absence of facts, with negated
+//! predicates prefixed by `!`: rules are evaluated in strata, so a negated predicate is
+//! only tested once all its facts are known, and rules where a predicate depends on its
+//! own negation are rejected.
+//! Rule heads can contain the aggregates `count`, `min`, `max` and `sum`, computed
+//! over all the matches of the body, grouped by the other head terms, like
+//! `*nb_files(count($f)) <- right(#authority, $f, #read)`.
 //! It is possible to create rules like these ones:
 //!
-//! - *caveat() <- !resource("file1")
-//! - *caveat() <- !resource($0), !owner("user1", $0) // the $0 represents a "hole" that must be filled with the correct value
-//! - *caveat() <- !time($0) @ $0 < 2019-02-05T23:00:00Z // expiration date
-//! - *caveat() <- !application($0), !operation($1), !user($2), !right(#app, $0, $1), !owner($2, $0), !credit($2, $3) @ $3 > 0 // verifies that the user owns the applications, the application has the right on the operation, there's a credit information for the operation, and the credit is larger than 0
+//! - *caveat() <- resource("file1")
+//! - *caveat() <- resource($0), owner("user1", $0) // the $0 represents a "hole" that must be filled with the correct value
+//! - *caveat() <- time($0) @ $0 < 2019-02-05T23:00:00Z // expiration date
+//! - *caveat() <- application($0), operation($1), user($2), right(#app, $0, $1), owner($2, $0), credit($2, $3) @ $3 > 0 // verifies that the user owns the applications, the application has the right on the operation, there's a credit information for the operation, and the credit is larger than 0
+//! - *caveat() <- user($0), !banned($0) // the user must not be banned
+//! - *caveat() <- roles($0) @ $0 intersects [#admin, #owner] // values can be booleans (`true`, `false`) or sets like `[#read, #write]`, and sets support the `contains`, `intersects` and `subset` constraints
+//! - *caveat() <- quota($0), requested($1) @ $1 < $0 // expressions can compare variables, and use arithmetic like `$now <= $expires + 3600` or string methods like `$path.starts_with($prefix)`
+//!
+//! Once all the caveats pass, the verifier can test a list of policies in order, like
+//! `allow if *allowed($0) <- right(#authority, $0, #read), resource(#ambient, $0)` or
+//! `deny if *denied($0) <- user($0), banned($0)`. The first policy that matches decides
+//! whether the token is accepted, and if none matches, it is rejected.
+//!
+//! Block builders and verifiers can also load a whole source text with `add_source`:
+//! facts, rules, caveats written as `check if *caveat($0) <- ...`, policies and the
+//! block context written as `context "..."`, each ending with `;`, with `//` comments.
+//! `Biscuit::to_source` prints the contents of a token in that format.
+//!
+//! Facts, rules, caveats and policies can be written as templates with parameters
+//! like `resource(#ambient, {path})`. The parameters are bound with `set` to values
+//! built with `builder::string`, `builder::int`, etc, so they cannot change the
+//! meaning of the Datalog code, and the templates with unbound parameters are rejected.
 //!
 //! ## Symbols and symbol tables
 //!
@@ -201,7 +228,7 @@
 //!
 //! They can be used for pretty printing of a fact or rule. As an example, with a table
 //! containing `["resource", "operation", "read", "caveat1"], we could have the following rule:
-//! `#4 <- #0("file.txt"), #1(#2)` that would be printed as `*caveat1() <- !resource("file.txt"), !operation(#read)`
+//! `#4 <- #0("file.txt"), #1(#2)` that would be printed as `*caveat1() <- resource("file.txt"), operation(#read)`
 //!
 //! biscuit implementations come with a default symbol table to avoid transmitting
 //! frequent values with every token.
@@ -1,66 +1,243 @@
 use crate::{datalog, error, token::builder};
 use nom::{
     branch::alt,
-    bytes::complete::{escaped_transform, tag, take_while1},
+    bytes::complete::{escaped_transform, tag, take_while, take_while1},
     character::{
-        complete::{char, digit1, space0},
+        complete::{char, digit1, multispace0, multispace1, one_of},
         is_alphanumeric,
     },
-    combinator::{map, map_opt, map_res, opt, recognize, value},
-    multi::separated_list1,
-    sequence::{delimited, pair, preceded},
+    combinator::{cut, map, map_opt, map_res, not, opt, recognize, value},
+    error::{ErrorKind, FromExternalError, ParseError},
+    multi::{fold_many0, separated_list1},
+    sequence::{delimited, pair, preceded, terminated},
     IResult,
 };
 use std::{
+    collections::BTreeSet,
     convert::{TryFrom, TryInto},
+    fmt,
     str::FromStr,
     time::{Duration, SystemTime},
 };
 
-pub fn fact(i: &str) -> IResult<&str, builder::Fact> {
+/// error of the parsers: the remaining input where parsing
+/// failed, and what was expected there
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error<'a> {
+    pub input: &'a str,
+    pub expected: Expected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expected {
+    Char(char),
+    Description(&'static str),
+    Kind(ErrorKind),
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Char(c) => write!(f, "'{}'", c),
+            Expected::Description(d) => write!(f, "{}", d),
+            Expected::Kind(ErrorKind::Tag) => write!(f, "a keyword"),
+            Expected::Kind(ErrorKind::Digit) => write!(f, "a number"),
+            Expected::Kind(ErrorKind::TakeWhile1) => write!(f, "a name"),
+            Expected::Kind(ErrorKind::MapRes) | Expected::Kind(ErrorKind::MapOpt) => {
+                write!(f, "a valid value")
+            }
+            Expected::Kind(kind) => write!(f, "{}", kind.description()),
+        }
+    }
+}
+
+impl<'a> ParseError<&'a str> for Error<'a> {
+    fn from_error_kind(input: &'a str, kind: ErrorKind) -> Self {
+        Error {
+            input,
+            expected: Expected::Kind(kind),
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn from_char(input: &'a str, c: char) -> Self {
+        Error {
+            input,
+            expected: Expected::Char(c),
+        }
+    }
+
+    /// keeps the error of the alternative that went the furthest
+    fn or(self, other: Self) -> Self {
+        if self.input.len() < other.input.len() {
+            self
+        } else {
+            other
+        }
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for Error<'a> {
+    fn from_external_error(input: &'a str, kind: ErrorKind, _e: E) -> Self {
+        Error::from_error_kind(input, kind)
+    }
+}
+
+impl<'a> Error<'a> {
+    /// converts the error to the public type, `input` being the whole text given to the parser
+    pub fn to_parse_error(&self, input: &str) -> error::Parse {
+        error::Parse::expected(input, input.len() - self.input.len(), self.expected.to_string())
+    }
+}
+
+/// list of elements separated by commas: after a comma, the next element is required
+fn comma_list1<'a, O, F>(mut element: F) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<O>, Error<'a>>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O, Error<'a>>,
+{
+    move |i: &'a str| {
+        let (mut i, first) = element(i)?;
+        let mut elements = vec![first];
+
+        while let Ok((rest, _)) = preceded(multispace0, char::<_, Error>(','))(i) {
+            let (rest, e) = cut(&mut element)(rest)?;
+            elements.push(e);
+            i = rest;
+        }
+
+        Ok((i, elements))
+    }
+}
+
+/// replaces the error of a parser that fails before parsing
+/// anything with a description of what was expected
+fn expect<'a, O, F>(description: &'static str, mut parser: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, Error<'a>>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O, Error<'a>>,
+{
+    move |i: &'a str| {
+        parser(i).map_err(|e| {
+            e.map(|e| {
+                let start = i.trim_start_matches(|c| " \t\r\n".contains(c));
+                if e.input.len() >= start.len() {
+                    Error {
+                        input: start,
+                        expected: Expected::Description(description),
+                    }
+                } else {
+                    e
+                }
+            })
+        })
+    }
+}
+
+/// runs a parser on the whole input, only whitespace can remain after what was parsed
+fn parse_all<'a, O, F>(input: &'a str, parser: F) -> Result<O, error::Token>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O, Error<'a>>,
+{
+    match terminated(parser, multispace0)(input) {
+        Ok(("", o)) => Ok(o),
+        Ok((rest, _)) => Err(error::Token::ParseError(error::Parse::expected(
+            input,
+            input.len() - rest.len(),
+            "end of input".to_string(),
+        ))),
+        Err(e) => Err(error::Token::ParseError(nom_error(input, e))),
+    }
+}
+
+fn nom_error(input: &str, e: nom::Err<Error>) -> error::Parse {
+    match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e.to_parse_error(input),
+        nom::Err::Incomplete(_) => {
+            error::Parse::expected(input, input.len(), "more input".to_string())
+        }
+    }
+}
+
+pub fn fact(i: &str) -> IResult<&str, builder::Fact, Error<'_>> {
     predicate(i).map(|(i, p)| (i, builder::Fact(p)))
 }
 
-pub fn caveat(i: &str) -> IResult<&str, builder::Caveat> {
+pub fn caveat(i: &str) -> IResult<&str, builder::Caveat, Error<'_>> {
     let (i, queries) = separated_list1(
-      preceded(space0, tag("||")),
-      preceded(space0, rule)
+      preceded(multispace0, tag("||")),
+      preceded(multispace0, rule)
     )(i)?;
 
     Ok((i, builder::Caveat { queries }))
 }
 
-pub fn rule(i: &str) -> IResult<&str, builder::Rule> {
-    let (i, _) = char('*')(i)?;
-    let (i, head) = predicate(i)?;
-    let (i, _) = space0(i)?;
+pub fn policy(i: &str) -> IResult<&str, builder::Policy, Error<'_>> {
+    let (i, kind) = alt((
+        value(builder::PolicyKind::Allow, tag("allow")),
+        value(builder::PolicyKind::Deny, tag("deny")),
+    ))(i)?;
+    let (i, _) = preceded(multispace1, expect("'if'", tag("if")))(i)?;
+    let (i, _) = multispace1(i)?;
+    let (i, caveat) = caveat(i)?;
 
-    let (i, _) = tag("<-")(i)?;
+    Ok((i, builder::Policy { queries: caveat.queries, kind }))
+}
 
-    let (i, _) = space0(i)?;
-    let (i, predicates) = separated_list1(
-      preceded(space0, char(',')),
-      preceded(space0, predicate)
-    )(i)?;
+pub fn rule(i: &str) -> IResult<&str, builder::Rule, Error<'_>> {
+    let (i, _) = char('*')(i)?;
+    let (i, head) = head_predicate(i)?;
+    let (i, _) = multispace0(i)?;
+
+    let (i, _) = expect("'<-'", tag("<-"))(i)?;
+
+    let (i, _) = multispace0(i)?;
+    let (i, body) = comma_list1(preceded(multispace0, body_predicate))(i)?;
+
+    let mut predicates = Vec::new();
+    let mut negated = Vec::new();
+    for (is_negated, p) in body {
+        if is_negated {
+            negated.push(p);
+        } else {
+            predicates.push(p);
+        }
+    }
 
-    let (i, constraints) = if let Ok((i, _)) =
-        preceded::<_, _, _, (&str, nom::error::ErrorKind), _, _>(space0, char('@'))(i)
+    let mut constraints = Vec::new();
+    let mut expressions = Vec::new();
+    let i = if let Ok((i, _)) =
+        preceded::<_, _, _, (&str, nom::error::ErrorKind), _, _>(multispace0, char('@'))(i)
     {
-        separated_list1(preceded(space0, char(',')), constraint)(i)?
+        let (i, conditions) = comma_list1(condition)(i)?;
+        for c in conditions {
+            match c {
+                Condition::Constraint(c) => constraints.push(c),
+                Condition::Expression(e) => expressions.push(e),
+            }
+        }
+        i
     } else {
-        (i, Vec::new())
+        i
     };
 
-    Ok((i, builder::Rule(head, predicates, constraints)))
+    Ok((i, builder::Rule { head, body: predicates, constraints, expressions, negated }))
+}
+
+/// predicate in a rule body, returns true if it is negated (`!banned($u)`)
+fn body_predicate(i: &str) -> IResult<&str, (bool, builder::Predicate), Error<'_>> {
+    alt((
+        map(preceded(char('!'), predicate), |p| (true, p)),
+        map(predicate, |p| (false, p)),
+    ))(i)
 }
 
 impl TryFrom<&str> for builder::Fact {
     type Error = error::Token;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        fact(value)
-            .map(|(_, o)| o)
-            .map_err(|_| error::Token::ParseError)
+        parse_all(value, fact)
     }
 }
 
@@ -68,9 +245,7 @@ impl TryFrom<&str> for builder::Rule {
     type Error = error::Token;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        rule(value)
-            .map(|(_, o)| o)
-            .map_err(|_| error::Token::ParseError)
+        parse_all(value, rule)
     }
 }
 
@@ -78,9 +253,7 @@ impl FromStr for builder::Fact {
     type Err = error::Token;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        fact(s)
-            .map(|(_, o)| o)
-            .map_err(|_| error::Token::ParseError)
+        parse_all(s, fact)
     }
 }
 
@@ -88,9 +261,7 @@ impl FromStr for builder::Rule {
     type Err = error::Token;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        rule(s)
-            .map(|(_, o)| o)
-            .map_err(|_| error::Token::ParseError)
+        parse_all(s, rule)
     }
 }
 
@@ -98,9 +269,7 @@ impl TryFrom<&str> for builder::Caveat {
     type Error = error::Token;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        caveat(value)
-            .map(|(_, o)| o)
-            .map_err(|_| error::Token::ParseError)
+        parse_all(value, caveat)
     }
 }
 
@@ -108,9 +277,23 @@ impl FromStr for builder::Caveat {
     type Err = error::Token;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        caveat(s)
-            .map(|(_, o)| o)
-            .map_err(|_| error::Token::ParseError)
+        parse_all(s, caveat)
+    }
+}
+
+impl TryFrom<&str> for builder::Policy {
+    type Error = error::Token;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        parse_all(value, policy)
+    }
+}
+
+impl FromStr for builder::Policy {
+    type Err = error::Token;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_all(s, policy)
     }
 }
 
@@ -118,21 +301,113 @@ impl FromStr for builder::Predicate {
     type Err = error::Token;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        predicate(s)
-            .map(|(_, o)| o)
-            .map_err(|_| error::Token::ParseError)
+        parse_all(s, predicate)
+    }
+}
+
+/// statement of a Datalog source text
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Fact(builder::Fact),
+    Rule(builder::Rule),
+    /// `check if *query($0) <- ... || *query() <- ...`
+    Caveat(builder::Caveat),
+    /// `allow if ...` or `deny if ...`
+    Policy(builder::Policy),
+    /// `context "..."`, the context of a block
+    Context(String),
+}
+
+/// parses a source text made of statements ending with `;`, and `//` comments
+///
+/// statements can span multiple lines. They are returned with
+/// their offset in the input
+pub fn parse_source(input: &str) -> Result<Vec<(usize, Statement)>, error::Parse> {
+    let mut statements = Vec::new();
+    let mut i = skip_comments(input);
+
+    while !i.is_empty() {
+        let offset = input.len() - i.len();
+
+        let (kind, res) = if keyword("allow")(i).is_ok() || keyword("deny")(i).is_ok() {
+            ("policy", map(policy, Statement::Policy)(i))
+        } else if let Ok((rest, _)) = keyword("check")(i) {
+            let caveat = preceded(pair(tag("if"), multispace1), caveat);
+            ("caveat", map(caveat, Statement::Caveat)(rest))
+        } else if let Ok((rest, _)) = keyword("context")(i) {
+            ("context", map(parse_string, Statement::Context)(rest))
+        } else if i.starts_with('*') {
+            ("rule", map(rule, Statement::Rule)(i))
+        } else {
+            ("fact", map(fact, Statement::Fact)(i))
+        };
+
+        let rest = match res {
+            Ok((rest, statement)) => {
+                statements.push((offset, statement));
+                rest
+            }
+            Err(e) => {
+                let mut e = nom_error(input, e);
+                e.message = format!("invalid {}: {}", kind, e.message);
+                return Err(e);
+            }
+        };
+
+        i = skip_comments(rest);
+        match i.strip_prefix(';') {
+            Some(rest) => i = skip_comments(rest),
+            None => {
+                let offset = input.len() - i.len();
+                let expected = format!("';' after the {}", kind);
+                return Err(error::Parse::expected(input, offset, expected));
+            }
+        }
     }
+
+    Ok(statements)
 }
 
-fn predicate(i: &str) -> IResult<&str, builder::Predicate> {
-    let (i, _) = space0(i)?;
-    let (i, fact_name) = name(i)?;
+/// a keyword followed by whitespace
+fn keyword<'a>(k: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str, Error<'a>> {
+    move |i: &'a str| terminated(tag(k), multispace1)(i)
+}
 
-    let (i, _) = space0(i)?;
+/// skips whitespace and `//` comments
+fn skip_comments(mut i: &str) -> &str {
+    loop {
+        i = i.trim_start();
+        if !i.starts_with("//") {
+            return i;
+        }
+        i = match i.find('\n') {
+            Some(end) => &i[end..],
+            None => "",
+        };
+    }
+}
+
+fn predicate(i: &str) -> IResult<&str, builder::Predicate, Error<'_>> {
+    predicate_with(i, atom)
+}
+
+/// rule heads can contain aggregates
+fn head_predicate(i: &str) -> IResult<&str, builder::Predicate, Error<'_>> {
+    predicate_with(i, |i| alt((aggregate, atom))(i))
+}
+
+fn predicate_with<F>(i: &str, id: F) -> IResult<&str, builder::Predicate, Error<'_>>
+where
+    F: Fn(&str) -> IResult<&str, builder::Atom, Error<'_>>,
+{
+    let (i, _) = multispace0(i)?;
+    let (i, fact_name) = expect("a predicate", name)(i)?;
+
+    let (i, _) = multispace0(i)?;
     let (i, ids) = delimited(
         char('('),
-        separated_list1(preceded(space0, char(',')), atom),
-        preceded(space0, char(')')),
+        map(opt(comma_list1(id)), Option::unwrap_or_default),
+        preceded(multispace0, char(')')),
     )(i)?;
 
     Ok((
@@ -144,14 +419,137 @@ fn predicate(i: &str) -> IResult<&str, builder::Predicate> {
     ))
 }
 
-fn constraint(i: &str) -> IResult<&str, builder::Constraint> {
-    let (i, _) = space0(i)?;
+fn constraint(i: &str) -> IResult<&str, builder::Constraint, Error<'_>> {
+    let (i, _) = multispace0(i)?;
     let (i, id) = map_res(preceded(char('$'), name), |s| s.parse())(i)?;
     let (i, kind) = constraint_kind(i)?;
 
     Ok((i, builder::Constraint { id, kind }))
 }
 
+/// constraint or expression, after the `@` in a rule
+enum Condition {
+    Constraint(builder::Constraint),
+    Expression(builder::Expression),
+}
+
+fn condition(i: &str) -> IResult<&str, Condition, Error<'_>> {
+    alt((
+        // `$0 < 1 + $1` starts with a valid constraint, but is an expression
+        map(
+            terminated(constraint, not(preceded(multispace0, one_of("+-*/.")))),
+            Condition::Constraint,
+        ),
+        map(expression, Condition::Expression),
+    ))(i)
+}
+
+pub fn expression(i: &str) -> IResult<&str, builder::Expression, Error<'_>> {
+    let (i, _) = multispace0(i)?;
+    let (i, ops) = expect("an expression", alt((method_expression, comparison_expression)))(i)?;
+
+    Ok((i, builder::Expression { ops }))
+}
+
+fn comparison_expression(i: &str) -> IResult<&str, Vec<builder::Op>, Error<'_>> {
+    let (i, mut ops) = additive_term(i)?;
+    let (i, op) = delimited(
+        multispace0,
+        alt((
+            value(builder::Binary::LessOrEqual, tag("<=")),
+            value(builder::Binary::GreaterOrEqual, tag(">=")),
+            value(builder::Binary::Equal, tag("==")),
+            value(builder::Binary::NotEqual, tag("!=")),
+            value(builder::Binary::LessThan, tag("<")),
+            value(builder::Binary::GreaterThan, tag(">")),
+        )),
+        multispace0,
+    )(i)?;
+    let (i, right) = additive_term(i)?;
+
+    ops.extend(right);
+    ops.push(builder::Op::Binary(op));
+    Ok((i, ops))
+}
+
+fn method_expression(i: &str) -> IResult<&str, Vec<builder::Op>, Error<'_>> {
+    let (i, mut ops) = primary_term(i)?;
+    let (i, _) = char('.')(i)?;
+    let (i, op) = alt((
+        value(builder::Binary::StartsWith, tag("starts_with")),
+        value(builder::Binary::EndsWith, tag("ends_with")),
+        value(builder::Binary::Contains, tag("contains")),
+    ))(i)?;
+    let (i, right) = delimited(
+        char('('),
+        delimited(multispace0, additive_term, multispace0),
+        char(')'),
+    )(i)?;
+
+    ops.extend(right);
+    ops.push(builder::Op::Binary(op));
+    Ok((i, ops))
+}
+
+fn additive_term(i: &str) -> IResult<&str, Vec<builder::Op>, Error<'_>> {
+    let (i, first) = multiplicative_term(i)?;
+
+    fold_many0(
+        pair(
+            delimited(
+                multispace0,
+                alt((
+                    value(builder::Binary::Add, char('+')),
+                    value(builder::Binary::Sub, char('-')),
+                )),
+                multispace0,
+            ),
+            multiplicative_term,
+        ),
+        first,
+        |mut ops, (op, right)| {
+            ops.extend(right);
+            ops.push(builder::Op::Binary(op));
+            ops
+        },
+    )(i)
+}
+
+fn multiplicative_term(i: &str) -> IResult<&str, Vec<builder::Op>, Error<'_>> {
+    let (i, first) = primary_term(i)?;
+
+    fold_many0(
+        pair(
+            delimited(
+                multispace0,
+                alt((
+                    value(builder::Binary::Mul, char('*')),
+                    value(builder::Binary::Div, char('/')),
+                )),
+                multispace0,
+            ),
+            primary_term,
+        ),
+        first,
+        |mut ops, (op, right)| {
+            ops.extend(right);
+            ops.push(builder::Op::Binary(op));
+            ops
+        },
+    )(i)
+}
+
+fn primary_term(i: &str) -> IResult<&str, Vec<builder::Op>, Error<'_>> {
+    alt((
+        delimited(
+            char('('),
+            delimited(multispace0, additive_term, multispace0),
+            char(')'),
+        ),
+        map(atom, |a| vec![builder::Op::Value(a)]),
+    ))(i)
+}
+
 #[derive(Clone)]
 enum Operator {
     Lower,
@@ -162,23 +560,29 @@ enum Operator {
     In,
     NotIn,
     Matches,
+    Contains,
+    Intersects,
+    Subset,
 }
 
-fn operator(i: &str) -> IResult<&str, Operator> {
+fn operator(i: &str) -> IResult<&str, Operator, Error<'_>> {
     alt((
         value(Operator::LowerOrEqual, tag("<=")),
         value(Operator::LargerOrEqual, tag(">=")),
         value(Operator::Lower, tag("<")),
         value(Operator::Larger, tag(">")),
         value(Operator::Equal, tag("==")),
+        value(Operator::Intersects, tag("intersects")),
         value(Operator::In, tag("in")),
         value(Operator::NotIn, tag("not in")),
         value(Operator::Matches, tag("matches")),
+        value(Operator::Contains, tag("contains")),
+        value(Operator::Subset, tag("subset")),
     ))(i)
 }
 
-fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
-    let (i, op) = delimited(space0, operator, space0)(i)?;
+fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind, Error<'_>> {
+    let (i, op) = delimited(multispace0, expect("an operator", operator), multispace0)(i)?;
 
     match op {
         Operator::Lower => map(parse_integer, |i| {
@@ -222,10 +626,7 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
             char('['),
             alt((
                 map(
-                    separated_list1(
-                        preceded(space0, char(',')),
-                        preceded(space0, parse_integer),
-                    ),
+                    comma_list1(preceded(multispace0, parse_integer)),
                     |mut h| {
                         builder::ConstraintKind::Integer(datalog::IntConstraint::In(
                             h.drain(..).collect(),
@@ -233,10 +634,7 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
                     },
                 ),
                 map(
-                    separated_list1(
-                        preceded(space0, char(',')),
-                        preceded(space0, parse_string),
-                    ),
+                    comma_list1(preceded(multispace0, parse_string)),
                     |mut h| {
                         builder::ConstraintKind::String(datalog::StrConstraint::In(
                             h.drain(..).collect(),
@@ -244,10 +642,7 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
                     },
                 ),
                 map(
-                    separated_list1(
-                        preceded(space0, char(',')),
-                        preceded(space0, parse_symbol),
-                    ),
+                    comma_list1(preceded(multispace0, parse_symbol)),
                     |mut h| {
                         builder::ConstraintKind::Symbol(builder::SymbolConstraint::In(
                             h.drain(..).map(|s| s.to_string()).collect(),
@@ -255,10 +650,7 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
                     },
                 ),
                 map(
-                    separated_list1(
-                        preceded(space0, char(',')),
-                        preceded(space0, parse_bytes),
-                    ),
+                    comma_list1(preceded(multispace0, parse_bytes)),
                     |mut h| {
                         builder::ConstraintKind::Bytes(datalog::BytesConstraint::In(
                             h.drain(..).collect(),
@@ -266,16 +658,13 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
                     },
                 ),
             )),
-            preceded(space0, char(']')),
+            preceded(multispace0, char(']')),
         )(i),
         Operator::NotIn => delimited(
             char('['),
             alt((
                 map(
-                    separated_list1(
-                        preceded(space0, char(',')),
-                        preceded(space0, parse_integer),
-                    ),
+                    comma_list1(preceded(multispace0, parse_integer)),
                     |mut h| {
                         builder::ConstraintKind::Integer(datalog::IntConstraint::NotIn(
                             h.drain(..).collect(),
@@ -283,10 +672,7 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
                     },
                 ),
                 map(
-                    separated_list1(
-                        preceded(space0, char(',')),
-                        preceded(space0, parse_string),
-                    ),
+                    comma_list1(preceded(multispace0, parse_string)),
                     |mut h| {
                         builder::ConstraintKind::String(datalog::StrConstraint::NotIn(
                             h.drain(..).collect(),
@@ -294,10 +680,7 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
                     },
                 ),
                 map(
-                    separated_list1(
-                        preceded(space0, char(',')),
-                        preceded(space0, parse_symbol),
-                    ),
+                    comma_list1(preceded(multispace0, parse_symbol)),
                     |mut h| {
                         builder::ConstraintKind::Symbol(builder::SymbolConstraint::NotIn(
                             h.drain(..).map(|s| s.to_string()).collect(),
@@ -305,10 +688,7 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
                     },
                 ),
                 map(
-                    separated_list1(
-                        preceded(space0, char(',')),
-                        preceded(space0, parse_bytes),
-                    ),
+                    comma_list1(preceded(multispace0, parse_bytes)),
                     |mut h| {
                         builder::ConstraintKind::Bytes(datalog::BytesConstraint::NotIn(
                             h.drain(..).collect(),
@@ -316,9 +696,9 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
                     },
                 ),
             )),
-            preceded(space0, char(']')),
+            preceded(multispace0, char(']')),
         )(i),
-        Operator::Matches => alt((
+        Operator::Matches => expect("a string pattern or a regular expression", alt((
             map_opt(parse_string, |mut s| {
                 if !s.is_empty() {
                     if s.get(..1) == Some("*") {
@@ -335,25 +715,37 @@ fn constraint_kind(i: &str) -> IResult<&str, builder::ConstraintKind> {
                 }
                 None
             }),
-            map(
+            map_res(
                 regex, //delimited(char('/'), parse_string_internal, char('/')),
-                |s| builder::ConstraintKind::String(datalog::StrConstraint::Regex(s)),
+                |s| {
+                    datalog::Regex::new(&s)
+                        .map(|r| builder::ConstraintKind::String(datalog::StrConstraint::Regex(r)))
+                },
             ),
-        ))(i),
+        )))(i),
+        Operator::Contains => map(set_element, |a| {
+            builder::ConstraintKind::Set(builder::SetConstraint::Contains(a))
+        })(i),
+        Operator::Intersects => map(parse_set, |s| {
+            builder::ConstraintKind::Set(builder::SetConstraint::Intersects(s))
+        })(i),
+        Operator::Subset => map(parse_set, |s| {
+            builder::ConstraintKind::Set(builder::SetConstraint::Subset(s))
+        })(i),
     }
 }
 
-fn name(i: &str) -> IResult<&str, &str> {
+fn name(i: &str) -> IResult<&str, &str, Error<'_>> {
     let is_name_char = |c: char| is_alphanumeric(c as u8) || c == '_';
 
     take_while1(is_name_char)(i)
 }
 
-fn printable(i: &str) -> IResult<&str, &str> {
+fn printable(i: &str) -> IResult<&str, &str, Error<'_>> {
     take_while1(|c: char| c != '\\' && c != '"')(i)
 }
 
-fn parse_string_internal(i: &str) -> IResult<&str, String> {
+fn parse_string_internal(i: &str) -> IResult<&str, String, Error<'_>> {
     escaped_transform(
         printable,
         '\\',
@@ -365,32 +757,32 @@ fn parse_string_internal(i: &str) -> IResult<&str, String> {
     )(i)
 }
 
-fn parse_string(i: &str) -> IResult<&str, String> {
+fn parse_string(i: &str) -> IResult<&str, String, Error<'_>> {
     delimited(char('"'), parse_string_internal, char('"'))(i)
 }
 
-fn string(i: &str) -> IResult<&str, builder::Atom> {
+fn string(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
     parse_string(i).map(|(i, s)| (i, builder::Atom::Str(s)))
 }
 
-fn parse_symbol(i: &str) -> IResult<&str, &str> {
+fn parse_symbol(i: &str) -> IResult<&str, &str, Error<'_>> {
     preceded(char('#'), name)(i)
 }
 
-fn symbol(i: &str) -> IResult<&str, builder::Atom> {
+fn symbol(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
     parse_symbol(i).map(|(i, s)| (i, builder::s(s)))
 }
 
-fn parse_integer(i: &str) -> IResult<&str, i64> {
+fn parse_integer(i: &str) -> IResult<&str, i64, Error<'_>> {
     map_res(recognize(pair(opt(char('-')), digit1)), |s: &str| s.parse())(i)
 }
-fn integer(i: &str) -> IResult<&str, builder::Atom> {
+fn integer(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
     parse_integer(i).map(|(i, n)| (i, builder::int(n)))
 }
 
-fn parse_date(i: &str) -> IResult<&str, u64> {
+fn parse_date(i: &str) -> IResult<&str, u64, Error<'_>> {
     map_res(
-        map_res(take_while1(|c: char| c != ',' && c != ' '), |s| {
+        map_res(take_while1(|c: char| c != ',' && !c.is_whitespace() && c != ')' && c != ']' && c != ';'), |s| {
             let r = chrono::DateTime::parse_from_rfc3339(s);
             r
         }),
@@ -401,15 +793,15 @@ fn parse_date(i: &str) -> IResult<&str, u64> {
     )(i)
 }
 
-fn date(i: &str) -> IResult<&str, builder::Atom> {
+fn date(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
     parse_date(i).map(|(i, t)| (i, builder::Atom::Date(t)))
 }
 
-fn parse_bytes(i: &str) -> IResult<&str, Vec<u8>> {
+fn parse_bytes(i: &str) -> IResult<&str, Vec<u8>, Error<'_>> {
     preceded(
         tag("hex:"),
         map_res(
-            take_while1(|c| {
+            take_while(|c| {
                 let c = c as u8;
                 (b'0' <= c && c <= b'9')
                     || (b'a' <= c && c <= b'f')
@@ -420,22 +812,80 @@ fn parse_bytes(i: &str) -> IResult<&str, Vec<u8>> {
     )(i)
 }
 
-fn bytes(i: &str) -> IResult<&str, builder::Atom> {
+fn bytes(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
     parse_bytes(i).map(|(i, s)| (i, builder::Atom::Bytes(s)))
 }
 
-fn variable(i: &str) -> IResult<&str, builder::Atom> {
+fn variable(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
     map(
         preceded(char('$'), name),
         builder::variable,
     )(i)
 }
 
-fn atom(i: &str) -> IResult<&str, builder::Atom> {
-    preceded(space0, alt((symbol, string, date, variable, integer, bytes)))(i)
+/// template parameter, like `{path}`
+fn parameter(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
+    map(
+        delimited(char('{'), name, char('}')),
+        builder::parameter,
+    )(i)
+}
+
+fn boolean(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
+    alt((
+        value(builder::Atom::Bool(true), tag("true")),
+        value(builder::Atom::Bool(false), tag("false")),
+    ))(i)
 }
 
-fn regex(i: &str) -> IResult<&str, String> {
+/// a set contains values, or other sets, but no variables
+fn parse_set(i: &str) -> IResult<&str, BTreeSet<builder::Atom>, Error<'_>> {
+    delimited(
+        char('['),
+        map(
+            opt(comma_list1(set_element)),
+            |elements| elements.unwrap_or_default().into_iter().collect(),
+        ),
+        preceded(multispace0, char(']')),
+    )(i)
+}
+
+fn set(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
+    map(parse_set, builder::Atom::Set)(i)
+}
+
+fn set_element(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
+    expect(
+        "a value",
+        preceded(multispace0, alt((symbol, string, date, integer, bytes, boolean, set))),
+    )(i)
+}
+
+fn atom(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
+    expect(
+        "a term",
+        preceded(multispace0, alt((symbol, string, date, variable, parameter, integer, bytes, boolean, set))),
+    )(i)
+}
+
+fn aggregate(i: &str) -> IResult<&str, builder::Atom, Error<'_>> {
+    let (i, _) = multispace0(i)?;
+    let (i, kind) = alt((
+        value(builder::AggregateKind::Count, tag("count")),
+        value(builder::AggregateKind::Min, tag("min")),
+        value(builder::AggregateKind::Max, tag("max")),
+        value(builder::AggregateKind::Sum, tag("sum")),
+    ))(i)?;
+    let (i, v) = delimited(
+        preceded(multispace0, char('(')),
+        preceded(multispace0, preceded(char('$'), name)),
+        preceded(multispace0, char(')')),
+    )(i)?;
+
+    Ok((i, builder::Atom::Aggregate(kind, v.to_string())))
+}
+
+fn regex(i: &str) -> IResult<&str, String, Error<'_>> {
     delimited(
         char('/'),
         escaped_transform(
@@ -490,6 +940,10 @@ mod tests {
             super::date("2019-12-02T13:49:53Z"),
             Ok(("", builder::Atom::Date(1575294593)))
         );
+        assert_eq!(
+            super::date("2019-12-02T13:49:53Z)"),
+            Ok((")", builder::Atom::Date(1575294593)))
+        );
     }
 
     #[test]
@@ -655,12 +1109,14 @@ mod tests {
                 builder::Constraint {
                     id: "0".to_string(),
                     kind: builder::ConstraintKind::String(datalog::StrConstraint::Regex(
-                        "abc[0-9]+".to_string()
+                        datalog::Regex::new("abc[0-9]+").unwrap()
                     )),
                 }
             ))
         );
 
+        assert!(super::constraint("$0 matches /abc[0-9+/").is_err());
+
         let h = ["abc".to_string(), "def".to_string()]
             .iter()
             .cloned()
@@ -778,4 +1234,356 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn expression() {
+        use builder::{Binary, Op};
+
+        assert_eq!(
+            super::expression("$a < $b"),
+            Ok((
+                "",
+                builder::Expression {
+                    ops: vec![
+                        Op::Value(builder::variable("a")),
+                        Op::Value(builder::variable("b")),
+                        Op::Binary(Binary::LessThan),
+                    ]
+                }
+            ))
+        );
+
+        assert_eq!(
+            super::expression("$x + 1 == $y"),
+            Ok((
+                "",
+                builder::Expression {
+                    ops: vec![
+                        Op::Value(builder::variable("x")),
+                        Op::Value(builder::int(1)),
+                        Op::Binary(Binary::Add),
+                        Op::Value(builder::variable("y")),
+                        Op::Binary(Binary::Equal),
+                    ]
+                }
+            ))
+        );
+
+        assert_eq!(
+            super::expression("$path.starts_with($prefix)"),
+            Ok((
+                "",
+                builder::Expression {
+                    ops: vec![
+                        Op::Value(builder::variable("path")),
+                        Op::Value(builder::variable("prefix")),
+                        Op::Binary(Binary::StartsWith),
+                    ]
+                }
+            ))
+        );
+
+        assert_eq!(
+            super::expression("$a - ($b - 2) * 3 != $c"),
+            Ok((
+                "",
+                builder::Expression {
+                    ops: vec![
+                        Op::Value(builder::variable("a")),
+                        Op::Value(builder::variable("b")),
+                        Op::Value(builder::int(2)),
+                        Op::Binary(Binary::Sub),
+                        Op::Value(builder::int(3)),
+                        Op::Binary(Binary::Mul),
+                        Op::Binary(Binary::Sub),
+                        Op::Value(builder::variable("c")),
+                        Op::Binary(Binary::NotEqual),
+                    ]
+                }
+            ))
+        );
+
+        let (_, e) = super::expression("$a - ($b - 2) * 3 != $c").unwrap();
+        assert_eq!(e.to_string(), "$a - ($b - 2) * 3 != $c");
+    }
+
+    #[test]
+    fn negated_rule() {
+        let (rest, rule) = super::rule("*allowed($u) <- user($u), !banned($u), ! revoked( $u )").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            rule,
+            builder::Rule {
+                head: builder::pred("allowed", &[builder::variable("u")]),
+                body: vec![builder::pred("user", &[builder::variable("u")])],
+                constraints: vec![],
+                expressions: vec![],
+                negated: vec![
+                    builder::pred("banned", &[builder::variable("u")]),
+                    builder::pred("revoked", &[builder::variable("u")]),
+                ],
+            }
+        );
+        assert_eq!(rule.to_string(), "*allowed($u) <- user($u), !banned($u), !revoked($u)");
+    }
+
+    #[test]
+    fn set_and_bool() {
+        use std::collections::BTreeSet;
+
+        let roles: BTreeSet<builder::Atom> = [builder::s("admin"), builder::s("owner")]
+            .iter()
+            .cloned()
+            .collect();
+        let nested: BTreeSet<builder::Atom> = [
+            builder::int(1),
+            builder::set(BTreeSet::new()),
+            builder::set([builder::string("a")].iter().cloned().collect()),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+
+        assert_eq!(
+            super::fact("user(\"alice\", [#admin, #owner], true, [1, [], [\"a\"]])"),
+            Ok((
+                "",
+                builder::fact(
+                    "user",
+                    &[
+                        builder::string("alice"),
+                        builder::set(roles.clone()),
+                        builder::boolean(true),
+                        builder::set(nested),
+                    ]
+                )
+            ))
+        );
+
+        let (rest, rule) = super::rule(
+            "*admin($u) <- user($u, $roles, $active, $n) @ $roles contains #admin, $roles intersects [#admin, #owner], $roles subset [#admin, #owner, #guest], $active == true"
+        ).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            rule.constraints,
+            vec![
+                builder::Constraint {
+                    id: "roles".to_string(),
+                    kind: builder::ConstraintKind::Set(builder::SetConstraint::Contains(
+                        builder::s("admin")
+                    )),
+                },
+                builder::Constraint {
+                    id: "roles".to_string(),
+                    kind: builder::ConstraintKind::Set(builder::SetConstraint::Intersects(
+                        roles.clone()
+                    )),
+                },
+                builder::Constraint {
+                    id: "roles".to_string(),
+                    kind: builder::ConstraintKind::Set(builder::SetConstraint::Subset(
+                        [builder::s("admin"), builder::s("owner"), builder::s("guest")]
+                            .iter()
+                            .cloned()
+                            .collect()
+                    )),
+                },
+            ]
+        );
+        assert_eq!(rule.expressions.len(), 1);
+        assert_eq!(
+            rule.to_string(),
+            "*admin($u) <- user($u, $roles, $active, $n) @ $roles contains #admin, $roles intersects [#admin, #owner], $roles subset [#admin, #guest, #owner], $active == true"
+        );
+
+        // sets cannot contain variables
+        assert!(super::fact("user([$x])").is_err());
+    }
+
+    #[test]
+    fn aggregate_rule() {
+        let (rest, rule) = super::rule("*usage($u, count($f), sum( $s )) <- file($u, $f, $s)").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            rule,
+            builder::rule(
+                "usage",
+                &[builder::variable("u"), builder::count("f"), builder::sum("s")],
+                &[builder::pred(
+                    "file",
+                    &[builder::variable("u"), builder::variable("f"), builder::variable("s")]
+                )],
+            )
+        );
+        assert_eq!(rule.to_string(), "*usage($u, count($f), sum($s)) <- file($u, $f, $s)");
+
+        // aggregates are only accepted in the head
+        assert!(super::rule("*usage($u) <- file($u, count($f))").is_err());
+    }
+
+    #[test]
+    fn policy() {
+        let (rest, policy) = super::policy(
+            "allow if *allowed($f) <- resource(#ambient, $f), owner(#authority, $f) || *allowed(#admin) <- right(#authority, #admin)"
+        ).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(policy.kind, builder::PolicyKind::Allow);
+        assert_eq!(policy.queries.len(), 2);
+        assert_eq!(
+            policy.to_string(),
+            "allow if *allowed($f) <- resource(#ambient, $f), owner(#authority, $f) || *allowed(#admin) <- right(#authority, #admin)"
+        );
+
+        let (rest, policy) = super::policy("deny if *denied($u) <- user($u), banned($u)").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(policy.kind, builder::PolicyKind::Deny);
+
+        assert!(super::policy("allowif *allowed(#admin) <- right(#authority, #admin)").is_err());
+        assert!(super::policy("reject if *denied(#admin) <- right(#authority, #admin)").is_err());
+    }
+
+    #[test]
+    fn parameters() {
+        let rule = super::rule("*check($f) <- resource(#ambient, {path}) @ $f == {path}").unwrap().1;
+        assert_eq!(rule.head.ids, vec![builder::variable("f")]);
+        assert_eq!(rule.body[0].ids, vec![builder::symbol("ambient"), builder::parameter("path")]);
+        assert_eq!(rule.to_string(), "*check($f) <- resource(#ambient, {path}) @ $f == {path}");
+
+        assert!(super::rule("*check($f) <- resource(#ambient, { path })").is_err());
+        assert!(super::rule("*check($f) <- resource(#ambient, {})").is_err());
+    }
+
+    #[test]
+    fn source() {
+        use super::Statement;
+
+        let source = r#"
+            // rights of the user
+            right(#authority, "file1", #read);
+            right(#authority, "file2", #write); // trailing comment
+
+            *can_read($f) <- right(#authority, $f, #read),
+                             resource(#ambient, $f);
+            check if *check($f) <- can_read($f)
+                  || *check($f) <- owner(#authority, $f);
+            allow if *allowed($f) <- resource(#ambient, $f);
+            deny if *denied(#all) <- operation(#ambient, #delete);
+        "#;
+
+        let statements = super::parse_source(source).unwrap();
+        assert_eq!(statements.len(), 6);
+        assert_eq!(
+            statements[0].1,
+            Statement::Fact(builder::fact(
+                "right",
+                &[builder::s("authority"), builder::string("file1"), builder::s("read")]
+            ))
+        );
+        assert_eq!(&source[statements[0].0..statements[0].0 + 6], "right(");
+        match &statements[2].1 {
+            Statement::Rule(rule) => assert_eq!(
+                rule.to_string(),
+                "*can_read($f) <- right(#authority, $f, #read), resource(#ambient, $f)"
+            ),
+            s => panic!("expected a rule, got {:?}", s),
+        }
+        match &statements[3].1 {
+            Statement::Caveat(caveat) => assert_eq!(caveat.queries.len(), 2),
+            s => panic!("expected a caveat, got {:?}", s),
+        }
+        match &statements[5].1 {
+            Statement::Policy(policy) => assert_eq!(policy.kind, builder::PolicyKind::Deny),
+            s => panic!("expected a policy, got {:?}", s),
+        }
+
+        assert_eq!(super::parse_source(" // only a comment"), Ok(vec![]));
+
+        let e = super::parse_source("right(#authority, \"file1\", #read);\nright(#authority, \"file2\" #write);")
+            .unwrap_err();
+        assert_eq!((e.line, e.column, e.offset), (2, 27, 61));
+        assert_eq!(e.message, "invalid fact: expected ')'");
+
+        let e = super::parse_source("right(#authority, \"file1\", #read);\n  *can_read($f) <- right(#authority, $f, #read)")
+            .unwrap_err();
+        assert_eq!((e.line, e.column), (2, 48));
+        assert_eq!(e.expected, Some(String::from("';' after the rule")));
+        assert_eq!(e.to_string(), "expected ';' after the rule at line 2, column 48");
+    }
+
+    #[test]
+    fn parse_errors() {
+        use crate::error;
+        use std::convert::TryFrom;
+
+        let parse_error = |r: &str| match builder::Rule::try_from(r) {
+            Err(error::Token::ParseError(e)) => (e.offset, e.message),
+            res => panic!("unexpected result: {:?}", res),
+        };
+
+        assert_eq!(
+            parse_error("*right(#authority, $f) - resource(#ambient, $f)"),
+            (23, String::from("expected '<-'"))
+        );
+        assert_eq!(
+            parse_error("*right(#authority, $f) <- resource(#ambient, ), owner($f)"),
+            (45, String::from("expected a term"))
+        );
+        assert_eq!(
+            parse_error("*right(#authority, $f) <- resource(#ambient, $f),"),
+            (49, String::from("expected a predicate"))
+        );
+        assert_eq!(
+            parse_error("*right(#authority, $f) <- resource(#ambient, \"file1)"),
+            (52, String::from("expected '\"'"))
+        );
+        assert_eq!(
+            parse_error("*right(#authority, $f) <- resource(#ambient, $f) @ $f in [1, \"a\"]"),
+            (61, String::from("expected a number"))
+        );
+        assert_eq!(
+            parse_error("*right(#authority, $f) <- resource(#ambient, $f) @ $f matches 12"),
+            (62, String::from("expected a string pattern or a regular expression"))
+        );
+        // the whole input must be parsed
+        assert_eq!(
+            parse_error("*right(#authority, $f) <- resource(#ambient, $f))"),
+            (48, String::from("expected end of input"))
+        );
+
+        let e = match builder::Fact::try_from("right(#authority,\n  [1, $x])") {
+            Err(error::Token::ParseError(e)) => e,
+            res => panic!("unexpected result: {:?}", res),
+        };
+        assert_eq!(e.input, "right(#authority,\n  [1, $x])");
+        assert_eq!((e.line, e.column), (2, 7));
+        assert_eq!(e.expected, Some(String::from("a value")));
+        assert_eq!(e.to_string(), "expected a value at line 2, column 7");
+    }
+
+    #[test]
+    fn rule_with_expressions() {
+        let (rest, rule) = super::rule(
+            "*valid($0) <- time(#ambient, $now), expires($0, $expires), quota($0, $q), requested($r) @ $q < 10, $now <= $expires + 3600, $r < $q"
+        ).unwrap();
+        assert_eq!(rest, "");
+
+        assert_eq!(
+            rule.constraints,
+            vec![builder::Constraint {
+                id: "q".to_string(),
+                kind: builder::ConstraintKind::Integer(builder::IntConstraint::Lower(10)),
+            }]
+        );
+        assert_eq!(rule.expressions.len(), 2);
+        assert_eq!(
+            rule.to_string(),
+            "*valid($0) <- time(#ambient, $now), expires($0, $expires), quota($0, $q), requested($r) @ $q < 10, $now <= $expires + 3600, $r < $q"
+        );
+
+        // a constraint followed by an operator is parsed as an expression
+        let (rest, rule) = super::rule("*valid($0) <- quota($0, $q), used($u) @ $u < 10 - $q").unwrap();
+        assert_eq!(rest, "");
+        assert!(rule.constraints.is_empty());
+        assert_eq!(rule.expressions[0].to_string(), "$u < 10 - $q");
+    }
 }
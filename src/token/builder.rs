@@ -4,11 +4,12 @@ use crate::datalog::{
     self, SymbolTable, ID,
 };
 use crate::error;
+use crate::parser::{self, Statement};
 use rand_core::{CryptoRng, RngCore};
-use std::{fmt, convert::{TryInto, TryFrom}, time::{SystemTime, Duration, UNIX_EPOCH}, collections::HashSet};
+use std::{fmt, convert::{TryInto, TryFrom}, time::{SystemTime, Duration, UNIX_EPOCH}, collections::{BTreeSet, HashSet}};
 
 // reexport those because the builder uses the same definitions
-pub use crate::datalog::{IntConstraint, StrConstraint, BytesConstraint};
+pub use crate::datalog::{AggregateKind, Binary, IntConstraint, StrConstraint, BytesConstraint, Regex};
 
 #[derive(Clone, Debug)]
 pub struct BlockBuilder {
@@ -34,27 +35,80 @@ impl BlockBuilder {
         }
     }
 
-    pub fn add_fact<F: TryInto<Fact>>(&mut self, fact: F) -> Result<(), error::Token> {
-        let fact = fact.try_into().map_err(|_| error::Token::ParseError)?;
+    pub fn add_fact<F: TryInto<Fact>>(&mut self, fact: F) -> Result<(), error::Token>
+    where
+        F::Error: Into<error::Token>,
+    {
+        let fact = fact.try_into().map_err(Into::into)?;
+        check_parameters(fact.parameters())?;
         let f = fact.convert(&mut self.symbols);
         self.facts.push(f);
         Ok(())
     }
 
-    pub fn add_rule<R: TryInto<Rule>>(&mut self, rule: R) -> Result<(), error::Token> {
-        let rule = rule.try_into().map_err(|_| error::Token::ParseError)?;
-        let r = rule.convert(&mut self.symbols);
+    pub fn add_rule<R: TryInto<Rule>>(&mut self, rule: R) -> Result<(), error::Token>
+    where
+        R::Error: Into<error::Token>,
+    {
+        let rule = rule.try_into().map_err(Into::into)?;
+        check_parameters(rule.parameters())?;
+        // an unsafe rule must not add its symbols to the block
+        let mut symbols = self.symbols.clone();
+        let r = rule.convert(&mut symbols);
+        check_safety(&r, &symbols)?;
+        check_stratification(&self.rules, &r, &symbols)?;
+
+        self.symbols = symbols;
         self.rules.push(r);
         Ok(())
     }
 
-    pub fn add_caveat<C: TryInto<Caveat>>(&mut self, caveat: C) -> Result<(), error::Token> {
-        let caveat = caveat.try_into().map_err(|_| error::Token::ParseError)?;
-        let c = caveat.convert(&mut self.symbols);
+    pub fn add_caveat<C: TryInto<Caveat>>(&mut self, caveat: C) -> Result<(), error::Token>
+    where
+        C::Error: Into<error::Token>,
+    {
+        let caveat = caveat.try_into().map_err(Into::into)?;
+        check_parameters(caveat.parameters())?;
+        let mut symbols = self.symbols.clone();
+        let c = caveat.convert(&mut symbols);
+        for query in c.queries.iter() {
+            check_safety(query, &symbols)?;
+        }
+
+        self.symbols = symbols;
         self.caveats.push(c);
         Ok(())
     }
 
+    /// creates a block from a Datalog source text, like the
+    /// ones generated by `Biscuit::block_source`
+    pub fn from_source(
+        index: u32,
+        base_symbols: SymbolTable,
+        source: &str,
+    ) -> Result<BlockBuilder, error::Token> {
+        let mut builder = BlockBuilder::new(index, base_symbols);
+        builder.add_source(source)?;
+        Ok(builder)
+    }
+
+    /// adds the facts, rules, caveats and context of a Datalog source text
+    ///
+    /// nothing is added if the source contains an error
+    pub fn add_source(&mut self, source: &str) -> Result<(), error::Token> {
+        for statement in parse_checked_source(source, &self.symbols, false)? {
+            match statement {
+                Statement::Fact(fact) => self.add_fact(fact)?,
+                Statement::Rule(rule) => self.add_rule(rule)?,
+                Statement::Caveat(caveat) => self.add_caveat(caveat)?,
+                Statement::Context(context) => self.set_context(context),
+                // rejected by parse_checked_source
+                Statement::Policy(_) => {}
+            }
+        }
+        Ok(())
+    }
+
     pub fn set_context(&mut self, context: String) {
         self.context = Some(context);
     }
@@ -163,6 +217,7 @@ pub struct BiscuitBuilder<'a> {
     pub rules: Vec<datalog::Rule>,
     pub caveats: Vec<datalog::Caveat>,
     pub context: Option<String>,
+    pub root_key_id: Option<u32>,
 }
 
 impl<'a> BiscuitBuilder<'a> {
@@ -178,11 +233,16 @@ impl<'a> BiscuitBuilder<'a> {
             rules: vec![],
             caveats: vec![],
             context: None,
+            root_key_id: None,
         }
     }
 
-    pub fn add_authority_fact<F: TryInto<Fact>>(&mut self, fact: F) -> Result<(), error::Token> {
-        let mut fact = fact.try_into().map_err(|_| error::Token::ParseError)?;
+    pub fn add_authority_fact<F: TryInto<Fact>>(&mut self, fact: F) -> Result<(), error::Token>
+    where
+        F::Error: Into<error::Token>,
+    {
+        let mut fact = fact.try_into().map_err(Into::into)?;
+        check_parameters(fact.parameters())?;
         let authority_symbol = Atom::Symbol("authority".to_string());
         if fact.0.ids.is_empty() || fact.0.ids[0] != authority_symbol {
             fact.0.ids.insert(0, authority_symbol);
@@ -193,22 +253,59 @@ impl<'a> BiscuitBuilder<'a> {
         Ok(())
     }
 
-    pub fn add_authority_rule<Ru: TryInto<Rule>>(&mut self, rule: Ru) -> Result<(), error::Token> {
-        let mut rule = rule.try_into().map_err(|_| error::Token::ParseError)?;
+    pub fn add_authority_rule<Ru: TryInto<Rule>>(&mut self, rule: Ru) -> Result<(), error::Token>
+    where
+        Ru::Error: Into<error::Token>,
+    {
+        let mut rule = rule.try_into().map_err(Into::into)?;
+        check_parameters(rule.parameters())?;
         let authority_symbol = Atom::Symbol("authority".to_string());
-        if rule.0.ids.is_empty() || rule.0.ids[0] != authority_symbol {
-            rule.0.ids.insert(0, authority_symbol);
+        if rule.head.ids.is_empty() || rule.head.ids[0] != authority_symbol {
+            rule.head.ids.insert(0, authority_symbol);
         }
 
-        let r = rule.convert(&mut self.symbols);
+        let mut symbols = self.symbols.clone();
+        let r = rule.convert(&mut symbols);
+        check_safety(&r, &symbols)?;
+        check_stratification(&self.rules, &r, &symbols)?;
+
+        self.symbols = symbols;
         self.rules.push(r);
         Ok(())
     }
 
-    pub fn add_authority_caveat<Ru: TryInto<Rule>>(&mut self, rule: Ru) -> Result<(), error::Token> {
-        let caveat = rule.try_into().map_err(|_| error::Token::ParseError)?;
-        let r = caveat.convert(&mut self.symbols);
-        self.caveats.push(datalog::Caveat { queries: vec![r]});
+    pub fn add_authority_caveat<C: TryInto<Caveat>>(&mut self, caveat: C) -> Result<(), error::Token>
+    where
+        C::Error: Into<error::Token>,
+    {
+        let caveat = caveat.try_into().map_err(Into::into)?;
+        check_parameters(caveat.parameters())?;
+        let mut symbols = self.symbols.clone();
+        let c = caveat.convert(&mut symbols);
+        for query in c.queries.iter() {
+            check_safety(query, &symbols)?;
+        }
+
+        self.symbols = symbols;
+        self.caveats.push(c);
+        Ok(())
+    }
+
+    /// adds the facts, rules, caveats and context of a Datalog
+    /// source text to the authority block
+    ///
+    /// nothing is added if the source contains an error
+    pub fn add_source(&mut self, source: &str) -> Result<(), error::Token> {
+        for statement in parse_checked_source(source, &self.symbols, false)? {
+            match statement {
+                Statement::Fact(fact) => self.add_authority_fact(fact)?,
+                Statement::Rule(rule) => self.add_authority_rule(rule)?,
+                Statement::Caveat(caveat) => self.add_authority_caveat(caveat)?,
+                Statement::Context(context) => self.set_context(context),
+                // rejected by parse_checked_source
+                Statement::Policy(_) => {}
+            }
+        }
         Ok(())
     }
 
@@ -223,8 +320,14 @@ impl<'a> BiscuitBuilder<'a> {
         self.context = Some(context);
     }
 
+    /// sets the identifier of the root key in the token, so verifiers
+    /// holding multiple root keys know which one to use
+    pub fn set_root_key_id(&mut self, id: u32) {
+        self.root_key_id = Some(id);
+    }
+
     pub fn build<R: RngCore + CryptoRng>(mut self, rng: &'a mut R) -> Result<Biscuit, error::Token> {
-        let new_syms = SymbolTable { symbols: self.symbols.symbols.split_off(self.symbols_start) };
+        let new_syms = SymbolTable::from(self.symbols.symbols.split_off(self.symbols_start));
 
         let authority_block = Block {
             index: 0,
@@ -235,11 +338,15 @@ impl<'a> BiscuitBuilder<'a> {
             context: self.context,
         };
 
-        Biscuit::new(rng, self.root, self.symbols, authority_block)
+        let mut biscuit = Biscuit::new(rng, self.root, self.symbols, authority_block)?;
+        if let Some(container) = biscuit.container.as_mut() {
+            container.root_key_id = self.root_key_id;
+        }
+        Ok(biscuit)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Hash, Eq)]
+#[derive(Debug, Clone, PartialEq, Hash, Eq, PartialOrd, Ord)]
 pub enum Atom {
     Symbol(String),
     Variable(String),
@@ -247,6 +354,13 @@ pub enum Atom {
     Str(String),
     Date(u64),
     Bytes(Vec<u8>),
+    Bool(bool),
+    Set(BTreeSet<Atom>),
+    /// aggregate over a variable, only valid in a rule head
+    Aggregate(AggregateKind, String),
+    /// template parameter, like `{path}`, that must be bound
+    /// to a value with `set` before the rule is used
+    Parameter(String),
 }
 
 impl Atom {
@@ -258,6 +372,12 @@ impl Atom {
             Atom::Str(s) => ID::Str(s.clone()),
             Atom::Date(d) => ID::Date(*d),
             Atom::Bytes(s) => ID::Bytes(s.clone()),
+            Atom::Bool(b) => ID::Bool(*b),
+            Atom::Set(s) => ID::Set(s.iter().map(|a| a.convert(symbols)).collect()),
+            // the aggregate itself is stored in the rule
+            Atom::Aggregate(_, s) => ID::Variable(symbols.insert(s) as u32),
+            // unbound parameters are rejected before conversion
+            Atom::Parameter(s) => ID::Variable(symbols.insert(s) as u32),
         }
     }
 
@@ -269,6 +389,8 @@ impl Atom {
         ID::Str(s) => Atom::Str(s.clone()),
         ID::Date(d) => Atom::Date(*d),
         ID::Bytes(s) => Atom::Bytes(s.clone()),
+        ID::Bool(b) => Atom::Bool(*b),
+        ID::Set(s) => Atom::Set(s.iter().map(|id| Atom::convert_from(id, symbols)).collect()),
       }
     }
 }
@@ -282,10 +404,62 @@ impl From<&Atom> for Atom {
             Atom::Str(ref s) => Atom::Str(s.clone()),
             Atom::Date(ref d) => Atom::Date(*d),
             Atom::Bytes(ref s) => Atom::Bytes(s.clone()),
+            Atom::Bool(ref b) => Atom::Bool(*b),
+            Atom::Set(ref s) => Atom::Set(s.clone()),
+            Atom::Aggregate(kind, ref v) => Atom::Aggregate(*kind, v.clone()),
+            Atom::Parameter(ref p) => Atom::Parameter(p.clone()),
         }
     }
 }
 
+impl From<i64> for Atom {
+    fn from(i: i64) -> Self {
+        Atom::Integer(i)
+    }
+}
+
+impl From<&str> for Atom {
+    fn from(s: &str) -> Self {
+        Atom::Str(s.to_string())
+    }
+}
+
+impl From<String> for Atom {
+    fn from(s: String) -> Self {
+        Atom::Str(s)
+    }
+}
+
+impl From<bool> for Atom {
+    fn from(b: bool) -> Self {
+        Atom::Bool(b)
+    }
+}
+
+impl From<&[u8]> for Atom {
+    fn from(b: &[u8]) -> Self {
+        Atom::Bytes(b.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Atom {
+    fn from(b: Vec<u8>) -> Self {
+        Atom::Bytes(b)
+    }
+}
+
+impl From<SystemTime> for Atom {
+    fn from(t: SystemTime) -> Self {
+        date(&t)
+    }
+}
+
+impl From<BTreeSet<Atom>> for Atom {
+    fn from(s: BTreeSet<Atom>) -> Self {
+        Atom::Set(s)
+    }
+}
+
 impl AsRef<Atom> for Atom {
     fn as_ref(&self) -> &Atom {
         self
@@ -297,18 +471,45 @@ impl fmt::Display for Atom {
         match self {
             Atom::Variable(i) => write!(f, "${}", i),
             Atom::Integer(i) => write!(f, "{}", i),
-            Atom::Str(s) => write!(f, "\"{}\"", s),
+            Atom::Str(s) => write!(f, "{}", print_string(s)),
             Atom::Symbol(s) => write!(f, "#{}", s),
-            Atom::Date(d) => {
-                let t = UNIX_EPOCH + Duration::from_secs(*d);
-                write!(f, "{:?}", t)
-            }
+            Atom::Date(d) => write!(f, "{}", print_date(&(UNIX_EPOCH + Duration::from_secs(*d)))),
             Atom::Bytes(s) => write!(f, "hex:{}", hex::encode(s)),
+            Atom::Bool(b) => write!(f, "{}", b),
+            Atom::Set(s) => write!(f, "{}", print_set(s)),
+            Atom::Aggregate(kind, v) => write!(f, "{}(${})", kind.name(), v),
+            Atom::Parameter(p) => write!(f, "{{{}}}", p),
         }
 
     }
 }
 
+fn print_set(s: &BTreeSet<Atom>) -> String {
+    let atoms = s.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    format!("[{}]", atoms.join(", "))
+}
+
+/// quotes a string, escaping the characters that the parser unescapes
+fn print_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+fn print_date(date: &SystemTime) -> String {
+    let date: chrono::DateTime<chrono::Utc> = (*date).into();
+    date.to_rfc3339()
+}
+
+/// prints the values of an `in` constraint in a stable order
+fn print_list<T, I: Iterator<Item = T>, F: Fn(T) -> String>(values: I, print: F) -> String {
+    let mut values = values.map(print).collect::<Vec<_>>();
+    values.sort();
+    format!("[{}]", values.join(", "))
+}
+
 #[derive(Debug, Clone, PartialEq, Hash, Eq)]
 pub struct Predicate {
     pub name: String,
@@ -376,6 +577,16 @@ impl Fact {
 }
 
 impl Fact {
+    /// binds the parameter `{name}` to a value
+    pub fn set<T: Into<Atom>>(&mut self, name: &str, value: T) -> Result<(), error::Token> {
+        set_parameter(self.0.ids.iter_mut(), name, &value.into())
+    }
+
+    /// names of the parameters that are not bound yet
+    pub fn parameters(&self) -> BTreeSet<String> {
+        parameters(self.0.ids.iter())
+    }
+
     pub fn convert(&self, symbols: &mut SymbolTable) -> datalog::Fact {
         datalog::Fact {
             predicate: self.0.convert(symbols),
@@ -425,42 +636,73 @@ impl AsRef<Constraint> for Constraint {
 
 impl fmt::Display for Constraint {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = |b: &Vec<u8>| format!("hex:{}", hex::encode(b));
+
         match &self.kind {
             ConstraintKind::Integer(IntConstraint::Lower(i)) => write!(f, "${} < {}", self.id, i),
             ConstraintKind::Integer(IntConstraint::Larger(i)) => write!(f, "${} > {}", self.id, i),
             ConstraintKind::Integer(IntConstraint::LowerOrEqual(i)) => write!(f, "${} <= {}", self.id, i),
             ConstraintKind::Integer(IntConstraint::LargerOrEqual(i)) => write!(f, "${} >= {}", self.id, i),
             ConstraintKind::Integer(IntConstraint::Equal(i)) => write!(f, "${} == {}", self.id, i),
-            ConstraintKind::Integer(IntConstraint::In(i)) => write!(f, "${} in {:?}", self.id, i),
-            ConstraintKind::Integer(IntConstraint::NotIn(i)) => write!(f, "${} not in {:?}", self.id, i),
-            ConstraintKind::String(StrConstraint::Prefix(i)) => write!(f, "${} matches {}*", self.id, i),
-            ConstraintKind::String(StrConstraint::Suffix(i)) => write!(f, "${} matches *{}", self.id, i),
-            ConstraintKind::String(StrConstraint::Equal(i)) => write!(f, "${} == {}", self.id, i),
-            ConstraintKind::String(StrConstraint::Regex(i)) => write!(f, "${} matches /{}/", self.id, i),
-            ConstraintKind::String(StrConstraint::In(i)) => write!(f, "${} in {:?}", self.id, i),
-            ConstraintKind::String(StrConstraint::NotIn(i)) => write!(f, "${} not in {:?}", self.id, i),
+            ConstraintKind::Integer(IntConstraint::In(i)) => {
+                // sorted as numbers rather than strings
+                let mut i = i.iter().collect::<Vec<_>>();
+                i.sort();
+                let i = i.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+                write!(f, "${} in [{}]", self.id, i.join(", "))
+            },
+            ConstraintKind::Integer(IntConstraint::NotIn(i)) => {
+                let mut i = i.iter().collect::<Vec<_>>();
+                i.sort();
+                let i = i.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+                write!(f, "${} not in [{}]", self.id, i.join(", "))
+            },
+            ConstraintKind::String(StrConstraint::Prefix(i)) => {
+                write!(f, "${} matches {}", self.id, print_string(&format!("{}*", i)))
+            },
+            ConstraintKind::String(StrConstraint::Suffix(i)) => {
+                write!(f, "${} matches {}", self.id, print_string(&format!("*{}", i)))
+            },
+            ConstraintKind::String(StrConstraint::Equal(i)) => write!(f, "${} == {}", self.id, print_string(i)),
+            ConstraintKind::String(StrConstraint::Regex(i)) => {
+                let escaped = i
+                    .as_str()
+                    .replace('\\', "\\\\")
+                    .replace('/', "\\/")
+                    .replace('\n', "\\n");
+                write!(f, "${} matches /{}/", self.id, escaped)
+            },
+            ConstraintKind::String(StrConstraint::In(i)) => {
+                write!(f, "${} in {}", self.id, print_list(i.iter(), |s| print_string(s)))
+            },
+            ConstraintKind::String(StrConstraint::NotIn(i)) => {
+                write!(f, "${} not in {}", self.id, print_list(i.iter(), |s| print_string(s)))
+            },
             ConstraintKind::Date(DateConstraint::Before(date)) => {
-              //let date = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(*i as i64, 0), Utc);
-              let date: chrono::DateTime<chrono::Utc> = (*date).into();
-              write!(f, "${} <= {}", self.id, date.to_rfc3339())
+                write!(f, "${} <= {}", self.id, print_date(date))
             },
             ConstraintKind::Date(DateConstraint::After(date)) => {
-              //let date = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(*i as i64, 0), Utc);
-              let date: chrono::DateTime<chrono::Utc> = (*date).into();
-              write!(f, "${} >= {}", self.id, date.to_rfc3339())
+                write!(f, "${} >= {}", self.id, print_date(date))
+            },
+            ConstraintKind::Symbol(SymbolConstraint::In(i)) => {
+                write!(f, "${} in {}", self.id, print_list(i.iter(), |s| format!("#{}", s)))
             },
-            ConstraintKind::Symbol(SymbolConstraint::In(i)) => write!(f, "${} in {:?}", self.id, i),
             ConstraintKind::Symbol(SymbolConstraint::NotIn(i)) => {
-                write!(f, "${} not in {:?}", self.id, i)
+                write!(f, "${} not in {}", self.id, print_list(i.iter(), |s| format!("#{}", s)))
             },
-            ConstraintKind::Bytes(BytesConstraint::Equal(i)) => write!(f, "${} == {}", self.id, hex::encode(i)),
+            ConstraintKind::Bytes(BytesConstraint::Equal(i)) => write!(f, "${} == {}", self.id, bytes(i)),
             ConstraintKind::Bytes(BytesConstraint::In(i)) => {
-                write!(f, "${} in {:?}", self.id, i.iter()
-                       .map(|s| format!("hex:{}", hex::encode(s))).collect::<HashSet<_>>())
+                write!(f, "${} in {}", self.id, print_list(i.iter(), bytes))
             },
             ConstraintKind::Bytes(BytesConstraint::NotIn(i)) => {
-                write!(f, "${} not in {:?}", self.id, i.iter()
-                       .map(|s| format!("hex:{}", hex::encode(s))).collect::<HashSet<_>>())
+                write!(f, "${} not in {}", self.id, print_list(i.iter(), bytes))
+            },
+            ConstraintKind::Set(SetConstraint::Contains(a)) => write!(f, "${} contains {}", self.id, a),
+            ConstraintKind::Set(SetConstraint::Intersects(s)) => {
+                write!(f, "${} intersects {}", self.id, print_set(s))
+            },
+            ConstraintKind::Set(SetConstraint::Subset(s)) => {
+                write!(f, "${} subset {}", self.id, print_set(s))
             },
         }
     }
@@ -474,6 +716,7 @@ pub enum ConstraintKind {
     Date(DateConstraint),
     Symbol(SymbolConstraint),
     Bytes(datalog::BytesConstraint),
+    Set(SetConstraint),
 }
 
 impl ConstraintKind {
@@ -498,6 +741,17 @@ impl ConstraintKind {
           let hset = h.iter().map(|s| symbols.insert(&s)).collect();
           datalog::ConstraintKind::Symbol(datalog::SymbolConstraint::NotIn(hset))
         },
+        ConstraintKind::Set(SetConstraint::Contains(a)) => {
+          datalog::ConstraintKind::Set(datalog::SetConstraint::Contains(a.convert(symbols)))
+        },
+        ConstraintKind::Set(SetConstraint::Intersects(s)) => {
+          let set = s.iter().map(|a| a.convert(symbols)).collect();
+          datalog::ConstraintKind::Set(datalog::SetConstraint::Intersects(set))
+        },
+        ConstraintKind::Set(SetConstraint::Subset(s)) => {
+          let set = s.iter().map(|a| a.convert(symbols)).collect();
+          datalog::ConstraintKind::Set(datalog::SetConstraint::Subset(set))
+        },
       }
     }
 
@@ -522,6 +776,17 @@ impl ConstraintKind {
           ConstraintKind::Symbol(SymbolConstraint::NotIn(hset))
         },
         datalog::ConstraintKind::Bytes(s) => ConstraintKind::Bytes(s.clone()),
+        datalog::ConstraintKind::Set(datalog::SetConstraint::Contains(id)) => {
+          ConstraintKind::Set(SetConstraint::Contains(Atom::convert_from(id, symbols)))
+        },
+        datalog::ConstraintKind::Set(datalog::SetConstraint::Intersects(s)) => {
+          let set = s.iter().map(|id| Atom::convert_from(id, symbols)).collect();
+          ConstraintKind::Set(SetConstraint::Intersects(set))
+        },
+        datalog::ConstraintKind::Set(datalog::SetConstraint::Subset(s)) => {
+          let set = s.iter().map(|id| Atom::convert_from(id, symbols)).collect();
+          ConstraintKind::Set(SetConstraint::Subset(set))
+        },
       }
     }
 }
@@ -539,65 +804,201 @@ pub enum SymbolConstraint {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct Rule(
-    pub Predicate,
-    pub Vec<Predicate>,
-    pub Vec<Constraint>,
-);
+pub enum SetConstraint {
+    /// the set contains this value
+    Contains(Atom),
+    /// the set has at least one value in common with this one
+    Intersects(BTreeSet<Atom>),
+    /// all the values of the set are in this one
+    Subset(BTreeSet<Atom>),
+}
+
+/// expression between the variables of a rule, like `$x + 1 == $y`
+///
+/// the operations are stored in reverse polish notation: `[$x, 1, +, $y, ==]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    pub ops: Vec<Op>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Value(Atom),
+    Binary(Binary),
+}
+
+impl Expression {
+    pub fn convert(&self, symbols: &mut SymbolTable) -> datalog::Expression {
+        datalog::Expression {
+            ops: self.ops.iter().map(|op| op.convert(symbols)).collect(),
+        }
+    }
+
+    pub fn convert_from(e: &datalog::Expression, symbols: &SymbolTable) -> Self {
+        Expression {
+            ops: e.ops.iter().map(|op| Op::convert_from(op, symbols)).collect(),
+        }
+    }
+}
+
+impl AsRef<Expression> for Expression {
+    fn as_ref(&self) -> &Expression {
+        self
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let printed = datalog::print_ops(self.ops.iter().map(|op| match op {
+            Op::Value(a) => datalog::PrintOp::Value(a.to_string()),
+            Op::Binary(b) => datalog::PrintOp::Binary(*b),
+        }));
+        write!(f, "{}", printed)
+    }
+}
+
+impl Op {
+    pub fn convert(&self, symbols: &mut SymbolTable) -> datalog::Op {
+        match self {
+            Op::Value(a) => datalog::Op::Value(a.convert(symbols)),
+            Op::Binary(b) => datalog::Op::Binary(*b),
+        }
+    }
+
+    pub fn convert_from(op: &datalog::Op, symbols: &SymbolTable) -> Self {
+        match op {
+            datalog::Op::Value(id) => Op::Value(Atom::convert_from(id, symbols)),
+            datalog::Op::Binary(b) => Op::Binary(*b),
+        }
+    }
+}
+
+/// a rule, usually created with `rule`, `constrained_rule` or by
+/// parsing a string
+///
+/// the head can contain aggregates, like `count($f)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub head: Predicate,
+    pub body: Vec<Predicate>,
+    pub constraints: Vec<Constraint>,
+    pub expressions: Vec<Expression>,
+    /// predicates that must not match any fact
+    pub negated: Vec<Predicate>,
+}
 
 impl Rule {
+    /// binds the parameter `{name}` to a value
+    pub fn set<T: Into<Atom>>(&mut self, name: &str, value: T) -> Result<(), error::Token> {
+        set_parameter(self.atoms_mut(), name, &value.into())
+    }
+
+    /// names of the parameters that are not bound yet
+    pub fn parameters(&self) -> BTreeSet<String> {
+        parameters(self.atoms())
+    }
+
+    /// terms of the head, predicates and expressions
+    fn atoms(&self) -> impl Iterator<Item = &Atom> {
+        self.head.ids.iter()
+            .chain(self.body.iter().chain(self.negated.iter()).flat_map(|p| p.ids.iter()))
+            .chain(self.expressions.iter().flat_map(|e| e.ops.iter()).filter_map(|op| match op {
+                Op::Value(a) => Some(a),
+                Op::Binary(_) => None,
+            }))
+    }
+
+    fn atoms_mut(&mut self) -> impl Iterator<Item = &mut Atom> {
+        self.head.ids.iter_mut()
+            .chain(self.body.iter_mut().chain(self.negated.iter_mut()).flat_map(|p| p.ids.iter_mut()))
+            .chain(self.expressions.iter_mut().flat_map(|e| e.ops.iter_mut()).filter_map(|op| match op {
+                Op::Value(a) => Some(a),
+                Op::Binary(_) => None,
+            }))
+    }
+
     pub fn convert(&self, symbols: &mut SymbolTable) -> datalog::Rule {
-        let head = self.0.convert(symbols);
+        let head = self.head.convert(symbols);
         let mut body = vec![];
+        let mut aggregates = vec![];
+
+        for (position, id) in self.head.ids.iter().enumerate() {
+            if let Atom::Aggregate(kind, _) = id {
+                aggregates.push(datalog::Aggregate {
+                    position: position as u32,
+                    kind: *kind,
+                });
+            }
+        }
         let mut constraints = vec![];
+        let mut expressions = vec![];
+        let mut negated = vec![];
 
-        for p in self.1.iter() {
+        for p in self.body.iter() {
             body.push(p.convert(symbols));
         }
 
-        for c in self.2.iter() {
+        for c in self.constraints.iter() {
             constraints.push(c.convert(symbols));
         }
 
+        for e in self.expressions.iter() {
+            expressions.push(e.convert(symbols));
+        }
+
+        for p in self.negated.iter() {
+            negated.push(p.convert(symbols));
+        }
+
         datalog::Rule {
             head,
             body,
             constraints,
+            expressions,
+            negated,
+            aggregates,
         }
     }
 
     pub fn convert_from(r: &datalog::Rule, symbols: &SymbolTable) -> Self {
-        Rule(
-            Predicate::convert_from(&r.head, symbols),
-            r.body.iter().map(|p| Predicate::convert_from(p, symbols)).collect(),
-            r.constraints.iter().map(|c| Constraint::convert_from(c, symbols)).collect(),
-        )
+        let mut head = Predicate::convert_from(&r.head, symbols);
+        for a in r.aggregates.iter() {
+            if let Some(Atom::Variable(v)) = head.ids.get(a.position as usize).cloned() {
+                head.ids[a.position as usize] = Atom::Aggregate(a.kind, v);
+            }
+        }
+
+        Rule {
+            head,
+            body: r.body.iter().map(|p| Predicate::convert_from(p, symbols)).collect(),
+            constraints: r.constraints.iter().map(|c| Constraint::convert_from(c, symbols)).collect(),
+            expressions: r.expressions.iter().map(|e| Expression::convert_from(e, symbols)).collect(),
+            negated: r.negated.iter().map(|p| Predicate::convert_from(p, symbols)).collect(),
+        }
     }
 }
 
 impl fmt::Display for Rule {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "*{} <- ", self.0)?;
-
-        if self.1.len() > 0 {
-            write!(f, "{}", self.1[0])?;
-
-            if self.1.len() > 1 {
-                for i in 1..self.1.len() {
-                    write!(f, ", {}", self.1[i])?;
-                }
-            }
-        }
-
-        if self.2.len() > 0 {
-            write!(f, " @ {}", self.2[0])?;
-
-            if self.2.len() > 1 {
-                for i in 1..self.2.len() {
-                    write!(f, ", {}", self.2[i])?;
-                }
-            }
-
+        write!(f, "*{} <- ", self.head)?;
+
+        let body = self
+            .body
+            .iter()
+            .map(|p| p.to_string())
+            .chain(self.negated.iter().map(|p| format!("!{}", p)))
+            .collect::<Vec<_>>();
+        write!(f, "{}", body.join(", "))?;
+
+        let conditions = self
+            .constraints
+            .iter()
+            .map(|c| c.to_string())
+            .chain(self.expressions.iter().map(|e| e.to_string()))
+            .collect::<Vec<_>>();
+
+        if !conditions.is_empty() {
+            write!(f, " @ {}", conditions.join(", "))?;
         }
 
         Ok(())
@@ -610,6 +1011,16 @@ pub struct Caveat {
 }
 
 impl Caveat {
+    /// binds the parameter `{name}` to a value in all the queries
+    pub fn set<T: Into<Atom>>(&mut self, name: &str, value: T) -> Result<(), error::Token> {
+        set_parameter(self.queries.iter_mut().flat_map(Rule::atoms_mut), name, &value.into())
+    }
+
+    /// names of the parameters that are not bound yet
+    pub fn parameters(&self) -> BTreeSet<String> {
+        parameters(self.queries.iter().flat_map(Rule::atoms))
+    }
+
     pub fn convert(&self, symbols: &mut SymbolTable) -> datalog::Caveat {
         let mut queries = vec![];
         for q in self.queries.iter() {
@@ -620,6 +1031,14 @@ impl Caveat {
     }
 }
 
+impl Caveat {
+    pub fn convert_from(c: &datalog::Caveat, symbols: &SymbolTable) -> Self {
+        Caveat {
+            queries: c.queries.iter().map(|q| Rule::convert_from(q, symbols)).collect(),
+        }
+    }
+}
+
 impl TryFrom<Rule> for Caveat {
     type Error = error::Token;
 
@@ -652,6 +1071,162 @@ impl fmt::Display for Caveat {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyKind {
+    Allow,
+    Deny,
+}
+
+/// verifier policy, like `allow if *allowed(#admin) <- right(#authority, #admin)`
+///
+/// the policy matches if one of its queries generates a fact
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub queries: Vec<Rule>,
+    pub kind: PolicyKind,
+}
+
+impl Policy {
+    /// binds the parameter `{name}` to a value in all the queries
+    pub fn set<T: Into<Atom>>(&mut self, name: &str, value: T) -> Result<(), error::Token> {
+        set_parameter(self.queries.iter_mut().flat_map(Rule::atoms_mut), name, &value.into())
+    }
+
+    /// names of the parameters that are not bound yet
+    pub fn parameters(&self) -> BTreeSet<String> {
+        parameters(self.queries.iter().flat_map(Rule::atoms))
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            PolicyKind::Allow => write!(f, "allow if ")?,
+            PolicyKind::Deny => write!(f, "deny if ")?,
+        }
+
+        let queries = self.queries.iter().map(|q| q.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", queries.join(" || "))
+    }
+}
+
+fn parameters<'a, I: Iterator<Item = &'a Atom>>(atoms: I) -> BTreeSet<String> {
+    atoms
+        .filter_map(|atom| match atom {
+            Atom::Parameter(p) => Some(p.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// replaces the parameter with the value, the value cannot be
+/// reinterpreted as Datalog since it is already a term
+fn set_parameter<'a, I: Iterator<Item = &'a mut Atom>>(
+    atoms: I,
+    name: &str,
+    value: &Atom,
+) -> Result<(), error::Token> {
+    let mut found = false;
+    for atom in atoms {
+        if matches!(atom, Atom::Parameter(p) if p == name) {
+            *atom = value.clone();
+            found = true;
+        }
+    }
+
+    if found {
+        Ok(())
+    } else {
+        Err(error::Token::UnknownParameter(name.to_string()))
+    }
+}
+
+/// rejects the templates where some parameters were not bound
+pub(crate) fn check_parameters(parameters: BTreeSet<String>) -> Result<(), error::Token> {
+    if parameters.is_empty() {
+        Ok(())
+    } else {
+        Err(error::Token::UnboundParameters(parameters.into_iter().collect()))
+    }
+}
+
+/// rejects the rules where some variables are not bound by the body
+/// predicates (see `datalog::Rule::is_safe`), and the rules without
+/// body predicates, that the parser does not accept
+pub(crate) fn check_safety(rule: &datalog::Rule, symbols: &SymbolTable) -> Result<(), error::Token> {
+    if rule.body.is_empty() {
+        Err(error::Token::EmptyRuleBody(symbols.print_rule(rule)))
+    } else if rule.is_safe() {
+        Ok(())
+    } else {
+        Err(error::Token::UnsafeRule(symbols.print_rule(rule)))
+    }
+}
+
+/// rejects a rule that cannot be stratified with the other rules of a
+/// block: a predicate would depend on its own negation
+pub(crate) fn check_stratification(
+    rules: &[datalog::Rule],
+    rule: &datalog::Rule,
+    symbols: &SymbolTable,
+) -> Result<(), error::Token> {
+    let rules = rules.iter().chain(std::iter::once(rule)).collect::<Vec<_>>();
+    if datalog::stratify(&rules).is_ok() {
+        Ok(())
+    } else {
+        Err(error::Token::FailedLogic(error::Logic::UnstratifiableRules(
+            symbols.print_rule(rule),
+        )))
+    }
+}
+
+/// parses a Datalog source text, and checks its rules before anything is added
+///
+/// policies are only accepted by the verifier, and contexts by blocks
+pub(crate) fn parse_checked_source(
+    source: &str,
+    symbols: &SymbolTable,
+    verifier: bool,
+) -> Result<Vec<Statement>, error::Token> {
+    let statements = parser::parse_source(source).map_err(error::Token::ParseError)?;
+
+    let mut symbols = symbols.clone();
+    for (offset, statement) in statements.iter() {
+        let misplaced = |message| {
+            Err(error::Token::ParseError(error::Parse::new(source, *offset, message)))
+        };
+
+        let parameters = match statement {
+            Statement::Fact(fact) => fact.parameters(),
+            Statement::Rule(rule) => rule.parameters(),
+            Statement::Caveat(caveat) => caveat.parameters(),
+            Statement::Policy(policy) => policy.parameters(),
+            Statement::Context(_) => BTreeSet::new(),
+        };
+        check_parameters(parameters)?;
+
+        let queries = match statement {
+            Statement::Fact(_) => continue,
+            Statement::Context(_) if verifier => {
+                return misplaced("a context can only be set in a block");
+            }
+            Statement::Context(_) => continue,
+            Statement::Rule(rule) => std::slice::from_ref(rule),
+            Statement::Caveat(caveat) => &caveat.queries[..],
+            Statement::Policy(_) if !verifier => {
+                return misplaced("policies can only be added to a verifier");
+            }
+            Statement::Policy(policy) => &policy.queries[..],
+        };
+
+        for query in queries.iter() {
+            check_safety(&query.convert(&mut symbols), &symbols)?;
+        }
+    }
+
+    Ok(statements.into_iter().map(|(_, statement)| statement).collect())
+}
+
 /// creates a new fact
 pub fn fact<I: AsRef<Atom>>(name: &str, ids: &[I]) -> Fact {
     Fact(pred(name, ids))
@@ -671,11 +1246,13 @@ pub fn rule<I: AsRef<Atom>, P: AsRef<Predicate>>(
     head_ids: &[I],
     predicates: &[P],
 ) -> Rule {
-    Rule(
-        pred(head_name, head_ids),
-        predicates.iter().map(|p| p.as_ref().clone()).collect(),
-        Vec::new(),
-    )
+    Rule {
+        head: pred(head_name, head_ids),
+        body: predicates.iter().map(|p| p.as_ref().clone()).collect(),
+        constraints: Vec::new(),
+        expressions: Vec::new(),
+        negated: Vec::new(),
+    }
 }
 
 /// creates a rule with constraints
@@ -685,11 +1262,13 @@ pub fn constrained_rule<I: AsRef<Atom>, P: AsRef<Predicate>, C: AsRef<Constraint
     predicates: &[P],
     constraints: &[C],
 ) -> Rule {
-    Rule(
-        pred(head_name, head_ids),
-        predicates.iter().map(|p| p.as_ref().clone()).collect(),
-        constraints.iter().map(|c| c.as_ref().clone()).collect(),
-    )
+    Rule {
+        head: pred(head_name, head_ids),
+        body: predicates.iter().map(|p| p.as_ref().clone()).collect(),
+        constraints: constraints.iter().map(|c| c.as_ref().clone()).collect(),
+        expressions: Vec::new(),
+        negated: Vec::new(),
+    }
 }
 
 /// creates an integer value
@@ -738,3 +1317,40 @@ pub fn variable(s: &str) -> Atom {
 pub fn bytes(s: &[u8]) -> Atom {
     Atom::Bytes(s.to_vec())
 }
+
+/// creates a boolean
+pub fn boolean(b: bool) -> Atom {
+    Atom::Bool(b)
+}
+
+/// creates a template parameter
+pub fn parameter(s: &str) -> Atom {
+    Atom::Parameter(s.to_string())
+}
+
+/// creates a set
+///
+/// sets can contain other sets, but not variables
+pub fn set(s: BTreeSet<Atom>) -> Atom {
+    Atom::Set(s)
+}
+
+/// counts the values of a variable, in a rule head
+pub fn count(s: &str) -> Atom {
+    Atom::Aggregate(AggregateKind::Count, s.to_string())
+}
+
+/// minimum of the values of a variable, in a rule head
+pub fn min(s: &str) -> Atom {
+    Atom::Aggregate(AggregateKind::Min, s.to_string())
+}
+
+/// maximum of the values of a variable, in a rule head
+pub fn max(s: &str) -> Atom {
+    Atom::Aggregate(AggregateKind::Max, s.to_string())
+}
+
+/// sum of the values of a variable, in a rule head
+pub fn sum(s: &str) -> Atom {
+    Atom::Aggregate(AggregateKind::Sum, s.to_string())
+}
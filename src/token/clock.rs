@@ -0,0 +1,33 @@
+//! source of the current time for the verifier
+//!
+//! `Verifier::set_time` adds the `time(#ambient, ...)` fact used by the
+//! expiration caveats (see `BlockBuilder::expiration_date`) with the time
+//! given by a `Clock`, the system clock by default. Tests and replays of
+//! past requests can set a `FixedClock` with `Verifier::set_clock`, or call
+//! `Verifier::set_time_at` directly
+use std::time::SystemTime;
+
+/// gives the current time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// current time of the system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// clock always giving the same time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
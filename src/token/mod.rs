@@ -1,19 +1,25 @@
 //! main structures to interact with Biscuit tokens
-use super::crypto::{KeyPair, PublicKey};
-use super::datalog::{Fact, Rule, Caveat, SymbolTable, World, ID};
+use super::crypto::{KeyPair, PublicKey, RootKeyProvider};
+use super::datalog::{Fact, Rule, Caveat, SymbolMap, SymbolTable, World, ID};
 use super::error;
 use super::format::SerializedBiscuit;
 use builder::{BiscuitBuilder, BlockBuilder};
 use prost::Message;
 use rand_core::{CryptoRng, RngCore};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 use crate::format::{convert::proto_block_to_token_block, schema};
 use verifier::Verifier;
+use verifier_policy::VerifierPolicy;
 
 pub mod builder;
+pub mod clock;
+pub mod revocation;
 pub mod sealed;
+pub mod third_party;
 pub mod verifier;
+pub mod verifier_policy;
 
 /// some symbols are predefined and available in every implementation, to avoid
 /// transmitting them with every token
@@ -261,25 +267,66 @@ impl Biscuit {
         sealed.to_vec().map_err(error::Token::Format)
     }
 
+    /// unique revocation identifiers of the authority block, then of each block
+    ///
+    /// they are derived from the signature, so they do not depend on the
+    /// contents of the blocks: revoking the identifier of a block revokes
+    /// every token derived from it. Sealed tokens do not have them
+    pub fn revocation_identifiers(&self) -> Vec<revocation::RevocationId> {
+        self.container
+            .as_ref()
+            .map(|c| c.revocation_identifiers())
+            .unwrap_or_default()
+            .into_iter()
+            .map(revocation::RevocationId::Unique)
+            .collect()
+    }
+
     /// returns the internal representation of the token
     pub fn container(&self) -> Option<&SerializedBiscuit> {
         self.container.as_ref()
     }
 
-    pub fn check_root_key(&self, root: PublicKey) -> Result<(), error::Token> {
+    /// identifier of the root key that created the token, if it was set
+    pub fn root_key_id(&self) -> Option<u32> {
+        self.container.as_ref().and_then(|c| c.root_key_id)
+    }
+
+    /// checks that the token was created with one of the root keys
+    /// chosen by the provider, and returns that key
+    pub fn check_root_key<K: RootKeyProvider>(&self, root: K) -> Result<PublicKey, error::Token> {
         self.container
             .as_ref()
             .map(|c| c.check_root_key(root).map_err(error::Token::Format))
-            .unwrap_or(Err(error::Token::Sealed))?;
-        Ok(())
+            .unwrap_or(Err(error::Token::Sealed))
+    }
+
+    /// creates a verifier, after checking the root key
+    ///
+    /// `root` is a `PublicKey`, or a `KeyStore` to choose among multiple
+    /// keys. `Verifier::root_key` returns the key that matched
+    pub fn verify<K: RootKeyProvider>(&self, root: K) -> Result<Verifier<'_>, error::Token> {
+        let key = self.check_root_key(root)?;
+        let mut verifier = Verifier::new(self).map_err(error::Token::FailedLogic)?;
+        verifier.root_key = Some(key);
+        Ok(verifier)
     }
 
-    pub fn verify(&self, root: PublicKey) -> Result<Verifier, error::Token> {
-        self.check_root_key(root)?;
-        Verifier::new(self).map_err(error::Token::FailedLogic)
+    /// like `verify`, the verifier starts with the facts, rules, caveats
+    /// and policies of `policy`, shared between the verifiers of all tokens
+    pub fn verify_with_policy<K: RootKeyProvider>(
+        &self,
+        root: K,
+        policy: Arc<VerifierPolicy>,
+    ) -> Result<Verifier<'_>, error::Token> {
+        let key = self.check_root_key(root)?;
+        let mut verifier =
+            Verifier::with_policy(self, Some(policy)).map_err(error::Token::FailedLogic)?;
+        verifier.root_key = Some(key);
+        Ok(verifier)
     }
 
-    pub fn verify_sealed(&self) -> Result<Verifier, error::Token> {
+    pub fn verify_sealed(&self) -> Result<Verifier<'_>, error::Token> {
         if self.container.is_some() {
             Err(error::Token::InternalError)
         } else {
@@ -287,71 +334,92 @@ impl Biscuit {
         }
     }
 
-    pub(crate) fn generate_world(&self, symbols: &SymbolTable) -> Result<World, error::Logic> {
+    /// the facts and rules are tagged with the index of their block, the
+    /// authority block having the index 0 (see `World::scoped`)
+    ///
+    /// they are converted with `map` if the world uses another symbol table,
+    /// `symbols` being the table of the token
+    pub(crate) fn generate_world(
+        &self,
+        symbols: &SymbolTable,
+        map: Option<&SymbolMap>,
+    ) -> Result<World, error::Logic> {
         let mut world = World::new();
 
         let authority_index = symbols.get("authority").unwrap();
         let ambient_index = symbols.get("ambient").unwrap();
+        let convert_fact = |fact: &Fact| map.map(|map| map.fact(fact)).unwrap_or_else(|| fact.clone());
+        let convert_rule = |rule: &Rule| map.map(|map| map.rule(rule)).unwrap_or_else(|| rule.clone());
 
-        for fact in self.authority.facts.iter().cloned() {
+        for fact in self.authority.facts.iter() {
             if fact.predicate.ids[0] == ID::Symbol(ambient_index) {
                 return Err(error::Logic::InvalidAuthorityFact(
-                    symbols.print_fact(&fact),
+                    symbols.print_fact(fact),
                 ));
             }
 
-            world.facts.insert(fact);
+            world.add_fact_with_origin(convert_fact(fact), 0);
         }
 
-        for rule in self.authority.rules.iter().cloned() {
-            world.rules.push(rule);
+        for rule in self.authority.rules.iter() {
+            world.add_rule_with_origin(convert_rule(rule), 0);
         }
 
         for (i, block) in self.blocks.iter().enumerate() {
             // blocks cannot provide authority or ambient facts
-            for fact in block.facts.iter().cloned() {
+            for fact in block.facts.iter() {
                 if fact.predicate.ids[0] == ID::Symbol(authority_index)
                     || fact.predicate.ids[0] == ID::Symbol(ambient_index)
                 {
                     return Err(error::Logic::InvalidBlockFact(
                         i as u32,
-                        symbols.print_fact(&fact),
+                        symbols.print_fact(fact),
                     ));
                 }
 
-                world.facts.insert(fact);
+                world.add_fact_with_origin(convert_fact(fact), i + 1);
             }
 
-            for rule in block.rules.iter().cloned() {
+            for rule in block.rules.iter() {
                 // block rules cannot generate authority or ambient facts
                 if rule.head.ids[0] == ID::Symbol(authority_index)
                     || rule.head.ids[0] == ID::Symbol(ambient_index)
                 {
                     return Err(error::Logic::InvalidBlockRule(
                         i as u32,
-                        symbols.print_rule(&rule),
+                        symbols.print_rule(rule),
                     ));
                 }
-                world.rules.push(rule);
+                world.add_rule_with_origin(convert_rule(rule), i + 1);
             }
         }
 
-        world.run();
+        if let Err(i) = world.stratify() {
+            // the world has the rules of the blocks in the same order,
+            // they are printed before their conversion
+            let rule = std::iter::once(&self.authority)
+                .chain(self.blocks.iter())
+                .flat_map(|block| block.rules.iter())
+                .nth(i)
+                .unwrap_or(&world.rules[i]);
+            return Err(error::Logic::UnstratifiableRules(symbols.print_rule(rule)));
+        }
 
         Ok(world)
     }
 
-    pub(crate) fn caveats(&self) -> Vec<Vec<Caveat>> {
-        let mut result = Vec::new();
-        let v = self.authority.caveats.to_vec();
-        result.push(v);
-
-        for block in self.blocks.iter() {
-            let v = block.caveats.to_vec();
-            result.push(v);
-        }
-
-        result
+    /// caveats of each block, converted with `map` like in `generate_world`
+    pub(crate) fn caveats(&self, map: Option<&SymbolMap>) -> Vec<Vec<Caveat>> {
+        std::iter::once(&self.authority)
+            .chain(self.blocks.iter())
+            .map(|block| {
+                block
+                    .caveats
+                    .iter()
+                    .map(|c| map.map(|map| map.caveat(c)).unwrap_or_else(|| c.clone()))
+                    .collect()
+            })
+            .collect()
     }
 
     /// checks the caveats of a token, in the context of the request it comes with
@@ -377,8 +445,10 @@ impl Biscuit {
         ambient_rules: Vec<Rule>,
         verifier_caveats: Vec<Caveat>,
         queries: HashMap<String, Rule>,
-    ) -> Result<HashMap<String, Vec<Fact>>, error::Logic> {
-        let mut world = self.generate_world(symbols)?;
+    ) -> Result<HashMap<String, Vec<Fact>>, error::Token> {
+        let mut world = self
+            .generate_world(symbols, None)
+            .map_err(error::Token::FailedLogic)?;
 
         for fact in ambient_facts.drain(..) {
             world.facts.insert(fact);
@@ -388,7 +458,7 @@ impl Biscuit {
             world.rules.push(rule);
         }
 
-        world.run();
+        world.run().map_err(error::Token::RunLimit)?;
         //println!("world:\n{}", symbols.print_world(&world));
 
         // we only keep the verifier rules
@@ -468,7 +538,7 @@ impl Biscuit {
         if errors.is_empty() {
             Ok(query_results)
         } else {
-            Err(error::Logic::FailedCaveats(errors))
+            Err(error::Token::FailedLogic(error::Logic::FailedCaveats(errors)))
         }
     }
 
@@ -499,6 +569,61 @@ impl Biscuit {
         rng: &mut T,
         keypair: &KeyPair,
         block: Block,
+    ) -> Result<Self, error::Token> {
+        self.append_block(rng, keypair, block, None)
+    }
+
+    /// creates the request sent to a third party to get a block signed
+    /// by it, see the `third_party` module
+    pub fn third_party_request(&self) -> Result<third_party::ThirdPartyRequest, error::Token> {
+        let container = self.container.as_ref().ok_or(error::Token::Sealed)?;
+        let previous_id = container
+            .revocation_identifiers()
+            .pop()
+            .ok_or(error::Token::InternalError)?;
+
+        Ok(third_party::ThirdPartyRequest {
+            previous_id,
+            index: (1 + self.blocks.len()) as u32,
+            symbols: self.symbols.clone(),
+        })
+    }
+
+    /// adds a block signed by a third party
+    ///
+    /// the block must have been created from a request for this token,
+    /// with `third_party_request`
+    pub fn append_third_party<T: RngCore + CryptoRng>(
+        &self,
+        rng: &mut T,
+        keypair: &KeyPair,
+        block: third_party::ThirdPartyBlock,
+    ) -> Result<Self, error::Token> {
+        let request = self.third_party_request()?;
+        block
+            .external
+            .verify(&request.previous_id, &block.payload)
+            .map_err(error::Token::Format)?;
+
+        let token_block = block.block()?;
+        self.append_block(rng, keypair, token_block, Some(block))
+    }
+
+    /// public key of the third party that signed a block, the authority
+    /// block having the index 0
+    pub fn external_key(&self, index: usize) -> Option<PublicKey> {
+        self.container
+            .as_ref()
+            .and_then(|c| index.checked_sub(1).and_then(|i| c.external_signatures.get(i)))
+            .and_then(|external| external.as_ref().map(|e| e.public_key))
+    }
+
+    fn append_block<T: RngCore + CryptoRng>(
+        &self,
+        rng: &mut T,
+        keypair: &KeyPair,
+        block: Block,
+        third_party: Option<third_party::ThirdPartyBlock>,
     ) -> Result<Self, error::Token> {
         if self.container.is_none() {
             return Err(error::Token::Sealed);
@@ -524,9 +649,15 @@ impl Biscuit {
 
         let container = match self.container.as_ref() {
             None => return Err(error::Token::Sealed),
-            Some(c) => c
-                .append(rng, keypair, &block)
-                .map_err(error::Token::Format)?,
+            Some(c) => match third_party {
+                // the third party block is kept as it was signed
+                Some(third_party) => {
+                    c.append_serialized(rng, keypair, third_party.payload, Some(third_party.external))
+                }
+                None => c
+                    .append(rng, keypair, &block)
+                    .map_err(error::Token::Format)?,
+            },
         };
 
         symbols
@@ -553,6 +684,32 @@ impl Biscuit {
       res
     }
 
+    /// prints the contents of the token in the Datalog syntax accepted by
+    /// `add_source`, with a comment before each block
+    pub fn to_source(&self) -> String {
+        let mut res = String::from("// authority block\n");
+        res.push_str(&self.authority.to_source(&self.symbols));
+
+        for block in self.blocks.iter() {
+            res.push_str(&format!("\n// block {}\n", block.index));
+            res.push_str(&block.to_source(&self.symbols));
+        }
+
+        res
+    }
+
+    /// prints the contents of a block in the Datalog syntax accepted by
+    /// `add_source`, the authority block having the index 0
+    pub fn block_source(&self, index: usize) -> Option<String> {
+        if index == 0 {
+            Some(self.authority.to_source(&self.symbols))
+        } else {
+            self.blocks
+                .get(index - 1)
+                .map(|block| block.to_source(&self.symbols))
+        }
+    }
+
     /// pretty printer for this token
     pub fn print(&self) -> String {
         let authority = print_block(&self.symbols, &self.authority);
@@ -608,7 +765,7 @@ fn print_block(symbols: &SymbolTable, block: &Block) -> String {
     )
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Block {
     /// position of the block
     pub index: u32,
@@ -640,6 +797,29 @@ impl Block {
         }
     }
 
+    /// prints the context, facts, rules and caveats of the block, one statement per line
+    ///
+    /// symbols, predicate and variable names must only contain letters,
+    /// digits and underscores to be parsed back
+    pub fn to_source(&self, symbols: &SymbolTable) -> String {
+        let mut statements = Vec::new();
+
+        if let Some(context) = &self.context {
+            statements.push(format!("context {}", builder::string(context)));
+        }
+        for fact in self.facts.iter() {
+            statements.push(builder::Fact::convert_from(fact, symbols).to_string());
+        }
+        for rule in self.rules.iter() {
+            statements.push(builder::Rule::convert_from(rule, symbols).to_string());
+        }
+        for caveat in self.caveats.iter() {
+            statements.push(format!("check if {}", builder::Caveat::convert_from(caveat, symbols)));
+        }
+
+        statements.iter().map(|s| format!("{};\n", s)).collect()
+    }
+
     pub fn symbol_add(&mut self, s: &str) -> ID {
         self.symbols.add(s)
     }
@@ -656,7 +836,7 @@ impl Block {
         verifier_caveats: &[Caveat],
         queries: &HashMap<String, Rule>,
         query_results: &mut HashMap<String, HashMap<u32, Vec<Fact>>>,
-    ) -> Result<(), error::Logic> {
+    ) -> Result<(), error::Token> {
         let authority_index = symbols.get("authority").unwrap();
         let ambient_index = symbols.get("ambient").unwrap();
 
@@ -664,10 +844,10 @@ impl Block {
             if fact.predicate.ids[0] == ID::Symbol(authority_index)
                 || fact.predicate.ids[0] == ID::Symbol(ambient_index)
             {
-                return Err(error::Logic::InvalidBlockFact(
+                return Err(error::Token::FailedLogic(error::Logic::InvalidBlockFact(
                     i as u32,
                     symbols.print_fact(&fact),
-                ));
+                )));
             }
 
             world.facts.insert(fact);
@@ -677,7 +857,7 @@ impl Block {
             world.rules.push(rule);
         }
 
-        world.run();
+        world.run().map_err(error::Token::RunLimit)?;
 
         let mut errors = vec![];
         for (j, caveat) in self.caveats.iter().enumerate() {
@@ -730,15 +910,16 @@ impl Block {
         if errors.is_empty() {
             Ok(())
         } else {
-            Err(error::Logic::FailedCaveats(errors))
+            Err(error::Token::FailedLogic(error::Logic::FailedCaveats(errors)))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::builder::{fact, pred, rule, s, var, int};
+    use super::builder::{date, fact, pred, rule, s, string, var, int};
     use super::*;
+    use super::verifier::FactScope;
     use crate::crypto::KeyPair;
     use crate::error::*;
     use rand::prelude::*;
@@ -888,10 +1069,10 @@ mod tests {
             let res = final_token.check(&symbols, ambient_facts, vec![], vec![], HashMap::new());
             println!("res2: {:#?}", res);
             assert_eq!(res,
-              Err(Logic::FailedCaveats(vec![
+              Err(Token::FailedLogic(Logic::FailedCaveats(vec![
                 FailedCaveat::Block(FailedBlockCaveat { block_id: 0, caveat_id: 0, rule: String::from("*caveat1($resource) <- resource(#ambient, $resource), operation(#ambient, #read), right(#authority, $resource, #read)") }),
                 FailedCaveat::Block(FailedBlockCaveat { block_id: 1, caveat_id: 0, rule: String::from("*caveat2(#file1) <- resource(#ambient, #file1)") })
-              ])));
+              ]))));
         }
     }
 
@@ -1010,13 +1191,12 @@ mod tests {
             verifier.add_operation("read");
             verifier.set_time();
             verifier.revocation_check(&[0, 1, 2, 5, 1234]);
+            // the ids are checked before the caveats, none is added
+            assert!(verifier.dump().2.is_empty());
 
             let res = verifier.verify();
             println!("res3: {:?}", res);
-
-            // error message should be like this:
-            //"Verifier caveat 0 failed: revocation_check(0?) <- revocation_id(0?) | 0? not in {2, 1234, 1, 5, 0}"
-            assert!(res.is_err());
+            assert_eq!(res, Err(Token::FailedLogic(Logic::Revoked(1))));
         }
     }
 
@@ -1152,6 +1332,9 @@ mod tests {
             let res = verifier.verify();
             println!("res1: {:?}", res);
 
+            // like the other block facts, the revocation ids are only
+            // visible to the verifier in the shared scope
+            verifier.set_fact_scope(FactScope::Shared);
             let res2 = verifier.query(rule(
                 "revocation_id_verif",
                 &[builder::Atom::Variable("id".to_string())],
@@ -1261,7 +1444,92 @@ mod tests {
         println!("biscuit2 (with name fact): {}", biscuit2.print());
         let mut verifier2 = biscuit2.verify(root.public()).unwrap();
         let res2 = verifier2.verify();
-        assert_eq!(res2, Ok(()));
+        assert_eq!(res2, res1);
+
+        // previous versions let the facts of a block satisfy an earlier caveat
+        verifier2.set_fact_scope(FactScope::Shared);
+        let res2 = verifier2.verify();
+        assert_eq!(res2, Ok(None));
+    }
+
+    #[test]
+    fn fact_scope() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file1", "read");
+        builder.add_right("file2", "read");
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        block2.add_rule("*allowed($f) <- resource(#ambient, $f) @ $f == \"file1\"").unwrap();
+        block2.add_caveat("*check($f) <- allowed($f)").unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1.append(&mut rng, &keypair2, block2.build()).unwrap();
+
+        // a later block tries to extend the rights given by the previous one
+        let mut block3 = biscuit2.create_block();
+        block3.add_rule("*allowed($f) <- resource(#ambient, $f)").unwrap();
+        block3.add_fact("granted(\"file2\")").unwrap();
+        let keypair3 = KeyPair::new(&mut rng);
+        let biscuit3 = biscuit2.append(&mut rng, &keypair3, block3.build()).unwrap();
+
+        let mut verifier = biscuit3.verify(root.public()).unwrap();
+        verifier.add_resource("file2");
+        verifier.add_operation("read");
+        let failed = Err(Token::FailedLogic(Logic::FailedCaveats(vec![
+            FailedCaveat::Block(FailedBlockCaveat {
+                block_id: 1,
+                caveat_id: 0,
+                rule: String::from("*check($f) <- allowed($f)"),
+            }),
+        ])));
+        assert_eq!(verifier.verify(), failed);
+        let diagnostics = verifier.diagnose_caveats().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert!(verifier.explain_caveats().unwrap().is_empty());
+        // the verifier does not see the facts of the blocks either
+        assert!(verifier.query("*granted($f) <- granted($f)").unwrap().is_empty());
+
+        verifier.set_fact_scope(FactScope::Shared);
+        assert_eq!(verifier.verify(), Ok(None));
+        assert_eq!(
+            verifier.query("*granted($f) <- granted($f)").unwrap(),
+            vec![fact("granted", &[string("file2")])]
+        );
+
+        let mut verifier = biscuit3.verify(root.public()).unwrap();
+        verifier.add_resource("file1");
+        verifier.add_operation("read");
+        assert_eq!(verifier.verify(), Ok(None));
+        assert_eq!(verifier.explain_caveats().unwrap().len(), 1);
+
+        // the revocation ids are visible in the scope of their block only
+        let mut block3 = biscuit2.create_block();
+        block3.revocation_id(1234);
+        let keypair3 = KeyPair::new(&mut rng);
+        let biscuit3 = biscuit2.append(&mut rng, &keypair3, block3.build()).unwrap();
+        let mut block4 = biscuit3.create_block();
+        block4.add_caveat("*known($id) <- revocation_id($id)").unwrap();
+        let keypair4 = KeyPair::new(&mut rng);
+        let biscuit4 = biscuit3.append(&mut rng, &keypair4, block4.build()).unwrap();
+
+        let mut verifier = biscuit4.verify(root.public()).unwrap();
+        verifier.add_resource("file1");
+        verifier.add_operation("read");
+        assert_eq!(
+            verifier.verify(),
+            Err(Token::FailedLogic(Logic::FailedCaveats(vec![
+                FailedCaveat::Block(FailedBlockCaveat {
+                    block_id: 3,
+                    caveat_id: 0,
+                    rule: String::from("*known($id) <- revocation_id($id)"),
+                }),
+            ])))
+        );
+        verifier.set_fact_scope(FactScope::Shared);
+        assert_eq!(verifier.verify(), Ok(None));
     }
 
     #[test]
@@ -1277,13 +1545,13 @@ mod tests {
 
         let mut block2 = biscuit1.create_block();
         block2.add_rule("*has_bytes(0) <- bytes(#authority, $0) @ $0 in [ hex:00000000, hex:0102AB ]").unwrap();
+        block2.add_caveat("*ok(0) <- has_bytes($0)").unwrap();
         let keypair2 = KeyPair::new(&mut rng);
         let biscuit2 = biscuit1
             .append(&mut rng, &keypair2, block2.build())
             .unwrap();
 
         let mut verifier = biscuit2.verify(root.public()).unwrap();
-        verifier.add_caveat("*ok(0) <- has_bytes($0)").unwrap();
 
         let res = verifier.verify();
         println!("res1: {:?}", res);
@@ -1293,4 +1561,1167 @@ mod tests {
         println!("query result: {:x?}", res);
         println!("query result: {}", res[0]);
     }
+
+    #[test]
+    fn expressions() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_authority_fact("quota(#authority, \"/folder1\", 100)").unwrap();
+        builder.add_authority_fact("expires(#authority, 2020-01-01T00:00:00Z)").unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        block2.add_caveat("*quota($path) <- resource(#ambient, $path), requested(#ambient, $r), quota(#authority, $prefix, $q) @ $r < $q, $path.starts_with($prefix)").unwrap();
+        block2.add_caveat("*not_expired(0) <- time(#ambient, $now), expires(#authority, $exp) @ $now <= $exp + 3600").unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1
+            .append(&mut rng, &keypair2, block2.build())
+            .unwrap();
+
+        let serialized = biscuit2.to_vec().unwrap();
+        let biscuit3 = Biscuit::from(&serialized).unwrap();
+
+        {
+            let mut verifier = biscuit3.verify(root.public()).unwrap();
+            verifier.add_resource("/folder1/file1");
+            verifier.add_fact("requested(#ambient, 99)").unwrap();
+            verifier.add_fact("time(#ambient, 2020-01-01T00:59:00Z)").unwrap();
+
+            let res = verifier.verify();
+            println!("res1: {:?}", res);
+            res.unwrap();
+        }
+
+        {
+            let mut verifier = biscuit3.verify(root.public()).unwrap();
+            verifier.add_resource("/folder2/file1");
+            verifier.add_fact("requested(#ambient, 100)").unwrap();
+            verifier.add_fact("time(#ambient, 2020-01-01T01:01:00Z)").unwrap();
+
+            let res = verifier.verify();
+            println!("res2: {:?}", res);
+            assert_eq!(res,
+              Err(Token::FailedLogic(Logic::FailedCaveats(vec![
+                FailedCaveat::Block(FailedBlockCaveat { block_id: 1, caveat_id: 0, rule: String::from("*quota($path) <- resource(#ambient, $path), requested(#ambient, $r), quota(#authority, $prefix, $q) @ $r < $q, $path.starts_with($prefix)") }),
+                FailedCaveat::Block(FailedBlockCaveat { block_id: 1, caveat_id: 1, rule: String::from("*not_expired(0) <- time(#ambient, $now), expires(#authority, $exp) @ $now <= $exp + 3600") }),
+              ]))));
+        }
+    }
+
+    #[test]
+    fn negation() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_authority_fact("user(#authority, \"alice\")").unwrap();
+        builder.add_authority_fact("user(#authority, \"bob\")").unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        block2.add_rule("*allowed($u) <- user(#authority, $u), !banned($u)").unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1
+            .append(&mut rng, &keypair2, block2.build())
+            .unwrap();
+
+        let serialized = biscuit2.to_vec().unwrap();
+        let biscuit3 = Biscuit::from(&serialized).unwrap();
+
+        let mut verifier = biscuit3.verify(root.public()).unwrap();
+        // the verifier caveats use the rule of the block
+        verifier.set_fact_scope(FactScope::Shared);
+        verifier.add_fact("banned(\"bob\")").unwrap();
+        verifier.add_caveat("*check(0) <- allowed(\"alice\")").unwrap();
+        verifier.add_caveat("*check(1) <- user(#authority, \"bob\"), !allowed(\"bob\")").unwrap();
+
+        let res = verifier.verify();
+        println!("res1: {:?}", res);
+        res.unwrap();
+
+        // the facts are generated again after adding a fact
+        verifier.add_fact("banned(\"alice\")").unwrap();
+        let res = verifier.query("*allowed($u) <- allowed($u)").unwrap();
+        assert!(res.is_empty());
+
+        assert_eq!(
+            verifier.add_rule("*banned($u) <- user(#authority, $u), !allowed($u)"),
+            Err(Token::FailedLogic(Logic::UnstratifiableRules(String::from(
+                "*banned($u) <- user(#authority, $u), !allowed($u)"
+            ))))
+        );
+
+        // rules from the token are checked when loading it
+        let mut block3 = biscuit2.create_block();
+        block3.add_rule("*banned($u) <- user(#authority, $u), !allowed($u)").unwrap();
+        let keypair3 = KeyPair::new(&mut rng);
+        let biscuit4 = biscuit2
+            .append(&mut rng, &keypair3, block3.build())
+            .unwrap();
+
+        assert!(matches!(
+            biscuit4.verify(root.public()),
+            Err(Token::FailedLogic(Logic::UnstratifiableRules(_)))
+        ));
+
+        // and by the block builder, with the other rules of the block
+        let mut block4 = biscuit2.create_block();
+        block4.add_rule("*allowed($u) <- user(#authority, $u), !banned($u)").unwrap();
+        assert_eq!(
+            block4.add_rule("*banned($u) <- user(#authority, $u), !allowed($u)"),
+            Err(Token::FailedLogic(Logic::UnstratifiableRules(String::from(
+                "*banned($u) <- user(#authority, $u), !allowed($u)"
+            ))))
+        );
+    }
+
+    #[test]
+    fn aggregates() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        for i in 0..4 {
+            builder
+                .add_authority_fact(format!("right(#authority, \"file{}\", #read)", i).as_str())
+                .unwrap();
+        }
+        builder.add_authority_fact("right(#authority, \"file0\", #write)").unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        block2.add_rule("*nb_files(count($f)) <- right(#authority, $f, #read)").unwrap();
+        block2.add_caveat("*check($n) <- nb_files($n) @ $n <= 5").unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1
+            .append(&mut rng, &keypair2, block2.build())
+            .unwrap();
+
+        let serialized = biscuit2.to_vec().unwrap();
+        let biscuit3 = Biscuit::from(&serialized).unwrap();
+        println!("biscuit3: {}", biscuit3.print());
+
+        let mut verifier = biscuit3.verify(root.public()).unwrap();
+        // the verifier queries the facts generated by the block
+        verifier.set_fact_scope(FactScope::Shared);
+        let res = verifier.query("*nb_files($n) <- nb_files($n)").unwrap();
+        assert_eq!(res, vec![fact("nb_files", &[int(4)])]);
+
+        let res = verifier
+            .query("*rights($f, count($r)) <- right(#authority, $f, $r) @ $f in [\"file0\"]")
+            .unwrap();
+        assert_eq!(res, vec![fact("rights", &[string("file0"), int(2)])]);
+
+        verifier.verify().unwrap();
+
+        for i in 4..6 {
+            verifier
+                .add_fact(format!("right(#authority, \"file{}\", #read)", i).as_str())
+                .unwrap();
+        }
+        let res = verifier.verify();
+        println!("res: {:?}", res);
+        assert!(matches!(res, Err(Token::FailedLogic(Logic::FailedCaveats(_)))));
+    }
+
+    #[test]
+    fn sets() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder
+            .add_authority_fact("roles(#authority, \"alice\", [#admin, #reader], true)")
+            .unwrap();
+        builder
+            .add_authority_fact("roles(#authority, \"bob\", [#reader, [\"nested\"]], false)")
+            .unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        block2
+            .add_caveat("*check($u) <- user(#ambient, $u), roles(#authority, $u, $roles, $active) @ $roles intersects [#admin, #writer], $roles subset [#admin, #reader, #writer], $active == true")
+            .unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1
+            .append(&mut rng, &keypair2, block2.build())
+            .unwrap();
+
+        let serialized = biscuit2.to_vec().unwrap();
+        let biscuit3 = Biscuit::from(&serialized).unwrap();
+        println!("biscuit3: {}", biscuit3.print());
+
+        let mut verifier = biscuit3.verify(root.public()).unwrap();
+        verifier.add_fact("user(#ambient, \"alice\")").unwrap();
+        verifier.verify().unwrap();
+
+        let res = verifier
+            .query("*nested($u) <- roles(#authority, $u, $roles, $active) @ $roles contains [\"nested\"]")
+            .unwrap();
+        assert_eq!(res, vec![fact("nested", &[string("bob")])]);
+        let res = verifier
+            .query("*admin($u) <- roles(#authority, $u, $roles, $active) @ $roles.contains(#admin)")
+            .unwrap();
+        assert_eq!(res, vec![fact("admin", &[string("alice")])]);
+
+        let mut verifier = biscuit3.verify(root.public()).unwrap();
+        verifier.add_fact("user(#ambient, \"bob\")").unwrap();
+        let res = verifier.verify();
+        println!("res: {:?}", res);
+        assert!(matches!(res, Err(Token::FailedLogic(Logic::FailedCaveats(_)))));
+    }
+
+    #[test]
+    fn provenance() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_authority_fact("owner(#authority, \"alice\", \"file1\")").unwrap();
+        builder
+            .add_authority_rule("*right(#authority, $f, #read) <- owner(#authority, $u, $f)")
+            .unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        block2
+            .add_caveat("*check($f) <- resource(#ambient, $f), right(#authority, $f, #read)")
+            .unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1
+            .append(&mut rng, &keypair2, block2.build())
+            .unwrap();
+
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.add_resource("file1");
+        verifier.add_caveat("*allowed($u) <- owner(#authority, $u, \"file1\")").unwrap();
+        verifier.verify().unwrap();
+
+        let explanations = verifier.explain_caveats().unwrap();
+        assert_eq!(explanations.len(), 2);
+        assert_eq!(explanations[0].block_id, None);
+        assert_eq!(explanations[1].block_id, Some(1));
+        assert_eq!(explanations[1].caveat_id, 0);
+
+        let derivation = &explanations[1].derivations[0];
+        println!("{}", derivation);
+        assert_eq!(
+            derivation.to_string(),
+            "check(\"file1\") from *check($f) <- resource(#ambient, $f), right(#authority, $f, #read)
+  resource(#ambient, \"file1\")
+  right(#authority, \"file1\", #read) from *right(#authority, $f, #read) <- owner(#authority, $u, $f)
+    owner(#authority, \"alice\", \"file1\")
+"
+        );
+
+        let res = verifier
+            .query_derivations("*can_read($f) <- right(#authority, $f, #read)")
+            .unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].fact, fact("can_read", &[string("file1")]));
+        assert_eq!(res[0].premises[0].fact, fact("right", &[s("authority"), string("file1"), s("read")]));
+        assert_eq!(res[0].premises[0].premises.len(), 1);
+    }
+
+    #[test]
+    fn policies() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file1", "read");
+        builder.add_right("file2", "write");
+        builder.add_authority_fact("user(#authority, \"alice\")").unwrap();
+        let biscuit = builder.build(&mut rng).unwrap();
+
+        let policies = [
+            "deny if *denied(#admin) <- user(#authority, \"alice\"), resource(#ambient, \"admin\")",
+            "allow if *allowed($f) <- resource(#ambient, $f), operation(#ambient, $op), right(#authority, $f, $op)",
+            "allow if *allowed(#list) <- operation(#ambient, #list)",
+        ];
+
+        let mut verifier = biscuit.verify(root.public()).unwrap();
+        for policy in policies.iter() {
+            verifier.add_policy(*policy).unwrap();
+        }
+        verifier.snapshot();
+
+        verifier.add_resource("file1");
+        verifier.add_operation("read");
+        assert_eq!(verifier.verify(), Ok(Some(1)));
+
+        // the deny policy is tested first
+        verifier.reset();
+        for policy in policies.iter() {
+            verifier.add_policy(*policy).unwrap();
+        }
+        verifier.add_resource("admin");
+        verifier.add_operation("list");
+        assert_eq!(
+            verifier.verify(),
+            Err(error::Token::FailedLogic(Logic::Deny(0)))
+        );
+
+        verifier.reset();
+        for policy in policies.iter() {
+            verifier.add_policy(*policy).unwrap();
+        }
+        verifier.add_resource("file1");
+        verifier.add_operation("write");
+        assert_eq!(
+            verifier.verify(),
+            Err(error::Token::FailedLogic(Logic::NoMatchingPolicy))
+        );
+
+        // caveats are checked before the policies
+        verifier.reset();
+        verifier.add_policy("allow if *allowed(#list) <- operation(#ambient, #list)").unwrap();
+        verifier.add_caveat("*check(#file2) <- resource(#ambient, \"file2\")").unwrap();
+        verifier.add_resource("file1");
+        verifier.add_operation("list");
+        match verifier.verify() {
+            Err(error::Token::FailedLogic(Logic::FailedCaveats(caveats))) => assert_eq!(caveats.len(), 1),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // without policies, the caveats decide
+        let mut verifier = biscuit.verify(root.public()).unwrap();
+        verifier.add_resource("file1");
+        assert_eq!(verifier.verify(), Ok(None));
+
+        assert!(verifier.add_policy("allow if *allowed($f) <- operation(#ambient, #read)").is_err());
+    }
+
+    #[test]
+    fn source() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder
+            .add_source(
+                r#"
+                right(#authority, "file1", #read);
+                right(#authority, "file2", #read);
+                *right(#authority, $f, #write) <- owner(#authority, "alice", $f);
+                owner(#authority, "alice", "file2");
+                "#,
+            )
+            .unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        block2
+            .add_source(
+                r#"
+                // restricts the token to reads
+                check if *check(#read) <- operation(#ambient, #read);
+                "#,
+            )
+            .unwrap();
+
+        // policies are only accepted by the verifier, and nothing is added on errors
+        match block2.add_source("valid(#a);\n  allow if *allowed(#a) <- valid(#a);") {
+            Err(error::Token::ParseError(e)) => {
+                assert_eq!((e.line, e.column), (2, 3));
+                assert_eq!(e.message, "policies can only be added to a verifier");
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(
+            block2.add_source("valid(#a);\n*invalid($f) <- valid(#a);"),
+            Err(error::Token::UnsafeRule(String::from("*invalid($f) <- valid(#a)")))
+        );
+        assert!(block2.facts.is_empty());
+
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1
+            .append(&mut rng, &keypair2, block2.build())
+            .unwrap();
+
+        let policies = r#"
+            allow if *allowed($f) <- resource(#ambient, $f), operation(#ambient, $op), right(#authority, $f, $op);
+            deny if *denied(#all) <- resource(#ambient, $f);
+        "#;
+
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.add_source(policies).unwrap();
+        verifier.add_resource("file2");
+        verifier.add_operation("read");
+        assert_eq!(verifier.verify(), Ok(Some(0)));
+
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.add_source(policies).unwrap();
+        verifier.add_resource("file2");
+        verifier.add_operation("write");
+        match verifier.verify() {
+            Err(error::Token::FailedLogic(Logic::FailedCaveats(caveats))) => assert_eq!(caveats.len(), 1),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn source_round_trip() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        // every kind of value and constraint, printed in the canonical format
+        let authority = r#"context "user \"alice\"\nid: 1\\2";
+right(#authority, "file1", #read);
+user(#authority, 12, -3, hex:0102ab, hex:, true, false, 2020-12-21T09:23:12+00:00);
+roles(#authority, [#admin, 1, "x", [], [#a]]);
+*int(#authority, $i) <- user(#authority, $i, $j, $b, $e, $t, $f, $d) @ $i < 100, $i > 1, $i <= 12, $i >= 12, $i == 12, $j in [-3, 1, 10], $j not in [2];
+*str(#authority, $s) <- right(#authority, $s, $r) @ $s matches "file*", $s matches "*1", $s == "file1", $s matches /^file\/\\d+$/, $s in ["file1", "file2"], $s not in ["a\"b"];
+*date(#authority, $d) <- user(#authority, $i, $j, $b, $e, $t, $f, $d) @ $d <= 2021-01-01T00:00:00+00:00, $d >= 2020-01-01T00:00:00+00:00;
+*sym(#authority, $r) <- right(#authority, $s, $r) @ $r in [#read, #write], $r not in [#admin];
+*bytes(#authority, $b) <- user(#authority, $i, $j, $b, $e, $t, $f, $d) @ $b == hex:0102ab, $b in [hex:, hex:0102ab], $b not in [hex:ff];
+*set(#authority, $r) <- roles(#authority, $r) @ $r contains #admin, $r intersects [#admin, #owner], $r subset [#admin, 1, "x", [], [#a]];
+*expr(#authority, $i) <- user(#authority, $i, $j, $b, $e, $t, $f, $d), !banned(#authority, $i) @ ($i + $j) * 2 > 10 - $j, $t == true, "file1".starts_with("file");
+*usage(#authority, count($s), max($i)) <- right(#authority, $s, $r), user(#authority, $i, $j, $b, $e, $t, $f, $d);
+check if *valid(#authority) <- right(#authority, "file1", #read) || *valid(#authority) <- user(#authority, 12, $j, $b, $e, $t, $f, $d);
+"#;
+        let block = r#"context "block";
+empty();
+check if *allowed() <- empty(), resource(#ambient, $f) @ $f matches "file*";
+"#;
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_source(authority).unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+        assert_eq!(biscuit1.block_source(0).unwrap(), authority);
+
+        let block2 = BlockBuilder::from_source(1, biscuit1.symbols.clone(), block).unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1
+            .append(&mut rng, &keypair2, block2.build())
+            .unwrap();
+        assert_eq!(biscuit2.block_source(1).unwrap(), block);
+        assert_eq!(biscuit2.block_source(2), None);
+        assert_eq!(
+            biscuit2.to_source(),
+            format!("// authority block\n{}\n// block 1\n{}", authority, block)
+        );
+
+        // the rebuilt token has the same contents
+        let mut builder = Biscuit::builder(&root);
+        builder.add_source(&biscuit2.block_source(0).unwrap()).unwrap();
+        let rebuilt = builder.build(&mut rng).unwrap();
+        let mut block2 = rebuilt.create_block();
+        block2.add_source(&biscuit2.block_source(1).unwrap()).unwrap();
+        let rebuilt = rebuilt.append(&mut rng, &keypair2, block2.build()).unwrap();
+
+        assert_eq!(rebuilt.symbols, biscuit2.symbols);
+        assert_eq!(rebuilt.authority, biscuit2.authority);
+        assert_eq!(rebuilt.blocks, biscuit2.blocks);
+    }
+
+    #[test]
+    fn unsafe_rules() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        assert_eq!(
+            builder.add_authority_rule("*right(#authority, $f, #read) <- resource(#ambient, #file1)"),
+            Err(error::Token::UnsafeRule(String::from(
+                "*right(#authority, $f, #read) <- resource(#ambient, #file1)"
+            )))
+        );
+        // the rejected rule did not add its symbols
+        assert!(builder.symbols.get("f").is_none());
+        assert!(builder
+            .add_authority_caveat("*check($f) <- resource(#ambient, #file1)")
+            .is_err());
+        assert!(builder
+            .add_source("check if *check($f) <- resource(#ambient, #file1);")
+            .is_err());
+        assert!(builder.caveats.is_empty());
+        // rules without body predicates would not parse back
+        assert_eq!(
+            builder.add_authority_rule(rule::<builder::Atom, builder::Predicate>("a", &[int(1)], &[])),
+            Err(error::Token::EmptyRuleBody(String::from("*a(#authority, 1) <- ")))
+        );
+        builder.add_right("file1", "read");
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        assert!(block2
+            .add_caveat("*check($f) <- resource(#ambient, #file1) @ $f > 1")
+            .is_err());
+        assert!(block2
+            .add_caveat("*check($f) <- resource(#ambient, $f), !revoked($f, $g)")
+            .is_err());
+
+        let mut verifier = biscuit1.verify(root.public()).unwrap();
+        assert!(verifier.add_rule("*owner($u, $f) <- user($u)").is_err());
+        assert!(verifier.add_caveat("*check($f) <- resource(#ambient, #file1)").is_err());
+        assert!(verifier.query("*data($f) <- resource(#ambient, #file1)").is_err());
+
+        // unsafe rules are rejected when loading a block
+        let user = block2.symbols.insert("user");
+        let owner = block2.symbols.insert("owner");
+        let u = block2.symbols.insert("u") as u32;
+        let f = block2.symbols.insert("f") as u32;
+        block2.rules.push(crate::datalog::rule(
+            owner,
+            &[ID::Variable(u), ID::Variable(f)],
+            &[crate::datalog::pred(user, &[ID::Variable(u)])],
+        ));
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1
+            .append(&mut rng, &keypair2, block2.build())
+            .unwrap();
+        let serialized = biscuit2.to_vec().unwrap();
+
+        assert_eq!(
+            Biscuit::from(&serialized).unwrap_err(),
+            error::Token::Format(error::Format::UnsafeRule(String::from("block 1, rule 0")))
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        use super::verifier::{Mismatch, QueryFailure};
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file2", "read");
+        builder.add_authority_fact("quota(#authority, 10)").unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        block2
+            .add_caveat("*check($f) <- resource(#ambient, $f), operation(#ambient, #read)")
+            .unwrap();
+        block2
+            .add_caveat("*check($f) <- resource(#ambient, $f), right(#authority, $f, #read)")
+            .unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1
+            .append(&mut rng, &keypair2, block2.build())
+            .unwrap();
+
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.add_resource("file1");
+        verifier.add_operation("write");
+        verifier.add_caveat("*quota($q) <- quota(#authority, $q) @ $q > 20").unwrap();
+        verifier.add_caveat("*quota($q) <- quota(#authority, $q) @ $q + 1 > 20").unwrap();
+
+        let diagnostics = verifier.diagnose_caveats().unwrap();
+        assert_eq!(diagnostics.len(), 4);
+
+        // the verifier caveat has a constraint on the variable
+        assert_eq!(diagnostics[0].caveat, FailedCaveat::Verifier(FailedVerifierCaveat {
+            caveat_id: 0,
+            rule: String::from("*quota($q) <- quota(#authority, $q) @ $q > 20"),
+        }));
+        assert_eq!(
+            diagnostics[0].queries[0].failure.to_string(),
+            "no fact matches quota(#authority, $q); quota(#authority, 10) present, expected $q"
+        );
+        assert_eq!(diagnostics[1].queries[0].failure, QueryFailure::FailedConditions);
+
+        assert!(matches!(
+            diagnostics[2].caveat,
+            FailedCaveat::Block(FailedBlockCaveat { block_id: 1, caveat_id: 0, .. })
+        ));
+        match &diagnostics[2].queries[0].failure {
+            QueryFailure::UnmatchedPredicate { index, closest, .. } => {
+                assert_eq!(*index, 1);
+                assert_eq!(closest.len(), 1);
+                assert_eq!(
+                    closest[0].mismatches,
+                    vec![Mismatch { position: 1, expected: s("read"), found: s("write") }]
+                );
+                assert_eq!(
+                    closest[0].to_string(),
+                    "operation(#ambient, #write) present, expected #read"
+                );
+            }
+            failure => panic!("unexpected failure: {:?}", failure),
+        }
+
+        // the first block caveat now succeeds. For the second one, the right
+        // on file2 is the closest match, with the value bound by the previous predicate
+        verifier.add_operation("read");
+        let diagnostics = verifier.diagnose_caveats().unwrap();
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(
+            diagnostics[2].queries[0].failure.to_string(),
+            "no fact matches right(#authority, $f, #read); right(#authority, \"file2\", #read) present, expected \"file1\""
+        );
+    }
+
+    #[test]
+    fn template_parameters() {
+        use builder::{parameter, Caveat, Fact, Policy};
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        let mut right: Fact = "right(#authority, {file}, {operation})".parse().unwrap();
+        assert_eq!(
+            right.parameters().into_iter().collect::<Vec<_>>(),
+            vec![String::from("file"), String::from("operation")]
+        );
+        right.set("file", string("file1")).unwrap();
+        // the value of a parameter is not interpreted as Datalog
+        let mut injected = right.clone();
+        injected.set("operation", string("read), right(#authority, \"file2\", #read")).unwrap();
+        assert_eq!(
+            injected.to_string(),
+            "right(#authority, \"file1\", \"read), right(#authority, \\\"file2\\\", #read\")"
+        );
+
+        assert_eq!(
+            builder.add_authority_fact(right.clone()),
+            Err(error::Token::UnboundParameters(vec![String::from("operation")]))
+        );
+        assert_eq!(
+            right.set("user", string("alice")),
+            Err(error::Token::UnknownParameter(String::from("user")))
+        );
+        right.set("operation", s("read")).unwrap();
+        builder.add_authority_fact(right).unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        // the same parameter can appear several times, in predicates and expressions
+        let mut caveat: Caveat =
+            "*check($f) <- resource(#ambient, $f), operation(#ambient, {op}) @ $f == {file} || *check({file}) <- owner({file})"
+                .parse()
+                .unwrap();
+        caveat.set("file", string("file1")).unwrap();
+        caveat.set("op", s("read")).unwrap();
+        assert_eq!(
+            caveat.to_string(),
+            "*check($f) <- resource(#ambient, $f), operation(#ambient, #read) @ $f == \"file1\" || *check(\"file1\") <- owner(\"file1\")"
+        );
+        let mut block2 = biscuit1.create_block();
+        block2.add_caveat(caveat).unwrap();
+        assert!(block2.add_source("check if *check($f) <- resource(#ambient, {f});").is_err());
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1.append(&mut rng, &keypair2, block2.build()).unwrap();
+
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        let mut policy: Policy = "allow if *allowed($f) <- right(#authority, $f, {operation})".parse().unwrap();
+        assert_eq!(
+            verifier.add_policy(policy.clone()),
+            Err(error::Token::UnboundParameters(vec![String::from("operation")]))
+        );
+        policy.set("operation", s("read")).unwrap();
+        verifier.add_policy(policy).unwrap();
+
+        let mut query = builder::rule("data", &[var("f")], &[pred("resource", &[s("ambient"), parameter("f")])]);
+        assert!(verifier.query(query.clone()).is_err());
+        query.set("f", var("f")).unwrap();
+
+        verifier.add_resource("file1");
+        verifier.add_operation("read");
+        assert_eq!(verifier.verify(), Ok(Some(0)));
+        let res: Vec<builder::Fact> = verifier.query(query).unwrap();
+        assert_eq!(res, vec![builder::fact("data", &[string("file1")])]);
+    }
+
+    #[test]
+    fn revocation_store() {
+        use super::revocation::{MemoryRevocationStore, RevocationId};
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file1", "read");
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut block2 = biscuit1.create_block();
+        block2.revocation_id(1234);
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1.append(&mut rng, &keypair2, block2.build()).unwrap();
+
+        let mut block3 = biscuit2.create_block();
+        block3.revocation_id(5678);
+        let keypair3 = KeyPair::new(&mut rng);
+        let biscuit3 = biscuit2.append(&mut rng, &keypair3, block3.build()).unwrap();
+
+        let mut store = MemoryRevocationStore::new();
+        store.revoke(RevocationId::Int(1));
+
+        let mut verifier = biscuit3.verify(root.public()).unwrap();
+        verifier.set_revocation_store(&store);
+        assert_eq!(verifier.verify(), Ok(None));
+
+        // revoking a block also revokes the tokens derived from it
+        store.revoke(RevocationId::Int(1234));
+        for token in [&biscuit2, &biscuit3].iter() {
+            let mut verifier = token.verify(root.public()).unwrap();
+            verifier.set_revocation_store(&store);
+            assert_eq!(
+                verifier.verify(),
+                Err(error::Token::FailedLogic(Logic::Revoked(1)))
+            );
+        }
+
+        let store: MemoryRevocationStore = vec![RevocationId::Int(5678)].into_iter().collect();
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.set_revocation_store(&store);
+        assert_eq!(verifier.verify(), Ok(None));
+        let mut verifier = biscuit3.verify(root.public()).unwrap();
+        verifier.set_revocation_store(&store);
+        assert_eq!(
+            verifier.verify(),
+            Err(error::Token::FailedLogic(Logic::Revoked(2)))
+        );
+
+        // revocation_check sees the ids of every block with the default scope,
+        // and accepts the blocks without revocation ids
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.revocation_check(&[5678]);
+        assert_eq!(verifier.verify(), Ok(None));
+        let mut verifier = biscuit3.verify(root.public()).unwrap();
+        verifier.revocation_check(&[5678]);
+        assert_eq!(
+            verifier.verify(),
+            Err(error::Token::FailedLogic(Logic::Revoked(2)))
+        );
+    }
+
+    #[test]
+    fn revocation_identifiers() {
+        use super::revocation::{MemoryRevocationStore, RevocationId};
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file1", "read");
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1.append(&mut rng, &keypair2, biscuit1.create_block().build()).unwrap();
+        let keypair3 = KeyPair::new(&mut rng);
+        let biscuit3 = biscuit2.append(&mut rng, &keypair3, biscuit2.create_block().build()).unwrap();
+        // same contents and key as biscuit2, but signed separately
+        let other2 = biscuit1.append(&mut rng, &keypair2, biscuit1.create_block().build()).unwrap();
+
+        let ids1 = biscuit1.revocation_identifiers();
+        let ids2 = biscuit2.revocation_identifiers();
+        let ids3 = biscuit3.revocation_identifiers();
+        assert_eq!(ids1.len(), 1);
+        assert_eq!(ids2.len(), 2);
+        assert_eq!(ids3.len(), 3);
+        assert_eq!(&ids2[..1], &ids1[..]);
+        assert_eq!(&ids3[..2], &ids2[..]);
+        assert_eq!(other2.revocation_identifiers()[0], ids1[0]);
+        assert_ne!(other2.revocation_identifiers()[1], ids2[1]);
+
+        let deserialized = Biscuit::from(&biscuit3.to_vec().unwrap()).unwrap();
+        assert_eq!(deserialized.revocation_identifiers(), ids3);
+        let sealed = Biscuit::from_sealed(&biscuit3.seal(b"secret").unwrap(), b"secret").unwrap();
+        assert!(sealed.revocation_identifiers().is_empty());
+
+        // revoking the second block revokes the tokens derived from it
+        let store: MemoryRevocationStore = vec![ids2[1].clone()].into_iter().collect();
+        for (token, result) in [
+            (&biscuit1, Ok(None)),
+            (&biscuit2, Err(error::Token::FailedLogic(Logic::Revoked(1)))),
+            (&biscuit3, Err(error::Token::FailedLogic(Logic::Revoked(1)))),
+            (&other2, Ok(None)),
+        ].iter() {
+            let mut verifier = token.verify(root.public()).unwrap();
+            verifier.set_revocation_store(&store);
+            assert_eq!(&verifier.verify(), result);
+        }
+
+        match &ids1[0] {
+            RevocationId::Unique(id) => assert_eq!(id.len(), 32),
+            id => panic!("unexpected identifier: {:?}", id),
+        }
+    }
+
+    #[test]
+    fn root_key_id() {
+        use crate::crypto::KeyStore;
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root1 = KeyPair::new(&mut rng);
+        let root2 = KeyPair::new(&mut rng);
+        let root3 = KeyPair::new(&mut rng);
+
+        let mut store = KeyStore::new();
+        store.insert(1, root1.public());
+        store.insert(2, root2.public());
+        assert_eq!(store.id(&root2.public()), Some(2));
+
+        let mut builder = Biscuit::builder(&root2);
+        builder.set_root_key_id(2);
+        let biscuit1 = builder.build(&mut rng).unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1.append(&mut rng, &keypair2, biscuit1.create_block().build()).unwrap();
+        let biscuit2 = Biscuit::from(&biscuit2.to_vec().unwrap()).unwrap();
+        assert_eq!(biscuit2.root_key_id(), Some(2));
+
+        let verifier = biscuit2.verify(&store).unwrap();
+        assert_eq!(verifier.root_key(), Some(root2.public()));
+        assert_eq!(biscuit2.check_root_key(root2.public()), Ok(root2.public()));
+        assert!(biscuit2.verify(root1.public()).is_err());
+
+        // the identifier selects the key, other keys are not tried
+        let mut rotated = KeyStore::new();
+        rotated.insert(1, root2.public());
+        rotated.insert(3, root3.public());
+        assert_eq!(
+            biscuit2.check_root_key(&rotated),
+            Err(error::Token::Format(Format::UnknownPublicKey))
+        );
+
+        // without an identifier, all the keys are tried
+        let biscuit3 = Biscuit::builder(&root1).build(&mut rng).unwrap();
+        assert_eq!(biscuit3.root_key_id(), None);
+        assert_eq!(biscuit3.check_root_key(&store), Ok(root1.public()));
+        let keys = [root3.public(), root1.public()];
+        assert_eq!(biscuit3.verify(&keys[..]).unwrap().root_key(), Some(root1.public()));
+        assert_eq!(
+            biscuit3.check_root_key(&rotated),
+            Err(error::Token::Format(Format::UnknownPublicKey))
+        );
+
+        let sealed = Biscuit::from_sealed(&biscuit3.seal(b"secret").unwrap(), b"secret").unwrap();
+        assert_eq!(sealed.check_root_key(&store), Err(error::Token::Sealed));
+        assert_eq!(sealed.verify_sealed().unwrap().root_key(), None);
+    }
+
+    #[test]
+    fn third_party_blocks() {
+        use super::third_party::{ThirdPartyBlock, ThirdPartyRequest};
+        use crate::error::Signature;
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+        let idp = KeyPair::new(&mut rng);
+        let other = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file1", "read");
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        // the request and the block go through the network
+        let request = biscuit1.third_party_request().unwrap().to_vec().unwrap();
+        let request = ThirdPartyRequest::from_slice(&request).unwrap();
+        let mut block = request.create_block();
+        block.add_fact("user(#idp, \"alice\")").unwrap();
+        let signed = request.sign(&mut rng, &idp, block.build()).unwrap().to_vec().unwrap();
+        let signed = ThirdPartyBlock::from_slice(&signed).unwrap();
+        assert_eq!(signed.public_key(), idp.public());
+
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1.append_third_party(&mut rng, &keypair2, signed.clone()).unwrap();
+        let biscuit2 = Biscuit::from(&biscuit2.to_vec().unwrap()).unwrap();
+        assert_eq!(biscuit2.external_key(0), None);
+        assert_eq!(biscuit2.external_key(1), Some(idp.public()));
+
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.trust_third_party("idp", idp.public()).unwrap();
+        verifier.add_caveat("*user($u) <- user(#idp, $u) @ $u == \"alice\"").unwrap();
+        assert_eq!(verifier.verify(), Ok(None));
+
+        let verifier = biscuit2.verify(root.public()).unwrap();
+        assert_eq!(
+            verifier_trust(verifier, other.public()),
+            Err(error::Token::FailedLogic(Logic::UntrustedThirdPartyFact(
+                1,
+                "user(#idp, \"alice\")".to_string()
+            )))
+        );
+
+        // the holder cannot provide the facts of the third party
+        let mut block = biscuit2.create_block();
+        block.add_rule("*user(#idp, $u) <- name($u)").unwrap();
+        let keypair3 = KeyPair::new(&mut rng);
+        let biscuit3 = biscuit2.append(&mut rng, &keypair3, block.build()).unwrap();
+        let verifier = biscuit3.verify(root.public()).unwrap();
+        assert_eq!(
+            verifier_trust(verifier, idp.public()),
+            Err(error::Token::FailedLogic(Logic::UntrustedThirdPartyFact(
+                2,
+                "*user(#idp, $u) <- name($u)".to_string()
+            )))
+        );
+
+        // or use the tag elsewhere than in the first term
+        let mut block = biscuit2.create_block();
+        block.add_fact("right(#read, #idp)").unwrap();
+        let keypair3 = KeyPair::new(&mut rng);
+        let biscuit3 = biscuit2.append(&mut rng, &keypair3, block.build()).unwrap();
+        let verifier = biscuit3.verify(root.public()).unwrap();
+        assert_eq!(
+            verifier_trust(verifier, idp.public()),
+            Err(error::Token::FailedLogic(Logic::UntrustedThirdPartyFact(
+                2,
+                "right(#read, #idp)".to_string()
+            )))
+        );
+
+        // the signature covers the previous block
+        let other_token = Biscuit::builder(&root).build(&mut rng).unwrap();
+        assert_eq!(
+            other_token.append_third_party(&mut rng, &keypair2, signed).unwrap_err(),
+            error::Token::Format(Format::Signature(Signature::InvalidSignature))
+        );
+
+        // sealed tokens lose the external signatures
+        let sealed = Biscuit::from_sealed(&biscuit2.seal(b"secret").unwrap(), b"secret").unwrap();
+        assert_eq!(sealed.third_party_request().unwrap_err(), error::Token::Sealed);
+        let verifier = sealed.verify_sealed().unwrap();
+        assert!(verifier_trust(verifier, idp.public()).is_err());
+
+        fn verifier_trust(mut verifier: Verifier, key: PublicKey) -> Result<(), error::Token> {
+            verifier.trust_third_party("idp", key)
+        }
+    }
+
+    #[test]
+    fn clock() {
+        use super::clock::FixedClock;
+        use std::time::UNIX_EPOCH;
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+        let expiration = UNIX_EPOCH + Duration::from_secs(1_607_075_201);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file1", "read");
+        let biscuit1 = builder.build(&mut rng).unwrap();
+        let mut block2 = biscuit1.create_block();
+        block2.expiration_date(expiration);
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1.append(&mut rng, &keypair2, block2.build()).unwrap();
+
+        let before = FixedClock(expiration - Duration::from_secs(60));
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.set_clock(&before);
+        verifier.set_time();
+        assert_eq!(verifier.verify(), Ok(None));
+        assert_eq!(
+            verifier.query("*now($t) <- time(#ambient, $t)").unwrap(),
+            vec![fact("now", &[date(&before.0)])]
+        );
+
+        let expired = Err(Token::FailedLogic(Logic::FailedCaveats(vec![
+            FailedCaveat::Block(FailedBlockCaveat {
+                block_id: 1,
+                caveat_id: 0,
+                rule: String::from("*expiration($date) <- time(#ambient, $date) @ $date <= 2020-12-04T09:46:41+00:00"),
+            }),
+        ])));
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.set_time_at(expiration + Duration::from_secs(1));
+        assert_eq!(verifier.verify(), expired);
+
+        // the system clock is used by default
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        verifier.set_time();
+        assert_eq!(verifier.verify(), expired);
+    }
+
+    #[test]
+    fn verifier_policy() {
+        use super::verifier_policy::VerifierPolicy;
+        use std::sync::Arc;
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut policy = VerifierPolicy::new();
+        policy
+            .add_source(
+                r#"
+                admin("alice");
+                *readable($f) <- right(#authority, $f, #read);
+                check if *check($f) <- resource(#ambient, $f), readable($f);
+                deny if *denied($u) <- user(#authority, $u), !admin($u), resource(#ambient, "admin.txt");
+                allow if *allowed(#read) <- operation(#ambient, #read);
+                "#,
+            )
+            .unwrap();
+        assert_eq!(
+            policy.add_rule("*right(#authority, $f, #read) <- resource(#ambient, $f), !readable($f)"),
+            Err(Token::FailedLogic(Logic::UnstratifiableRules(String::from(
+                "*right(#authority, $f, #read) <- resource(#ambient, $f), !readable($f)"
+            ))))
+        );
+        assert_eq!(policy.rules().len(), 1);
+
+        // the policy is stored, then loaded for each request
+        let path = std::env::temp_dir().join(format!("biscuit-policy-{}.bin", std::process::id()));
+        std::fs::write(&path, policy.to_vec().unwrap()).unwrap();
+        let loaded = VerifierPolicy::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, policy);
+        assert!(VerifierPolicy::from_slice(&[0xff, 0xff]).is_err());
+
+        // the symbols must be in the table of the policy
+        let mut proto = crate::format::schema::VerifierPolicies::decode(&policy.to_vec().unwrap()[..]).unwrap();
+        proto.symbols.truncate(1);
+        let mut truncated = vec![];
+        proto.encode(&mut truncated).unwrap();
+        assert_eq!(
+            VerifierPolicy::from_slice(&truncated),
+            Err(Token::Format(Format::DeserializationError(String::from(
+                "deserialization error: invalid symbol index"
+            ))))
+        );
+
+        // and the rules are checked like when adding them
+        let add_rule = |source: &str| {
+            let mut proto = crate::format::schema::VerifierPolicies::decode(&policy.to_vec().unwrap()[..]).unwrap();
+            let mut symbols = SymbolTable::from(proto.symbols.clone());
+            let rule: builder::Rule = source.parse().unwrap();
+            proto.rules.push(crate::format::convert::token_rule_to_proto_rule(&rule.convert(&mut symbols)));
+            proto.symbols = symbols.symbols;
+            let mut v = vec![];
+            proto.encode(&mut v).unwrap();
+            VerifierPolicy::from_slice(&v)
+        };
+        assert_eq!(
+            add_rule("*owner($u, $f) <- user($u)"),
+            Err(Token::UnsafeRule(String::from("*owner($u, $f) <- user($u)")))
+        );
+        assert_eq!(
+            add_rule("*right(#authority, $f, #read) <- resource(#ambient, $f), !readable($f)"),
+            Err(Token::FailedLogic(Logic::UnstratifiableRules(String::from(
+                "*right(#authority, $f, #read) <- resource(#ambient, $f), !readable($f)"
+            ))))
+        );
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file1", "read");
+        builder.add_right("admin.txt", "read");
+        builder.add_authority_fact("user(#authority, \"bob\")").unwrap();
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        for (resource, result) in [
+            ("file1", Ok(Some(1))),
+            ("admin.txt", Err(Token::FailedLogic(Logic::Deny(0)))),
+        ].iter() {
+            let mut verifier = biscuit1.verify(root.public()).unwrap();
+            verifier.load_policy(&loaded).unwrap();
+            verifier.add_resource(resource);
+            verifier.add_operation("read");
+            assert_eq!(&verifier.verify(), result);
+        }
+
+        // or shared by the verifiers of all tokens, the token is converted
+        // to the symbols of the policy
+        let shared = Arc::new(loaded.clone());
+        for (resource, result) in [
+            ("file1", Ok(Some(1))),
+            ("admin.txt", Err(Token::FailedLogic(Logic::Deny(0)))),
+        ].iter() {
+            let mut verifier = biscuit1.verify_with_policy(root.public(), Arc::clone(&shared)).unwrap();
+            verifier.add_resource(resource);
+            verifier.add_operation("read");
+            assert_eq!(&verifier.verify(), result);
+        }
+
+        let mut verifier = biscuit1.verify(root.public()).unwrap();
+        verifier.load_policy(&loaded).unwrap();
+        verifier.add_resource("file2");
+        verifier.add_operation("read");
+        assert!(matches!(
+            verifier.verify(),
+            Err(Token::FailedLogic(Logic::FailedCaveats(_)))
+        ));
+
+        // the rules of the policy are checked with the rules of the token
+        let mut block2 = biscuit1.create_block();
+        block2.add_rule("*right(\"file3\", #read) <- resource(#ambient, \"file3\"), !readable(\"file3\")").unwrap();
+        let keypair2 = KeyPair::new(&mut rng);
+        let biscuit2 = biscuit1.append(&mut rng, &keypair2, block2.build()).unwrap();
+        let mut verifier = biscuit2.verify(root.public()).unwrap();
+        let before = verifier.dump();
+        assert_eq!(
+            verifier.load_policy(&loaded),
+            Err(Token::FailedLogic(Logic::UnstratifiableRules(String::from(
+                "*right(\"file3\", #read) <- resource(#ambient, \"file3\"), !readable(\"file3\")"
+            ))))
+        );
+        // nothing was loaded
+        assert_eq!(verifier.dump(), before);
+        assert_eq!(verifier.verify(), Ok(None));
+    }
+
+    #[test]
+    fn shared_verifier() {
+        use super::verifier_policy::VerifierPolicy;
+        use std::sync::Arc;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Biscuit>();
+        assert_send_sync::<Verifier>();
+        assert_send_sync::<VerifierPolicy>();
+        assert_send_sync::<builder::Policy>();
+        assert_send_sync::<builder::Caveat>();
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let root = KeyPair::new(&mut rng);
+
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file1", "read");
+        builder.add_right("file2", "read");
+        let biscuit1 = builder.build(&mut rng).unwrap();
+
+        let mut policy = VerifierPolicy::new();
+        policy
+            .add_source("allow if *allowed($f) <- resource(#ambient, $f), right(#authority, $f, #read);")
+            .unwrap();
+        let policy = Arc::new(policy);
+
+        // prepared once, then cloned for each request
+        let mut base = biscuit1.verify(root.public()).unwrap();
+        base.load_policy(&policy).unwrap();
+        base.add_operation("read");
+        base.snapshot();
+        let (facts, rules, _) = base.dump();
+
+        std::thread::scope(|scope| {
+            for &(resource, allowed) in [("file1", true), ("file2", true), ("file3", false)].iter() {
+                let mut verifier = base.clone();
+                scope.spawn(move || {
+                    verifier.add_resource(resource);
+                    assert_eq!(verifier.verify().is_ok(), allowed);
+                });
+            }
+        });
+
+        // the clones added their facts and symbols to their own local layer
+        assert_eq!(base.dump().0, facts);
+        assert_eq!(base.dump().1, rules);
+        base.add_resource("file1");
+        assert_eq!(base.verify(), Ok(Some(0)));
+        base.reset();
+        assert_eq!(base.dump().0, facts);
+
+        // the symbols of the tokens start with the ones of this policy, so
+        // the tokens are not converted
+        let mut policy = VerifierPolicy::new();
+        policy
+            .add_source("allow if *right(#read) <- resource(#ambient, \"file1\"), right(#authority, \"file1\", #read);")
+            .unwrap();
+        let policy = Arc::new(policy);
+        let mut builder = Biscuit::builder(&root);
+        builder.add_right("file2", "read");
+        let biscuit2 = builder.build(&mut rng).unwrap();
+        for (biscuit, allowed) in [(&biscuit1, true), (&biscuit2, false)].iter() {
+            let mut verifier = biscuit.verify_with_policy(root.public(), Arc::clone(&policy)).unwrap();
+            verifier.add_resource("file1");
+            verifier.add_operation("read");
+            assert_eq!(verifier.verify().is_ok(), *allowed);
+        }
+        assert_eq!(Arc::strong_count(&policy), 1);
+    }
 }
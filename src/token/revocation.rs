@@ -0,0 +1,194 @@
+//! revocation lists consulted by the verifier
+//!
+//! every block has a unique identifier derived from its signature (see
+//! `Biscuit::revocation_identifiers`), and can carry other identifiers, like
+//! the `revocation_id(1234)` facts added by `BlockBuilder::revocation_id`.
+//! A `RevocationStore` holds the revoked identifiers, and is shared by
+//! reference between verifiers with `Verifier::set_revocation_store`, so
+//! a large list is not copied for every request
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// identifier that can be revoked
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RevocationId {
+    /// set by the token creator with `BlockBuilder::revocation_id`
+    Int(i64),
+    /// derived from the signature of the block, written as `hex:...`
+    Unique(Vec<u8>),
+}
+
+impl fmt::Display for RevocationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RevocationId::Int(i) => write!(f, "{}", i),
+            RevocationId::Unique(id) => write!(f, "hex:{}", hex::encode(id)),
+        }
+    }
+}
+
+impl FromStr for RevocationId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id = match s.strip_prefix("hex:") {
+            Some(id) => hex::decode(id).ok().map(RevocationId::Unique),
+            None => s.parse::<i64>().ok().map(RevocationId::Int),
+        };
+        id.ok_or_else(|| format!("invalid revocation identifier: {}", s))
+    }
+}
+
+/// list of revoked identifiers
+///
+/// it is shared between verifiers, possibly in multiple threads
+pub trait RevocationStore: Send + Sync {
+    fn is_revoked(&self, id: &RevocationId) -> bool;
+}
+
+/// revocation list held in memory
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRevocationStore {
+    ids: HashSet<RevocationId>,
+}
+
+impl MemoryRevocationStore {
+    pub fn new() -> Self {
+        MemoryRevocationStore::default()
+    }
+
+    pub fn revoke(&mut self, id: RevocationId) {
+        self.ids.insert(id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl std::iter::FromIterator<RevocationId> for MemoryRevocationStore {
+    fn from_iter<I: IntoIterator<Item = RevocationId>>(iter: I) -> Self {
+        MemoryRevocationStore {
+            ids: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl RevocationStore for MemoryRevocationStore {
+    fn is_revoked(&self, id: &RevocationId) -> bool {
+        self.ids.contains(id)
+    }
+}
+
+/// revocation list stored in a text file, with one identifier per line
+///
+/// empty lines and lines starting with `#` are ignored. The file is read
+/// when opening the store and in `reload`, and `revoke` appends to it,
+/// creating it if it was removed
+#[derive(Debug)]
+pub struct FileRevocationStore {
+    path: PathBuf,
+    ids: MemoryRevocationStore,
+}
+
+impl FileRevocationStore {
+    /// reads the revocation list, returns an error of kind `NotFound` if
+    /// the file does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut store = FileRevocationStore {
+            path: path.as_ref().to_path_buf(),
+            ids: MemoryRevocationStore::new(),
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// reads the file again, to get the identifiers written by other processes
+    pub fn reload(&mut self) -> io::Result<()> {
+        let file = File::open(&self.path)?;
+
+        let mut ids = MemoryRevocationStore::new();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let id = line.parse().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", index + 1, e))
+            })?;
+            ids.revoke(id);
+        }
+
+        self.ids = ids;
+        Ok(())
+    }
+
+    /// adds an identifier to the list and to the file
+    pub fn revoke(&mut self, id: RevocationId) -> io::Result<()> {
+        let mut file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        writeln!(file, "{}", id)?;
+        self.ids.revoke(id);
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl RevocationStore for FileRevocationStore {
+    fn is_revoked(&self, id: &RevocationId) -> bool {
+        self.ids.is_revoked(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store() {
+        let path = std::env::temp_dir().join(format!("biscuit-revocation-{}.txt", std::process::id()));
+        std::fs::write(&path, "# revoked tokens\n1234\n\n-5\nhex:0aff\n").unwrap();
+
+        let mut store = FileRevocationStore::open(&path).unwrap();
+        assert!(store.is_revoked(&RevocationId::Int(1234)));
+        assert!(store.is_revoked(&RevocationId::Unique(vec![10, 255])));
+        assert!(store.is_revoked(&RevocationId::Int(-5)));
+        assert!(!store.is_revoked(&RevocationId::Int(5678)));
+
+        store.revoke(RevocationId::Int(5678)).unwrap();
+        store.revoke(RevocationId::Unique(vec![1, 2])).unwrap();
+        assert!(store.is_revoked(&RevocationId::Int(5678)));
+        let reopened = FileRevocationStore::open(&path).unwrap();
+        assert!(reopened.is_revoked(&RevocationId::Int(5678)));
+        assert!(reopened.is_revoked(&RevocationId::Unique(vec![1, 2])));
+
+        std::fs::write(&path, "1234\nhex:abc\n").unwrap();
+        let error = store.reload().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "line 2: invalid revocation identifier: hex:abc");
+        // the previous list is kept when the file is invalid
+        assert!(store.is_revoked(&RevocationId::Int(5678)));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(store.reload().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(FileRevocationStore::open(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(!path.exists());
+        // the file is created again when revoking an identifier
+        store.revoke(RevocationId::Int(42)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "42\n");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+//! blocks signed by a third party
+//!
+//! the holder of a token creates a `ThirdPartyRequest` with
+//! `Biscuit::third_party_request` and sends it to the third party, like an
+//! identity provider. The third party creates a block with `create_block`,
+//! signs it with `sign`, and sends the `ThirdPartyBlock` back to the holder,
+//! that adds it with `Biscuit::append_third_party`.
+//!
+//! the signature covers the unique identifier of the last block, so the
+//! block can only be appended to the token it was requested for. A verifier
+//! trusts the facts of a third party block with `Verifier::trust_third_party`
+use super::builder::BlockBuilder;
+use super::Block;
+use crate::crypto::{KeyPair, PublicKey};
+use crate::datalog::SymbolTable;
+use crate::error;
+use crate::format::{
+    convert::{proto_block_to_token_block, proto_sig_to_token_sig, token_block_to_proto_block, token_sig_to_proto_sig},
+    proto_key_to_public_key, schema, ExternalSignature,
+};
+use prost::Message;
+use rand_core::{CryptoRng, RngCore};
+use std::collections::HashSet;
+
+/// what a third party needs to create a block for a token
+#[derive(Clone, Debug)]
+pub struct ThirdPartyRequest {
+    pub(crate) previous_id: Vec<u8>,
+    pub(crate) index: u32,
+    pub(crate) symbols: SymbolTable,
+}
+
+impl ThirdPartyRequest {
+    /// creates a block builder with the index and symbols of the token
+    pub fn create_block(&self) -> BlockBuilder {
+        BlockBuilder::new(self.index, self.symbols.clone())
+    }
+
+    /// signs a block created with `create_block`
+    pub fn sign<T: RngCore + CryptoRng>(
+        &self,
+        rng: &mut T,
+        keypair: &KeyPair,
+        block: Block,
+    ) -> Result<ThirdPartyBlock, error::Token> {
+        if block.index != self.index {
+            return Err(error::Token::InvalidBlockIndex(error::InvalidBlockIndex {
+                expected: self.index,
+                found: block.index,
+            }));
+        }
+
+        let h1 = self.symbols.symbols.iter().collect::<HashSet<_>>();
+        let h2 = block.symbols.symbols.iter().collect::<HashSet<_>>();
+        if !h1.is_disjoint(&h2) {
+            return Err(error::Token::SymbolTableOverlap);
+        }
+
+        let mut payload = Vec::new();
+        token_block_to_proto_block(&block).encode(&mut payload).map_err(|e| {
+            error::Token::Format(error::Format::BlockSerializationError(format!(
+                "error serializing block: {:?}",
+                e
+            )))
+        })?;
+
+        let external = ExternalSignature::new(rng, keypair, &self.previous_id, &payload);
+        Ok(ThirdPartyBlock { payload, external })
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, error::Token> {
+        let proto = schema::ThirdPartyBlockRequest {
+            previous_id: self.previous_id.clone(),
+            index: self.index,
+            symbols: self.symbols.symbols.clone(),
+        };
+
+        let mut v = Vec::new();
+        proto.encode(&mut v).map(|_| v).map_err(|e| {
+            error::Token::Format(error::Format::SerializationError(format!(
+                "serialization error: {:?}",
+                e
+            )))
+        })
+    }
+
+    pub fn from_slice(slice: &[u8]) -> Result<Self, error::Token> {
+        let proto = schema::ThirdPartyBlockRequest::decode(slice).map_err(|e| {
+            error::Token::Format(error::Format::DeserializationError(format!(
+                "deserialization error: {:?}",
+                e
+            )))
+        })?;
+
+        Ok(ThirdPartyRequest {
+            previous_id: proto.previous_id,
+            index: proto.index,
+            symbols: SymbolTable::from(proto.symbols),
+        })
+    }
+}
+
+/// block signed by a third party, to append to the token it was requested for
+#[derive(Clone, Debug)]
+pub struct ThirdPartyBlock {
+    pub(crate) payload: Vec<u8>,
+    pub(crate) external: ExternalSignature,
+}
+
+impl ThirdPartyBlock {
+    /// public key of the third party
+    pub fn public_key(&self) -> PublicKey {
+        self.external.public_key
+    }
+
+    /// deserializes the block
+    pub fn block(&self) -> Result<Block, error::Token> {
+        schema::Block::decode(&self.payload[..])
+            .map_err(|e| {
+                error::Token::Format(error::Format::BlockDeserializationError(format!(
+                    "error deserializing block: {:?}",
+                    e
+                )))
+            })
+            .and_then(|b| proto_block_to_token_block(&b).map_err(error::Token::Format))
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, error::Token> {
+        let proto = schema::ThirdPartyBlockContents {
+            payload: self.payload.clone(),
+            public_key: self.external.public_key.to_bytes().to_vec(),
+            signature: token_sig_to_proto_sig(&self.external.signature),
+        };
+
+        let mut v = Vec::new();
+        proto.encode(&mut v).map(|_| v).map_err(|e| {
+            error::Token::Format(error::Format::SerializationError(format!(
+                "serialization error: {:?}",
+                e
+            )))
+        })
+    }
+
+    pub fn from_slice(slice: &[u8]) -> Result<Self, error::Token> {
+        let proto = schema::ThirdPartyBlockContents::decode(slice).map_err(|e| {
+            error::Token::Format(error::Format::DeserializationError(format!(
+                "deserialization error: {:?}",
+                e
+            )))
+        })?;
+
+        Ok(ThirdPartyBlock {
+            payload: proto.payload,
+            external: ExternalSignature {
+                public_key: proto_key_to_public_key(&proto.public_key).map_err(error::Token::Format)?,
+                signature: proto_sig_to_token_sig(proto.signature).map_err(error::Token::Format)?,
+            },
+        })
+    }
+}
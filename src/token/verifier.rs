@@ -1,68 +1,216 @@
 use super::builder::{
-    constrained_rule, date, fact, pred, s, string, Constraint, ConstraintKind, Fact,
-    IntConstraint, Rule, Caveat, var,
+    check_parameters, check_safety, parse_checked_source, date, fact, s, string, Atom, Fact,
+    Policy, PolicyKind, Predicate, Rule, Caveat,
 };
+use super::clock::{Clock, SystemClock};
+use super::revocation::{MemoryRevocationStore, RevocationId, RevocationStore};
+use super::verifier_policy::VerifierPolicy;
 use super::Biscuit;
+use crate::crypto::PublicKey;
 use crate::datalog;
 use crate::error;
-use std::{convert::TryInto, time::SystemTime};
+use crate::parser::Statement;
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::TryInto,
+    fmt,
+    sync::Arc,
+    time::SystemTime,
+};
+
+/// facts visible to the caveats of a block
+///
+/// the caveats of the verifier and of the authority block, the policies and
+/// the queries use the facts of the authority block, of the verifier, and of
+/// the blocks trusted with `Verifier::trust_third_party`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FactScope {
+    /// the facts of the authority block, of the verifier and of the block
+    /// itself: a block can only restrict the rights of the token
+    Block,
+    /// the facts of every block, the behavior of previous versions,
+    /// where the rules of a block can make the caveats of another block pass
+    Shared,
+}
 
+/// verifies a token
+///
+/// the facts and rules of the token, of the policy (see
+/// `Biscuit::verify_with_policy`) and of the verifier are kept in separate
+/// worlds, that the rules run over without copying them. The worlds and
+/// symbol tables of the token, of the policy and of the last snapshot are
+/// shared through `Arc` between the verifier and its clones, the facts,
+/// rules and symbols added after that being kept in a small local layer
+/// over them. A verifier prepared once for a token can be cloned cheaply
+/// for each request, possibly in other threads
+#[derive(Clone)]
 pub struct Verifier<'a> {
     token: &'a Biscuit,
-    base_world: datalog::World,
-    base_symbols: datalog::SymbolTable,
+    /// facts and rules of the token, with the symbols of the verifier
+    token_world: Arc<datalog::World>,
+    /// caveats of each block of the token, with the symbols of the verifier
+    token_caveats: Arc<Vec<Vec<datalog::Caveat>>>,
+    policy: Option<Arc<VerifierPolicy>>,
+    /// facts and rules added to the verifier before the last snapshot
+    base_world: Arc<datalog::World>,
+    /// symbols of the policy and of the token, and the ones added
+    /// to the verifier before the last snapshot
+    base_symbols: Arc<datalog::SymbolTable>,
+    /// facts and rules added since the last snapshot
     world: datalog::World,
+    /// symbols added since the last snapshot, extending `base_symbols`
     symbols: datalog::SymbolTable,
     caveats: Vec<Caveat>,
+    policies: Vec<Policy>,
+    limits: datalog::RunLimits,
+    revocation: Option<&'a dyn RevocationStore>,
+    /// ids revoked with `revocation_check`
+    revoked: MemoryRevocationStore,
+    clock: &'a dyn Clock,
+    fact_scope: FactScope,
+    /// blocks signed by a trusted third party
+    trusted_blocks: BTreeSet<usize>,
+    pub(crate) root_key: Option<PublicKey>,
 }
 
 impl<'a> Verifier<'a> {
     pub(crate) fn new(token: &'a Biscuit) -> Result<Self, error::Logic> {
-        let base_world = token.generate_world(&token.symbols)?;
-        let base_symbols = token.symbols.clone();
-        let world = base_world.clone();
-        let symbols = token.symbols.clone();
+        Verifier::with_policy(token, None)
+    }
+
+    /// the symbols of the policy are kept, so its world can be shared: the
+    /// token is converted to a table extending the one of the policy if it
+    /// was created with other symbols
+    pub(crate) fn with_policy(
+        token: &'a Biscuit,
+        policy: Option<Arc<VerifierPolicy>>,
+    ) -> Result<Self, error::Logic> {
+        let (symbols, map) = match policy.as_ref() {
+            Some(policy) => {
+                let mut symbols = datalog::SymbolTable::with_base(Arc::clone(policy.symbols()));
+                let map = datalog::SymbolMap::new(&token.symbols, &mut symbols);
+                (symbols, Some(map).filter(|map| !map.is_identity()))
+            }
+            None => (token.symbols.clone(), None),
+        };
+        let token_world = token.generate_world(&token.symbols, map.as_ref())?;
+        let token_caveats = token.caveats(map.as_ref());
+
+        if let Some(policy) = policy.as_ref() {
+            let rules = policy
+                .world()
+                .rules
+                .iter()
+                .chain(token_world.rules.iter())
+                .collect::<Vec<_>>();
+            if let Err(i) = datalog::stratify(&rules) {
+                return Err(error::Logic::UnstratifiableRules(symbols.print_rule(rules[i])));
+            }
+        }
+
+        let base_symbols = Arc::new(symbols);
+        let symbols = datalog::SymbolTable::with_base(Arc::clone(&base_symbols));
 
         Ok(Verifier {
             token,
-            base_world,
+            token_world: Arc::new(token_world),
+            token_caveats: Arc::new(token_caveats),
+            policy,
+            base_world: Arc::new(datalog::World::new()),
             base_symbols,
-            world,
+            world: datalog::World::new(),
             symbols,
             caveats: vec![],
+            policies: vec![],
+            limits: datalog::RunLimits::default(),
+            revocation: None,
+            revoked: MemoryRevocationStore::new(),
+            clock: &SystemClock,
+            fact_scope: FactScope::Block,
+            trusted_blocks: BTreeSet::new(),
+            root_key: None,
         })
     }
 
+    /// root key that created the token, None for sealed tokens
+    pub fn root_key(&self) -> Option<PublicKey> {
+        self.root_key
+    }
+
     pub fn reset(&mut self) {
         self.caveats.clear();
-        self.world = self.base_world.clone();
-        self.symbols = self.base_symbols.clone();
+        self.policies.clear();
+        self.world = datalog::World::new();
+        self.symbols = datalog::SymbolTable::with_base(Arc::clone(&self.base_symbols));
     }
 
+    /// keeps the facts, rules and symbols added until now after a `reset`
+    ///
+    /// they are moved to new shared tables, the local layers starting empty
     pub fn snapshot(&mut self) {
-        self.base_world = self.world.clone();
-        self.base_symbols = self.symbols.clone();
+        let world = std::mem::take(&mut self.world);
+        if !world.facts.is_empty() || !world.rules.is_empty() {
+            let mut base_world = datalog::World::clone(&self.base_world);
+            for fact in world.facts.iter() {
+                base_world.add_fact(fact.clone());
+            }
+            base_world.rules.extend(world.rules);
+            self.base_world = Arc::new(base_world);
+        }
+
+        if !self.symbols.symbols.is_empty() {
+            self.base_symbols = Arc::new(std::mem::take(&mut self.symbols));
+        }
+        self.symbols = datalog::SymbolTable::with_base(Arc::clone(&self.base_symbols));
     }
 
-    pub fn add_fact<F: TryInto<Fact>>(&mut self, fact: F) -> Result<(), error::Token> {
-        let fact = fact.try_into().map_err(|_| error::Token::ParseError)?;
-        self.world.facts.insert(fact.convert(&mut self.symbols));
+    pub fn add_fact<F: TryInto<Fact>>(&mut self, fact: F) -> Result<(), error::Token>
+    where
+        F::Error: Into<error::Token>,
+    {
+        let fact = fact.try_into().map_err(Into::into)?;
+        check_parameters(fact.parameters())?;
+        let fact = fact.convert(&mut self.symbols);
+        self.world.add_fact(fact);
         Ok(())
     }
 
-    pub fn add_rule<R: TryInto<Rule>>(&mut self, rule: R) -> Result<(), error::Token> {
-        let rule = rule.try_into().map_err(|_| error::Token::ParseError)?;
-        self.world.rules.push(rule.convert(&mut self.symbols));
+    pub fn add_rule<R: TryInto<Rule>>(&mut self, rule: R) -> Result<(), error::Token>
+    where
+        R::Error: Into<error::Token>,
+    {
+        let rule = rule.try_into().map_err(Into::into)?;
+        check_parameters(rule.parameters())?;
+        let rule = rule.convert(&mut self.symbols);
+        check_safety(&rule, &self.symbols)?;
+
+        let mut rules = self.rules();
+        rules.push(&rule);
+        if datalog::stratify(&rules).is_err() {
+            // report the new rule, since the other ones were accepted
+            return Err(error::Token::FailedLogic(
+                error::Logic::UnstratifiableRules(self.symbols.print_rule(&rule)),
+            ));
+        }
+
+        self.world.add_rule(rule);
         Ok(())
     }
 
     pub fn query<R: TryInto<Rule>>(
         &mut self,
         rule: R,
-    ) -> Result<Vec<Fact>, error::Token> {
-        let rule = rule.try_into().map_err(|_| error::Token::ParseError)?;
-        self.world.run();
-        let mut res = self.world.query_rule(rule.convert(&mut self.symbols));
+    ) -> Result<Vec<Fact>, error::Token>
+    where
+        R::Error: Into<error::Token>,
+    {
+        let rule = rule.try_into().map_err(Into::into)?;
+        check_parameters(rule.parameters())?;
+        let query = rule.convert(&mut self.symbols);
+        check_safety(&query, &self.symbols)?;
+        let scope = self.scope(None);
+        let world = self.run_world(scope.as_ref(), false, &mut datalog::RunBudget::new(self.limits))?;
+        let mut res = world.query_rule(query);
 
         Ok(res
            .drain(..)
@@ -70,108 +218,754 @@ impl<'a> Verifier<'a> {
            .collect())
     }
 
+    /// runs a query, and explains how each result was generated
+    ///
+    /// this records the provenance of every generated fact, so it is
+    /// slower than `query`
+    pub fn query_derivations<R: TryInto<Rule>>(
+        &mut self,
+        rule: R,
+    ) -> Result<Vec<Derivation>, error::Token>
+    where
+        R::Error: Into<error::Token>,
+    {
+        let rule = rule.try_into().map_err(Into::into)?;
+        check_parameters(rule.parameters())?;
+        let query = rule.convert(&mut self.symbols);
+        check_safety(&query, &self.symbols)?;
+        let scope = self.scope(None);
+        let world = self.run_world(scope.as_ref(), true, &mut datalog::RunBudget::new(self.limits))?;
+
+        Ok(world
+            .query_rule_premises(&query)
+            .iter()
+            .map(|(fact, premises)| self.query_derivation(&world, &query, fact, premises))
+            .collect())
+    }
+
     /// verifier caveats
-    pub fn add_caveat<R: TryInto<Caveat>>(&mut self, caveat: R) -> Result<(), error::Token> {
-        let caveat = caveat.try_into().map_err(|_| error::Token::ParseError)?;
+    pub fn add_caveat<R: TryInto<Caveat>>(&mut self, caveat: R) -> Result<(), error::Token>
+    where
+        R::Error: Into<error::Token>,
+    {
+        let caveat = caveat.try_into().map_err(Into::into)?;
+        check_parameters(caveat.parameters())?;
+        for query in caveat.convert(&mut self.symbols).queries.iter() {
+            check_safety(query, &self.symbols)?;
+        }
         self.caveats.push(caveat);
         Ok(())
     }
 
+    /// adds a policy, like `allow if *allowed(#admin) <- right(#authority, #admin)`
+    ///
+    /// policies are tested in order after the caveats, and the
+    /// first one that matches decides if the token is accepted
+    pub fn add_policy<P: TryInto<Policy>>(&mut self, policy: P) -> Result<(), error::Token>
+    where
+        P::Error: Into<error::Token>,
+    {
+        let policy = policy.try_into().map_err(Into::into)?;
+        check_parameters(policy.parameters())?;
+        for query in policy.queries.iter() {
+            check_safety(&query.convert(&mut self.symbols), &self.symbols)?;
+        }
+        self.policies.push(policy);
+        Ok(())
+    }
+
+    /// adds the facts, rules, caveats and policies of a Datalog source text
+    ///
+    /// the source is parsed and its rules are checked before anything is added,
+    /// but if a rule cannot be stratified, the statements before it are kept
+    pub fn add_source(&mut self, source: &str) -> Result<(), error::Token> {
+        for statement in parse_checked_source(source, &self.symbols, true)? {
+            match statement {
+                Statement::Fact(fact) => self.add_fact(fact)?,
+                Statement::Rule(rule) => self.add_rule(rule)?,
+                Statement::Caveat(caveat) => self.add_caveat(caveat)?,
+                Statement::Policy(policy) => self.add_policy(policy)?,
+                // rejected by parse_checked_source
+                Statement::Context(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// adds the facts, rules, caveats and policies of a `VerifierPolicy`
+    ///
+    /// they were checked when building the policy, so only the stratification
+    /// of its rules with the rules of the token is checked here. Nothing is
+    /// added if it fails
+    pub fn load_policy(&mut self, policy: &VerifierPolicy) -> Result<(), error::Token> {
+        let mut symbols = self.symbols.clone();
+        let facts = policy
+            .facts()
+            .iter()
+            .map(|f| f.convert(&mut symbols))
+            .collect::<Vec<_>>();
+
+        let new_rules = policy
+            .rules()
+            .iter()
+            .map(|r| r.convert(&mut symbols))
+            .collect::<Vec<_>>();
+        let mut rules = self.rules();
+        rules.extend(new_rules.iter());
+        if let Err(i) = datalog::stratify(&rules) {
+            return Err(error::Token::FailedLogic(error::Logic::UnstratifiableRules(
+                symbols.print_rule(rules[i]),
+            )));
+        }
+
+        // the caveats and policies are converted in `verify`, this only adds their symbols
+        for caveat in policy.caveats() {
+            caveat.convert(&mut symbols);
+        }
+        for p in policy.policies() {
+            for query in p.queries.iter() {
+                query.convert(&mut symbols);
+            }
+        }
+
+        self.symbols = symbols;
+        for fact in facts {
+            self.world.add_fact(fact);
+        }
+        self.world.rules.extend(new_rules);
+        self.caveats.extend(policy.caveats().iter().cloned());
+        self.policies.extend(policy.policies().iter().cloned());
+        Ok(())
+    }
+
     pub fn add_resource(&mut self, resource: &str) {
         let fact = fact("resource", &[s("ambient"), string(resource)]);
-        self.world.facts.insert(fact.convert(&mut self.symbols));
+        let fact = fact.convert(&mut self.symbols);
+        self.world.add_fact(fact);
     }
 
     pub fn add_operation(&mut self, operation: &str) {
         let fact = fact("operation", &[s("ambient"), s(operation)]);
-        self.world.facts.insert(fact.convert(&mut self.symbols));
+        let fact = fact.convert(&mut self.symbols);
+        self.world.add_fact(fact);
     }
 
+    /// adds the `time(#ambient, ...)` fact with the current time of the clock
     pub fn set_time(&mut self) {
-        let fact = fact("time", &[s("ambient"), date(&SystemTime::now())]);
-        self.world.facts.insert(fact.convert(&mut self.symbols));
+        let now = self.clock.now();
+        self.set_time_at(now);
     }
 
+    /// adds the `time(#ambient, ...)` fact with this time, to verify a request
+    /// as it was at that moment
+    pub fn set_time_at(&mut self, time: SystemTime) {
+        let fact = fact("time", &[s("ambient"), date(&time)]);
+        let fact = fact.convert(&mut self.symbols);
+        self.world.add_fact(fact);
+    }
+
+    /// sets the clock used by `set_time`, the system clock by default
+    pub fn set_clock(&mut self, clock: &'a dyn Clock) {
+        self.clock = clock;
+    }
+
+    /// rejects the tokens where a block has one of these revocation ids
+    ///
+    /// the ids are checked in `verify` before running the rules, and a revoked
+    /// token fails with `Logic::Revoked` and the index of the block. No caveat
+    /// is added. `set_revocation_store` avoids copying a large list in every
+    /// verifier
     pub fn revocation_check(&mut self, ids: &[i64]) {
-        let caveat = constrained_rule(
-            "revocation_check",
-            &[var("id")],
-            &[pred("revocation_id", &[var("id")])],
-            &[Constraint {
-                id: "id".to_string(),
-                kind: ConstraintKind::Integer(IntConstraint::NotIn(ids.iter().cloned().collect())),
-            }],
-        );
-        let _ = self.add_caveat(caveat);
-    }
-
-    pub fn verify(&mut self) -> Result<(), error::Token> {
+        for id in ids {
+            self.revoked.revoke(RevocationId::Int(*id));
+        }
+    }
+
+    /// accepts the facts containing `#tag` only from the blocks signed
+    /// by `key`, see `Biscuit::append_third_party`
+    ///
+    /// the facts and rule heads of the other blocks cannot contain this
+    /// tag, the error giving the index of the block (1 for the first block
+    /// after the authority block). The authority block and the verifier are always trusted. The
+    /// facts of the blocks signed by `key` become visible to the verifier
+    /// (see `FactScope`)
+    pub fn trust_third_party(&mut self, tag: &str, key: PublicKey) -> Result<(), error::Token> {
+        let signed = (1..=self.token.blocks.len())
+            .filter(|i| self.token.external_key(*i) == Some(key))
+            .collect::<Vec<_>>();
+
+        let tag = match self.token.symbols.get(tag) {
+            Some(tag) => datalog::ID::Symbol(tag),
+            // the blocks cannot use the tag
+            None => {
+                self.trusted_blocks.extend(signed);
+                return Ok(());
+            }
+        };
+
+        for (i, block) in self.token.blocks.iter().enumerate() {
+            if signed.contains(&(i + 1)) {
+                continue;
+            }
+
+            if let Some(fact) = block.facts.iter().find(|f| f.predicate.ids.contains(&tag)) {
+                return Err(error::Token::FailedLogic(error::Logic::UntrustedThirdPartyFact(
+                    (i + 1) as u32,
+                    self.token.symbols.print_fact(fact),
+                )));
+            }
+            if let Some(rule) = block.rules.iter().find(|r| r.head.ids.contains(&tag)) {
+                return Err(error::Token::FailedLogic(error::Logic::UntrustedThirdPartyFact(
+                    (i + 1) as u32,
+                    self.token.symbols.print_rule(rule),
+                )));
+            }
+        }
+
+        self.trusted_blocks.extend(signed);
+        Ok(())
+    }
+
+    /// chooses the facts visible to the caveats of each block, the default
+    /// being `FactScope::Block`
+    pub fn set_fact_scope(&mut self, scope: FactScope) {
+        self.fact_scope = scope;
+    }
+
+    /// checks the revocation identifiers of every block with this store in `verify`
+    pub fn set_revocation_store(&mut self, store: &'a dyn RevocationStore) {
+        self.revocation = Some(store);
+    }
+
+    /// sets the limits applied when running the Datalog rules
+    /// in `query` and `verify`
+    pub fn set_limits(&mut self, limits: datalog::RunLimits) {
+        self.limits = limits;
+    }
+
+    /// checks the caveats, then the policies
+    ///
+    /// this returns the index of the allow policy that matched, or None if
+    /// the verifier has no policies. A matching deny policy, or no matching
+    /// policy at all, are reported as errors
+    pub fn verify(&mut self) -> Result<Option<usize>, error::Token> {
         //FIXME: should check for the presence of any other symbol in the token
         if self.symbols.get("authority").is_none() || self.symbols.get("ambient").is_none() {
             return Err(error::Token::MissingSymbols);
         }
 
-        self.world.run();
+        if self.revocation.is_some() || !self.revoked.is_empty() {
+            for (block_id, id) in self.revocation_ids() {
+                let revoked = self.revoked.is_revoked(&id)
+                    || self.revocation.map(|store| store.is_revoked(&id)).unwrap_or(false);
+                if revoked {
+                    return Err(error::Token::FailedLogic(error::Logic::Revoked(block_id)));
+                }
+            }
+        }
+
+        let caveats = self.all_caveats();
+        let policies = self.all_policies();
+        let scopes = self.caveat_scopes(&caveats);
+        let worlds = self.run_caveat_worlds(&scopes, false)?;
 
         let mut errors = vec![];
-        for (i, caveat) in self.caveats.iter().enumerate() {
-            let c = caveat.convert(&mut self.symbols);
-            let mut successful = false;
+        for (block_id, caveat_id, caveat) in caveats {
+            let world = &worlds[&self.scope(block_id)];
+            let successful = caveat
+                .queries
+                .iter()
+                .any(|query| !world.query_rule(query.clone()).is_empty());
+
+            if !successful {
+                let rule = self.symbols.print_caveat(&caveat);
+                errors.push(match block_id {
+                    None => error::FailedCaveat::Verifier(error::FailedVerifierCaveat {
+                        caveat_id,
+                        rule,
+                    }),
+                    Some(block_id) => error::FailedCaveat::Block(error::FailedBlockCaveat {
+                        block_id,
+                        caveat_id,
+                        rule,
+                    }),
+                });
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(error::Token::FailedLogic(error::Logic::FailedCaveats(
+                errors,
+            )));
+        }
+
+        if policies.is_empty() {
+            return Ok(None);
+        }
+
+        let world = &worlds[&self.scope(None)];
+        for (i, (kind, queries)) in policies.into_iter().enumerate() {
+            let matched = queries
+                .into_iter()
+                .any(|query| !world.query_rule(query).is_empty());
+
+            if matched {
+                return match kind {
+                    PolicyKind::Allow => Ok(Some(i)),
+                    PolicyKind::Deny => Err(error::Token::FailedLogic(error::Logic::Deny(i))),
+                };
+            }
+        }
+
+        Err(error::Token::FailedLogic(error::Logic::NoMatchingPolicy))
+    }
 
+    /// explains the caveats that succeed
+    ///
+    /// for each of them, this gives the derivations of the facts generated
+    /// by its first successful query. Like `query_derivations`, this is
+    /// slower than `verify`
+    pub fn explain_caveats(&mut self) -> Result<Vec<CaveatDerivation>, error::Token> {
+        let caveats = self.all_caveats();
+        let scopes = self.caveat_scopes(&caveats);
+        let worlds = self.run_caveat_worlds(&scopes, true)?;
+
+        let mut explanations = vec![];
+        for (block_id, caveat_id, caveat) in caveats {
+            let world = &worlds[&self.scope(block_id)];
             for query in caveat.queries.iter() {
-                let res = self.world.query_rule(query.convert(&mut self.symbols));
+                let res = world.query_rule_premises(query);
                 if !res.is_empty() {
-                    successful = true;
+                    explanations.push(CaveatDerivation {
+                        block_id,
+                        caveat_id,
+                        rule: self.symbols.print_caveat(&caveat),
+                        derivations: res
+                            .iter()
+                            .map(|(fact, premises)| {
+                                self.query_derivation(world, query, fact, premises)
+                            })
+                            .collect(),
+                    });
                     break;
                 }
             }
+        }
 
-            if !successful {
-                errors.push(error::FailedCaveat::Verifier(error::FailedVerifierCaveat {
-                    caveat_id: i as u32,
-                    rule: self.symbols.print_caveat(&c),
-                }));
+        Ok(explanations)
+    }
+
+    /// explains why caveats fail
+    ///
+    /// for each failed caveat, and each of its queries, this finds the first
+    /// body predicate that could not be matched, and the facts that came
+    /// closest to matching it
+    pub fn diagnose_caveats(&mut self) -> Result<Vec<CaveatDiagnostic>, error::Token> {
+        let caveats = self.all_caveats();
+        let scopes = self.caveat_scopes(&caveats);
+        let worlds = self.run_caveat_worlds(&scopes, false)?;
+
+        let mut diagnostics = vec![];
+        for (block_id, caveat_id, caveat) in caveats {
+            let world = &worlds[&self.scope(block_id)];
+            let mut queries = vec![];
+            for query in caveat.queries.iter() {
+                match query.diagnose(&world.facts()) {
+                    None => break,
+                    Some(failure) => queries.push(QueryDiagnostic {
+                        rule: Rule::convert_from(query, &self.symbols),
+                        failure: QueryFailure::convert_from(&failure, query, &self.symbols),
+                    }),
+                }
+            }
+
+            // the caveat succeeds if one of its queries succeeds
+            if queries.len() < caveat.queries.len() {
+                continue;
             }
+
+            let rule = self.symbols.print_caveat(&caveat);
+            let caveat = match block_id {
+                None => error::FailedCaveat::Verifier(error::FailedVerifierCaveat {
+                    caveat_id,
+                    rule,
+                }),
+                Some(block_id) => error::FailedCaveat::Block(error::FailedBlockCaveat {
+                    block_id,
+                    caveat_id,
+                    rule,
+                }),
+            };
+            diagnostics.push(CaveatDiagnostic { caveat, queries });
         }
 
-        for (i, block_caveats) in self.token.caveats().iter().enumerate() {
+        Ok(diagnostics)
+    }
+
+    /// verifier caveats, starting with the ones of the policy, then block
+    /// caveats, with their block index (None for the verifier) and their
+    /// index in the block
+    fn all_caveats(&mut self) -> Vec<(Option<u32>, u32, datalog::Caveat)> {
+        let mut caveats = vec![];
+
+        let verifier_caveats = self
+            .policy
+            .iter()
+            .flat_map(|policy| policy.caveats().iter())
+            .chain(self.caveats.iter());
+        for (i, caveat) in verifier_caveats.enumerate() {
+            caveats.push((None, i as u32, caveat.convert(&mut self.symbols)));
+        }
+
+        for (i, block_caveats) in self.token_caveats.iter().enumerate() {
             for (j, caveat) in block_caveats.iter().enumerate() {
-                let mut successful = false;
+                caveats.push((Some(i as u32), j as u32, caveat.clone()));
+            }
+        }
 
-                for query in caveat.queries.iter() {
-                    let res = self.world.query_rule(query.clone());
-                    if !res.is_empty() {
-                        successful = true;
-                        break;
+        caveats
+    }
+
+    /// policies of the verifier, starting with the ones of the policy
+    fn all_policies(&mut self) -> Vec<(PolicyKind, Vec<datalog::Rule>)> {
+        let symbols = &mut self.symbols;
+        self.policy
+            .iter()
+            .flat_map(|policy| policy.policies().iter())
+            .chain(self.policies.iter())
+            .map(|p| (p.kind, p.queries.iter().map(|q| q.convert(symbols)).collect()))
+            .collect()
+    }
+
+    /// rules of the policy, of the token and of the verifier
+    fn rules(&self) -> Vec<&datalog::Rule> {
+        self.policy
+            .iter()
+            .flat_map(|policy| policy.world().rules.iter())
+            .chain(self.token_world.rules.iter())
+            .chain(self.base_world.rules.iter())
+            .chain(self.world.rules.iter())
+            .collect()
+    }
+
+    /// unique identifiers of the blocks, then the revocation identifiers
+    /// set in their facts, with the block index
+    fn revocation_ids(&self) -> Vec<(u32, RevocationId)> {
+        let mut ids = self
+            .token
+            .revocation_identifiers()
+            .into_iter()
+            .enumerate()
+            .map(|(i, id)| (i as u32, id))
+            .collect::<Vec<_>>();
+
+        let name = match self.token.symbols.get("revocation_id") {
+            Some(name) => name,
+            None => return ids,
+        };
+
+        let blocks = std::iter::once(&self.token.authority).chain(self.token.blocks.iter());
+        for (i, block) in blocks.enumerate() {
+            for fact in block.facts.iter().filter(|f| f.predicate.name == name) {
+                for id in fact.predicate.ids.iter() {
+                    if let datalog::ID::Integer(id) = id {
+                        ids.push((i as u32, RevocationId::Int(*id)));
                     }
                 }
+            }
+        }
 
-                if !successful {
-                    errors.push(error::FailedCaveat::Block(error::FailedBlockCaveat {
-                        block_id: i as u32,
-                        caveat_id: j as u32,
-                        rule: self.symbols.print_caveat(caveat),
-                    }));
-                }
+        ids
+    }
+
+    fn query_derivation(
+        &self,
+        world: &datalog::Overlay,
+        query: &datalog::Rule,
+        fact: &datalog::Fact,
+        premises: &[datalog::Fact],
+    ) -> Derivation {
+        Derivation {
+            fact: Fact::convert_from(fact, &self.symbols),
+            rule: Some(Rule::convert_from(query, &self.symbols)),
+            premises: premises
+                .iter()
+                .map(|p| Derivation::convert_from(&world.explain(p), world.rules(), &self.symbols))
+                .collect(),
+        }
+    }
+
+    /// blocks whose facts are visible to the caveats of a block, or of
+    /// the verifier for None, all of them being visible for None
+    fn scope(&self, block_id: Option<u32>) -> Option<BTreeSet<usize>> {
+        match (self.fact_scope, block_id) {
+            (FactScope::Shared, _) => None,
+            (FactScope::Block, Some(block_id)) if block_id > 0 => {
+                Some([0, block_id as usize].iter().cloned().collect())
             }
+            (FactScope::Block, _) => Some(
+                std::iter::once(0)
+                    .chain(self.trusted_blocks.iter().cloned())
+                    .collect(),
+            ),
         }
+    }
 
-        if !errors.is_empty() {
-            Err(error::Token::FailedLogic(error::Logic::FailedCaveats(
-                errors,
-            )))
-        } else {
-            Ok(())
+    /// scopes of the verifier and of the caveats, without duplicates
+    fn caveat_scopes(
+        &self,
+        caveats: &[(Option<u32>, u32, datalog::Caveat)],
+    ) -> Vec<Option<BTreeSet<usize>>> {
+        let mut scopes = vec![];
+        let block_ids = std::iter::once(None).chain(caveats.iter().map(|(block_id, _, _)| *block_id));
+        for block_id in block_ids {
+            let scope = self.scope(block_id);
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        scopes
+    }
+
+    /// runs the rules for each scope
+    ///
+    /// the runs share the limits: they have the same deadline, and the
+    /// facts generated for a scope count for the next ones
+    fn run_caveat_worlds<'b>(
+        &'b self,
+        scopes: &'b [Option<BTreeSet<usize>>],
+        provenance: bool,
+    ) -> Result<HashMap<&'b Option<BTreeSet<usize>>, datalog::Overlay<'b>>, error::Token> {
+        let mut budget = datalog::RunBudget::new(self.limits);
+        let mut worlds = HashMap::new();
+        for scope in scopes {
+            worlds.insert(scope, self.run_world(scope.as_ref(), provenance, &mut budget)?);
+        }
+        Ok(worlds)
+    }
+
+    /// runs the rules over the worlds of the policy, of the token and of the
+    /// verifier, restricting the token to some blocks if there is a scope
+    ///
+    /// the worlds are not modified: with negated predicates, facts generated
+    /// before adding more facts or rules could be wrong
+    fn run_world<'b>(
+        &'b self,
+        scope: Option<&'b BTreeSet<usize>>,
+        provenance: bool,
+        budget: &mut datalog::RunBudget,
+    ) -> Result<datalog::Overlay<'b>, error::Token> {
+        let mut world = datalog::Overlay::new();
+        if let Some(policy) = self.policy.as_ref() {
+            world.add_world(policy.world(), None);
+        }
+        world.add_world(&self.token_world, scope);
+        world.add_world(&self.base_world, None);
+        world.add_world(&self.world, None);
+        if provenance {
+            world.enable_provenance();
         }
+        world.run_within(budget).map_err(error::Token::RunLimit)?;
+        Ok(world)
     }
 
     pub fn print_world(&self) -> String {
-        self.symbols.print_world(&self.world)
+        let mut world = datalog::World::new();
+        world.facts = self.facts().cloned().collect();
+        world.rules = self.rules().into_iter().cloned().collect();
+        self.symbols.print_world(&world)
     }
 
     pub fn dump(&self) -> (Vec<Fact>, Vec<Rule>, Vec<Caveat>) {
-        (self.world.facts.iter().map(|f| Fact::convert_from(f, &self.symbols)).collect(),
-         self.world.rules.iter().map(|r| Rule::convert_from(r, &self.symbols)).collect(),
+        (self.facts().map(|f| Fact::convert_from(f, &self.symbols)).collect(),
+         self.rules().into_iter().map(|r| Rule::convert_from(r, &self.symbols)).collect(),
          self.caveats.clone())
     }
+
+    /// facts of the policy, of the token and of the verifier
+    fn facts(&self) -> impl Iterator<Item = &datalog::Fact> {
+        self.policy
+            .iter()
+            .flat_map(|policy| policy.world().facts.iter())
+            .chain(self.token_world.facts.iter())
+            .chain(self.base_world.facts.iter())
+            .chain(self.world.facts.iter())
+    }
+}
+
+/// explanation of a fact: the rule that generated it, and
+/// the explanations of the facts that matched the rule body
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derivation {
+    pub fact: Fact,
+    /// the rule that generated the fact, or None if it was provided directly
+    pub rule: Option<Rule>,
+    pub premises: Vec<Derivation>,
+}
+
+impl Derivation {
+    fn convert_from(
+        tree: &datalog::DerivationTree,
+        rules: &[&datalog::Rule],
+        symbols: &datalog::SymbolTable,
+    ) -> Self {
+        Derivation {
+            fact: Fact::convert_from(&tree.fact, symbols),
+            rule: tree
+                .rule
+                .map(|i| Rule::convert_from(rules[i], symbols)),
+            premises: tree
+                .premises
+                .iter()
+                .map(|p| Derivation::convert_from(p, rules, symbols))
+                .collect(),
+        }
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        write!(f, "{:indent$}{}", "", self.fact, indent = indent)?;
+        if let Some(rule) = &self.rule {
+            write!(f, " from {}", rule)?;
+        }
+        writeln!(f)?;
+
+        for premise in self.premises.iter() {
+            premise.fmt_indented(f, indent + 2)?;
+        }
+        Ok(())
+    }
+}
+
+/// prints the derivation tree, one fact per line, with
+/// the facts that matched a rule indented below it
+impl fmt::Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+/// explanation of a caveat that succeeded
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaveatDerivation {
+    /// index of the block containing the caveat, or None for verifier caveats
+    pub block_id: Option<u32>,
+    pub caveat_id: u32,
+    /// pretty print of the caveat
+    pub rule: String,
+    /// explanations of the facts generated by the first successful query
+    pub derivations: Vec<Derivation>,
+}
+
+/// detailed report on a failed caveat
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaveatDiagnostic {
+    pub caveat: error::FailedCaveat,
+    /// why each query of the caveat failed
+    pub queries: Vec<QueryDiagnostic>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryDiagnostic {
+    pub rule: Rule,
+    pub failure: QueryFailure,
+}
+
+/// why a query did not generate any fact
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryFailure {
+    /// no fact matched this body predicate, with the variables
+    /// bound by the previous predicates
+    UnmatchedPredicate {
+        /// index of the predicate in the query body
+        index: usize,
+        predicate: Predicate,
+        /// facts that came closest to matching the predicate
+        closest: Vec<PartialMatch>,
+    },
+    /// all the body predicates matched, but the expressions or
+    /// negated predicates rejected every match
+    FailedConditions,
+}
+
+impl QueryFailure {
+    fn convert_from(
+        failure: &datalog::RuleFailure,
+        query: &datalog::Rule,
+        symbols: &datalog::SymbolTable,
+    ) -> Self {
+        match failure {
+            datalog::RuleFailure::UnmatchedPredicate { index, closest } => {
+                QueryFailure::UnmatchedPredicate {
+                    index: *index,
+                    predicate: Predicate::convert_from(&query.body[*index], symbols),
+                    closest: closest
+                        .iter()
+                        .map(|m| PartialMatch {
+                            fact: Fact::convert_from(&m.fact, symbols),
+                            mismatches: m
+                                .mismatches
+                                .iter()
+                                .map(|(position, expected)| Mismatch {
+                                    position: *position,
+                                    expected: Atom::convert_from(expected, symbols),
+                                    found: Atom::convert_from(
+                                        &m.fact.predicate.ids[*position],
+                                        symbols,
+                                    ),
+                                })
+                                .collect(),
+                        })
+                        .collect(),
+                }
+            }
+            datalog::RuleFailure::FailedConditions => QueryFailure::FailedConditions,
+        }
+    }
+}
+
+impl fmt::Display for QueryFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryFailure::UnmatchedPredicate {
+                predicate, closest, ..
+            } => {
+                write!(f, "no fact matches {}", predicate)?;
+                for m in closest.iter() {
+                    write!(f, "; {}", m)?;
+                }
+                Ok(())
+            }
+            QueryFailure::FailedConditions => {
+                write!(f, "the body matched, but the conditions rejected every match")
+            }
+        }
+    }
+}
+
+/// fact that matched some of the terms of a predicate
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialMatch {
+    pub fact: Fact,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// prints like `operation(#ambient, #write) present, expected #read`
+impl fmt::Display for PartialMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let expected = self
+            .mismatches
+            .iter()
+            .map(|m| m.expected.to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{} present, expected {}", self.fact, expected.join(" and "))
+    }
+}
+
+/// term of a fact that did not match the predicate
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub position: usize,
+    /// the expected value, or the variable if the value did not satisfy its constraints
+    pub expected: Atom,
+    pub found: Atom,
 }
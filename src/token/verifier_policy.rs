@@ -0,0 +1,319 @@
+//! verifier facts, rules, caveats and policies that do not depend on a token
+//!
+//! a `VerifierPolicy` is built once, from code or from a Datalog source
+//! text, and checked like the verifier would do. It can be serialized to
+//! store it on disk, then loaded in the verifier of each request with
+//! `Verifier::load_policy`, without parsing the source again.
+//!
+//! `Biscuit::verify_with_policy` creates a verifier that shares the facts
+//! and rules of a policy held in an `Arc`, converted once when building it,
+//! so they are not copied for each token
+use super::builder::{
+    check_parameters, check_safety, check_stratification, parse_checked_source, Caveat, Fact, Policy, PolicyKind, Rule,
+};
+use super::default_symbol_table;
+use crate::datalog::{self, SymbolTable};
+use crate::error;
+use crate::format::{convert::*, schema};
+use crate::parser::Statement;
+use prost::Message;
+use std::{convert::TryInto, sync::Arc};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifierPolicy {
+    /// symbols used by the facts and rules, starting with the default table,
+    /// and extended by the symbol tables of the verifiers using the policy
+    symbols: Arc<SymbolTable>,
+    /// facts and rules converted with `symbols`
+    world: datalog::World,
+    facts: Vec<Fact>,
+    rules: Vec<Rule>,
+    caveats: Vec<Caveat>,
+    policies: Vec<Policy>,
+}
+
+impl Default for VerifierPolicy {
+    fn default() -> Self {
+        VerifierPolicy::new()
+    }
+}
+
+impl VerifierPolicy {
+    pub fn new() -> Self {
+        VerifierPolicy {
+            symbols: Arc::new(default_symbol_table()),
+            world: datalog::World::new(),
+            facts: vec![],
+            rules: vec![],
+            caveats: vec![],
+            policies: vec![],
+        }
+    }
+
+    pub fn add_fact<F: TryInto<Fact>>(&mut self, fact: F) -> Result<(), error::Token>
+    where
+        F::Error: Into<error::Token>,
+    {
+        let fact = fact.try_into().map_err(Into::into)?;
+        check_parameters(fact.parameters())?;
+        self.world.add_fact(fact.convert(Arc::make_mut(&mut self.symbols)));
+        self.facts.push(fact);
+        Ok(())
+    }
+
+    /// the rules are checked for stratification with the other rules of
+    /// the policy, and again with the rules of the token when loaded
+    pub fn add_rule<R: TryInto<Rule>>(&mut self, rule: R) -> Result<(), error::Token>
+    where
+        R::Error: Into<error::Token>,
+    {
+        let rule = rule.try_into().map_err(Into::into)?;
+        check_parameters(rule.parameters())?;
+        let converted = rule.convert(Arc::make_mut(&mut self.symbols));
+        check_safety(&converted, &self.symbols)?;
+        check_stratification(&self.world.rules, &converted, &self.symbols)?;
+
+        self.world.add_rule(converted);
+        self.rules.push(rule);
+        Ok(())
+    }
+
+    pub fn add_caveat<R: TryInto<Caveat>>(&mut self, caveat: R) -> Result<(), error::Token>
+    where
+        R::Error: Into<error::Token>,
+    {
+        let caveat = caveat.try_into().map_err(Into::into)?;
+        check_parameters(caveat.parameters())?;
+        for query in caveat.convert(Arc::make_mut(&mut self.symbols)).queries.iter() {
+            check_safety(query, &self.symbols)?;
+        }
+        self.caveats.push(caveat);
+        Ok(())
+    }
+
+    pub fn add_policy<P: TryInto<Policy>>(&mut self, policy: P) -> Result<(), error::Token>
+    where
+        P::Error: Into<error::Token>,
+    {
+        let policy = policy.try_into().map_err(Into::into)?;
+        check_parameters(policy.parameters())?;
+        for query in policy.queries.iter() {
+            check_safety(&query.convert(Arc::make_mut(&mut self.symbols)), &self.symbols)?;
+        }
+        self.policies.push(policy);
+        Ok(())
+    }
+
+    /// adds the facts, rules, caveats and policies of a Datalog source text,
+    /// like `Verifier::add_source`
+    pub fn add_source(&mut self, source: &str) -> Result<(), error::Token> {
+        for statement in parse_checked_source(source, &self.symbols, true)? {
+            match statement {
+                Statement::Fact(fact) => self.add_fact(fact)?,
+                Statement::Rule(rule) => self.add_rule(rule)?,
+                Statement::Caveat(caveat) => self.add_caveat(caveat)?,
+                Statement::Policy(policy) => self.add_policy(policy)?,
+                // rejected by parse_checked_source
+                Statement::Context(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn facts(&self) -> &[Fact] {
+        &self.facts
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn caveats(&self) -> &[Caveat] {
+        &self.caveats
+    }
+
+    pub fn policies(&self) -> &[Policy] {
+        &self.policies
+    }
+
+    pub(crate) fn symbols(&self) -> &Arc<SymbolTable> {
+        &self.symbols
+    }
+
+    pub(crate) fn world(&self) -> &datalog::World {
+        &self.world
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, error::Token> {
+        // every symbol was added when checking the facts and rules
+        let mut symbols = SymbolTable::clone(&self.symbols);
+
+        let proto = schema::VerifierPolicies {
+            facts: self
+                .facts
+                .iter()
+                .map(|f| token_fact_to_proto_fact(&f.convert(&mut symbols)))
+                .collect(),
+            rules: self
+                .rules
+                .iter()
+                .map(|r| token_rule_to_proto_rule(&r.convert(&mut symbols)))
+                .collect(),
+            caveats: self
+                .caveats
+                .iter()
+                .map(|c| token_caveat_to_proto_caveat(&c.convert(&mut symbols)))
+                .collect(),
+            policies: self
+                .policies
+                .iter()
+                .map(|p| schema::Policy {
+                    queries: p
+                        .queries
+                        .iter()
+                        .map(|q| token_rule_to_proto_rule(&q.convert(&mut symbols)))
+                        .collect(),
+                    kind: match p.kind {
+                        PolicyKind::Allow => schema::policy::Kind::Allow as i32,
+                        PolicyKind::Deny => schema::policy::Kind::Deny as i32,
+                    },
+                })
+                .collect(),
+            symbols: symbols.symbols,
+        };
+
+        let mut v = Vec::new();
+        proto.encode(&mut v).map(|_| v).map_err(|e| {
+            error::Token::Format(error::Format::SerializationError(format!(
+                "serialization error: {:?}",
+                e
+            )))
+        })
+    }
+
+    pub fn from_slice(slice: &[u8]) -> Result<Self, error::Token> {
+        let proto = schema::VerifierPolicies::decode(slice).map_err(|e| {
+            error::Token::Format(error::Format::DeserializationError(format!(
+                "deserialization error: {:?}",
+                e
+            )))
+        })?;
+
+        let symbols = SymbolTable::from(proto.symbols);
+        let invalid_symbol = || {
+            error::Token::Format(error::Format::DeserializationError(
+                "deserialization error: invalid symbol index".to_string(),
+            ))
+        };
+        let mut policy = VerifierPolicy {
+            symbols: Arc::new(SymbolTable::new()),
+            world: datalog::World::new(),
+            facts: vec![],
+            rules: vec![],
+            caveats: vec![],
+            policies: vec![],
+        };
+
+        for fact in proto.facts.iter() {
+            let fact = proto_fact_to_token_fact(fact).map_err(error::Token::Format)?;
+            if !predicate_symbols_valid(&fact.predicate, &symbols) {
+                return Err(invalid_symbol());
+            }
+            policy.facts.push(Fact::convert_from(&fact, &symbols));
+            policy.world.add_fact(fact);
+        }
+
+        for rule in proto.rules.iter() {
+            let rule = proto_rule_to_token_rule(rule).map_err(error::Token::Format)?;
+            if !rule_symbols_valid(&rule, &symbols) {
+                return Err(invalid_symbol());
+            }
+            // checked like in `add_rule`
+            check_safety(&rule, &symbols)?;
+            check_stratification(&policy.world.rules, &rule, &symbols)?;
+            policy.rules.push(Rule::convert_from(&rule, &symbols));
+            policy.world.add_rule(rule);
+        }
+
+        for caveat in proto.caveats.iter() {
+            let caveat = proto_caveat_to_token_caveat(caveat).map_err(error::Token::Format)?;
+            if !caveat.queries.iter().all(|q| rule_symbols_valid(q, &symbols)) {
+                return Err(invalid_symbol());
+            }
+            for query in caveat.queries.iter() {
+                check_safety(query, &symbols)?;
+            }
+            policy.caveats.push(Caveat::convert_from(&caveat, &symbols));
+        }
+
+        for p in proto.policies.iter() {
+            let kind = match schema::policy::Kind::from_i32(p.kind) {
+                Some(schema::policy::Kind::Allow) => PolicyKind::Allow,
+                Some(schema::policy::Kind::Deny) => PolicyKind::Deny,
+                None => {
+                    return Err(error::Token::Format(error::Format::DeserializationError(
+                        "deserialization error: invalid policy kind".to_string(),
+                    )))
+                }
+            };
+
+            let mut queries = vec![];
+            for query in p.queries.iter() {
+                let query = proto_rule_to_token_rule(query).map_err(error::Token::Format)?;
+                if !rule_symbols_valid(&query, &symbols) {
+                    return Err(invalid_symbol());
+                }
+                check_safety(&query, &symbols)?;
+                queries.push(Rule::convert_from(&query, &symbols));
+            }
+            policy.policies.push(Policy { queries, kind });
+        }
+
+        policy.symbols = Arc::new(symbols);
+        Ok(policy)
+    }
+}
+
+/// checks that a rule only refers to symbols of the table, the other ones
+/// could not be converted back to names
+fn rule_symbols_valid(rule: &datalog::Rule, symbols: &SymbolTable) -> bool {
+    let valid = |index: u64| (index as usize) < symbols.symbols.len();
+
+    predicate_symbols_valid(&rule.head, symbols)
+        && rule.body.iter().chain(rule.negated.iter()).all(|p| predicate_symbols_valid(p, symbols))
+        && rule.constraints.iter().all(|c| {
+            valid(c.id as u64)
+                && match &c.kind {
+                    datalog::ConstraintKind::Symbol(datalog::SymbolConstraint::In(s))
+                    | datalog::ConstraintKind::Symbol(datalog::SymbolConstraint::NotIn(s)) => {
+                        s.iter().all(|i| valid(*i))
+                    }
+                    datalog::ConstraintKind::Set(datalog::SetConstraint::Contains(id)) => {
+                        id_symbols_valid(id, symbols)
+                    }
+                    datalog::ConstraintKind::Set(datalog::SetConstraint::Intersects(s))
+                    | datalog::ConstraintKind::Set(datalog::SetConstraint::Subset(s)) => {
+                        s.iter().all(|id| id_symbols_valid(id, symbols))
+                    }
+                    _ => true,
+                }
+        })
+        && rule.expressions.iter().flat_map(|e| e.ops.iter()).all(|op| match op {
+            datalog::Op::Value(id) => id_symbols_valid(id, symbols),
+            datalog::Op::Binary(_) => true,
+        })
+}
+
+fn predicate_symbols_valid(predicate: &datalog::Predicate, symbols: &SymbolTable) -> bool {
+    (predicate.name as usize) < symbols.symbols.len()
+        && predicate.ids.iter().all(|id| id_symbols_valid(id, symbols))
+}
+
+fn id_symbols_valid(id: &datalog::ID, symbols: &SymbolTable) -> bool {
+    match id {
+        datalog::ID::Symbol(index) => (*index as usize) < symbols.symbols.len(),
+        datalog::ID::Variable(index) => (*index as usize) < symbols.symbols.len(),
+        datalog::ID::Set(s) => s.iter().all(|id| id_symbols_valid(id, symbols)),
+        _ => true,
+    }
+}